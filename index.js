@@ -263,7 +263,16 @@ class GamebuinoEmulator extends HTMLElement {
                 if (this.requestId) cancelAnimationFrame(this.requestId);
                 if (this.gamebuino) this.gamebuino.free();
                 this.gamebuino = Gamebuino.new();
-                this.gamebuino.load_program(new Uint8Array(buffer), 0x4000);
+                this.gamebuino.set_double_buffered(true);
+                this.gamebuino.sync_rtc_to_host_clock();
+                const bytes = new Uint8Array(buffer);
+                const isElf = bytes[0] === 0x7f && bytes[1] === 0x45 && bytes[2] === 0x4c && bytes[3] === 0x46;
+                if (isElf) {
+                    this.gamebuino.load_elf(bytes);
+                } else {
+                    this.gamebuino.load_program(bytes, 0x4000);
+                }
+                this.frameCount = this.gamebuino.frame_count();
                 if (this.audioCtx) {
                     this.audioCtx.close();
                     this.audioCtx = undefined;
@@ -284,14 +293,18 @@ class GamebuinoEmulator extends HTMLElement {
 
         this.gamebuino.run(iterations, this.buttonState);
 
-        const buf8 = new Uint8ClampedArray(
-            memory.buffer,
-            this.gamebuino.image_pointer(),
-            160 * 128 * 4
-        );
-        this.imageData.data.set(buf8);
-        this.ctx.putImageData(this.imageData, 0, 0);
-        this.ctx.drawImage(this.canvas, 0, 0);
+        const frameCount = this.gamebuino.frame_count();
+        if (frameCount !== this.frameCount) {
+            this.frameCount = frameCount;
+            const buf8 = new Uint8ClampedArray(
+                memory.buffer,
+                this.gamebuino.image_pointer(),
+                160 * 128 * 4
+            );
+            this.imageData.data.set(buf8);
+            this.ctx.putImageData(this.imageData, 0, 0);
+            this.ctx.drawImage(this.canvas, 0, 0);
+        }
 
         this.handleAudio();
 
@@ -382,23 +395,24 @@ class GamebuinoEmulator extends HTMLElement {
     initAudio() {
         const AudioContext = window.AudioContext || window.webkitAudioContext;
         if (!this.audioCtx && AudioContext) {
-            console.log("Audio sample rate = ", this.gamebuino.sample_rate);
-            this.audioCtx = new AudioContext({ sampleRate: this.gamebuino.sample_rate });
+            this.audioCtx = new AudioContext();
+            console.log("Audio sample rate = ", this.audioCtx.sampleRate);
+            this.gamebuino.set_output_sample_rate(this.audioCtx.sampleRate);
             this.audioCtx.resume();
         }
     }
 
     handleAudio() {
         if (!this.audioCtx) return;
-        const frameCount = this.gamebuino.sound_samples;
+        const frameCount = this.gamebuino.drain_output_sound(this.gamebuino.output_sound_samples());
         if (frameCount === 0) return;
-        const raw = new Uint16Array(memory.buffer, this.gamebuino.sound_data_pointer(), frameCount);
+        const samples = new Float32Array(memory.buffer, this.gamebuino.output_sound_pointer(), frameCount);
         const audioBuffer = this.audioCtx.createBuffer(1, frameCount, this.audioCtx.sampleRate);
         const channelBuffer = audioBuffer.getChannelData(0);
         const source = this.audioCtx.createBufferSource();
 
         for (let i = 0; i < frameCount; i++) {
-            channelBuffer[i] = raw[i] / 1024;
+            channelBuffer[i] = samples[i] / 2;
         }
 
         source.buffer = audioBuffer;
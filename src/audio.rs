@@ -0,0 +1,286 @@
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+
+/// Fixed-size FIFO of audio samples between the emulated game and the host.
+pub struct SampleRing<T> {
+    samples: Vec<T>,
+    read: usize,
+    write: usize,
+    pub overflows: u32,
+}
+
+impl<T: Copy + Default> SampleRing<T> {
+    /// `capacity` must be a power of two.
+    pub fn new(capacity: usize) -> SampleRing<T> {
+        SampleRing {
+            samples: vec![T::default(); capacity],
+            read: 0,
+            write: 0,
+            overflows: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.write.wrapping_sub(self.read)
+    }
+
+    /// Appends a sample, dropping it and counting an overflow if the host hasn't kept up.
+    pub fn push(&mut self, sample: T) {
+        if self.len() == self.samples.len() {
+            self.overflows = self.overflows.wrapping_add(1);
+            return;
+        }
+        let mask = self.samples.len() - 1;
+        self.samples[self.write & mask] = sample;
+        self.write = self.write.wrapping_add(1);
+    }
+
+    /// Moves up to `output.len()` of the oldest samples into `output`. Returns how many.
+    pub fn drain_into(&mut self, output: &mut [T]) -> usize {
+        let count = self.len().min(output.len());
+        let mask = self.samples.len() - 1;
+        for (i, sample) in output.iter_mut().take(count).enumerate() {
+            *sample = self.samples[self.read.wrapping_add(i) & mask];
+        }
+        self.read = self.read.wrapping_add(count);
+        count
+    }
+}
+
+/// Captures DAC output for export as a WAV file.
+pub struct WavRecorder {
+    segments: Vec<(u32, Vec<u16>)>,
+}
+
+impl WavRecorder {
+    const DAC_MIDPOINT: i32 = 512;
+    const DAC_TO_PCM: i32 = 64; // 10-bit DAC to 16-bit PCM
+
+    pub fn new() -> WavRecorder {
+        WavRecorder {
+            segments: Vec::new(),
+        }
+    }
+
+    /// Records a DAC sample played at `sample_rate`.
+    pub fn push(&mut self, sample: u16, sample_rate: u32) {
+        match self.segments.last_mut() {
+            Some((rate, samples)) if *rate == sample_rate => samples.push(sample),
+            _ => self.segments.push((sample_rate, vec![sample])),
+        }
+    }
+
+    /// Encodes the recording as 16-bit mono PCM at the highest sample rate used. Parts played
+    /// at other rates are resampled to it.
+    pub fn finish(self) -> Vec<u8> {
+        let output_rate = self
+            .segments
+            .iter()
+            .map(|(rate, _)| *rate)
+            .max()
+            .unwrap_or(crate::DEFAULT_SAMPLE_RATE);
+
+        let mut pcm: Vec<i16> = Vec::new();
+        for (rate, samples) in &self.segments {
+            let converted = samples.iter().map(|&sample| {
+                ((sample as i32 - WavRecorder::DAC_MIDPOINT) * WavRecorder::DAC_TO_PCM) as f32
+            });
+            if *rate == output_rate {
+                pcm.extend(converted.map(|sample| sample as i16));
+            } else {
+                let converted: Vec<f32> = converted.collect();
+                pcm.extend(
+                    resample_linear(&converted, *rate, output_rate)
+                        .into_iter()
+                        .map(|sample| sample as i16),
+                );
+            }
+        }
+
+        encode_wav(&pcm, output_rate)
+    }
+}
+
+/// Converts the DAC stream, whatever rate the game plays it at, to a fixed output rate using
+/// windowed-sinc interpolation.
+pub struct Resampler {
+    output_rate: u32,
+    input_rate: u32,
+    input: VecDeque<f32>,
+    position: f64,
+    pub output: SampleRing<f32>,
+}
+
+impl Resampler {
+    // Zero crossings of the sinc kernel on each side of the interpolated point
+    const ZERO_CROSSINGS: f64 = 8.0;
+
+    pub fn new(output_rate: u32, capacity: usize) -> Resampler {
+        Resampler {
+            output_rate,
+            input_rate: crate::DEFAULT_SAMPLE_RATE,
+            input: VecDeque::new(),
+            position: 0.0,
+            output: SampleRing::new(capacity),
+        }
+    }
+
+    pub fn output_rate(&self) -> u32 {
+        self.output_rate
+    }
+
+    /// Adds a DAC sample played at `sample_rate` and produces any output samples it completes.
+    pub fn push(&mut self, sample: u16, sample_rate: u32) {
+        self.input_rate = sample_rate;
+        self.input.push_back(
+            (sample as i32 - WavRecorder::DAC_MIDPOINT) as f32 / WavRecorder::DAC_MIDPOINT as f32,
+        );
+
+        let step = self.input_rate as f64 / self.output_rate as f64;
+        // Lower the cutoff below the output Nyquist frequency when downsampling
+        let cutoff = (1.0 / step).min(1.0);
+        let half_width = (Resampler::ZERO_CROSSINGS / cutoff).ceil();
+
+        while self.position + half_width < self.input.len() as f64 {
+            let first = (self.position - half_width).ceil().max(0.0) as usize;
+            let last = (self.position + half_width).floor() as usize;
+            let mut value = 0.0;
+            for index in first..=last {
+                let t = self.position - index as f64;
+                let window = 0.5 * (1.0 + (PI * t / half_width).cos());
+                value += self.input[index] as f64 * cutoff * sinc(cutoff * t) * window;
+            }
+            self.output.push(value as f32);
+            self.position += step;
+        }
+
+        // Drop input no longer within reach of the kernel
+        let used = ((self.position - half_width).floor().max(0.0) as usize).min(self.input.len());
+        self.input.drain(..used);
+        self.position -= used as f64;
+    }
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+fn resample_linear(samples: &[f32], input_rate: u32, output_rate: u32) -> Vec<f32> {
+    let step = input_rate as f64 / output_rate as f64;
+    let length = (samples.len() as f64 / step).round() as usize;
+    (0..length)
+        .map(|i| {
+            let position = i as f64 * step;
+            let index = position as usize;
+            let fraction = (position - index as f64) as f32;
+            let a = samples[index.min(samples.len() - 1)];
+            let b = samples[(index + 1).min(samples.len() - 1)];
+            a + (b - a) * fraction
+        })
+        .collect()
+}
+
+fn encode_wav(pcm: &[i16], sample_rate: u32) -> Vec<u8> {
+    let data_size = (pcm.len() * 2) as u32;
+    let mut wav = Vec::with_capacity(44 + data_size as usize);
+    wav.extend_from_slice(b"RIFF");
+    wav.extend_from_slice(&(36 + data_size).to_le_bytes());
+    wav.extend_from_slice(b"WAVE");
+
+    wav.extend_from_slice(b"fmt ");
+    wav.extend_from_slice(&16u32.to_le_bytes());
+    wav.extend_from_slice(&1u16.to_le_bytes()); // PCM
+    wav.extend_from_slice(&1u16.to_le_bytes()); // mono
+    wav.extend_from_slice(&sample_rate.to_le_bytes());
+    wav.extend_from_slice(&(sample_rate * 2).to_le_bytes()); // byte rate
+    wav.extend_from_slice(&2u16.to_le_bytes()); // block align
+    wav.extend_from_slice(&16u16.to_le_bytes()); // bits per sample
+
+    wav.extend_from_slice(b"data");
+    wav.extend_from_slice(&data_size.to_le_bytes());
+    for sample in pcm {
+        wav.extend_from_slice(&sample.to_le_bytes());
+    }
+    wav
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([
+            bytes[offset],
+            bytes[offset + 1],
+            bytes[offset + 2],
+            bytes[offset + 3],
+        ])
+    }
+
+    fn samples(wav: &[u8]) -> Vec<i16> {
+        wav[44..]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect()
+    }
+
+    #[test]
+    fn wav_header() {
+        let wav = encode_wav(&[0, 1, -1], 44100);
+        assert_eq!(&wav[..4], b"RIFF");
+        assert_eq!(u32_at(&wav, 4) as usize, wav.len() - 8);
+        assert_eq!(&wav[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&wav, 16), 16);
+        assert_eq!((u16_at(&wav, 20), u16_at(&wav, 22)), (1, 1)); // PCM, mono
+        assert_eq!(u32_at(&wav, 24), 44100);
+        assert_eq!(u32_at(&wav, 28), 88200);
+        assert_eq!((u16_at(&wav, 32), u16_at(&wav, 34)), (2, 16));
+        assert_eq!(&wav[36..40], b"data");
+        assert_eq!(u32_at(&wav, 40), 6);
+        assert_eq!(samples(&wav), [0, 1, -1]);
+    }
+
+    #[test]
+    fn dac_samples_are_centred() {
+        let mut recorder = WavRecorder::new();
+        for sample in [0, 512, 1023] {
+            recorder.push(sample, 22050);
+        }
+        let wav = recorder.finish();
+        assert_eq!(u32_at(&wav, 24), 22050);
+        assert_eq!(samples(&wav), [-32768, 0, 32704]);
+    }
+
+    #[test]
+    fn other_rates_are_resampled_to_the_highest() {
+        let mut recorder = WavRecorder::new();
+        for _ in 0..4 {
+            recorder.push(1023, 11025);
+        }
+        for _ in 0..4 {
+            recorder.push(0, 22050);
+        }
+        let wav = recorder.finish();
+        assert_eq!(u32_at(&wav, 24), 22050);
+        let samples = samples(&wav);
+        assert_eq!(samples.len(), 8 + 4);
+        assert!(samples[..8].iter().all(|&sample| sample == 32704));
+        assert!(samples[8..].iter().all(|&sample| sample == -32768));
+    }
+
+    #[test]
+    fn empty_recording_is_a_valid_wav() {
+        let wav = WavRecorder::new().finish();
+        assert_eq!(wav.len(), 44);
+        assert_eq!(u32_at(&wav, 24), crate::DEFAULT_SAMPLE_RATE);
+        assert_eq!(u32_at(&wav, 40), 0);
+    }
+}
@@ -0,0 +1,369 @@
+//! A shadow call stack, kept from the calls, returns and exceptions the program makes, for
+//! backtraces.
+
+use crate::{Gamebuino, LR_INDEX};
+use std::collections::VecDeque;
+use wasm_bindgen::prelude::*;
+
+// Past this depth, e.g. in runaway recursion, the outermost frames are forgotten
+const MAX_DEPTH: usize = 1024;
+// Values of LR, and so of return destinations, at or above this are EXC_RETURN
+const EXC_RETURN: u32 = 0xfffffff0;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FrameKind {
+    Call,
+    Exception,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct StackFrame {
+    pub kind: FrameKind,
+    /// Entry address of the function or exception handler.
+    pub function: u32,
+    /// Where execution continues once the frame returns: the instruction after the call, or
+    /// the interrupted one.
+    pub return_address: u32,
+    // Whether the function has pushed LR, so that a second push with the same LR is a tail call
+    lr_saved: bool,
+}
+
+impl StackFrame {
+    fn new(kind: FrameKind, function: u32, return_address: u32) -> StackFrame {
+        StackFrame {
+            kind,
+            function,
+            return_address,
+            lr_saved: false,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// Follows calls and returns from now on, for `backtrace`. The outermost frame is where
+    /// execution was when enabled, or the reset handler.
+    pub fn set_backtraces_enabled(&mut self, enabled: bool) {
+        self.backtraces = enabled;
+        self.update_call_tracking();
+    }
+
+    /// One line per frame, innermost first, with its PC, symbolized if an ELF is loaded.
+    /// Exception handlers are marked, and the frame after one is the code it interrupted.
+    /// Empty unless backtraces or profiling are enabled.
+    pub fn backtrace(&self) -> Vec<String> {
+        let mut pc = self.pc();
+        let mut lines = Vec::new();
+        for (index, frame) in self.call_stack.iter().rev().enumerate() {
+            let location = match self.symbolize(pc) {
+                symbol if symbol.is_empty() => {
+                    format!(
+                        "0x{:08x}+0x{:x}",
+                        frame.function,
+                        pc.wrapping_sub(frame.function)
+                    )
+                }
+                symbol => symbol,
+            };
+            let marker = match frame.kind {
+                FrameKind::Call => "",
+                FrameKind::Exception => " <exception>",
+            };
+            lines.push(format!(
+                "#{:<3} 0x{:08x} in {}{}",
+                index, pc, location, marker
+            ));
+            pc = frame.return_address;
+        }
+        lines
+    }
+}
+
+impl Gamebuino {
+    /// The shadow call stack, outermost frame first, while backtraces or profiling are
+    /// enabled.
+    pub fn call_stack(&self) -> &VecDeque<StackFrame> {
+        &self.call_stack
+    }
+
+    /// Called when backtraces or profiling are turned on or off. The call stack is only kept
+    /// while one of them needs it.
+    pub(crate) fn update_call_tracking(&mut self) {
+        let track_calls = self.backtraces || self.profiler.is_some();
+        if track_calls && !self.track_calls {
+            self.reset_call_stack();
+        } else if !track_calls {
+            self.call_stack.clear();
+        }
+        self.track_calls = track_calls;
+    }
+
+    pub(crate) fn reset_call_stack(&mut self) {
+        self.call_stack.clear();
+        self.call_stack.push_back(StackFrame::new(
+            FrameKind::Call,
+            self.pc(),
+            self.read_register(LR_INDEX),
+        ));
+    }
+
+    fn push_frame(&mut self, frame: StackFrame) {
+        if self.call_stack.len() >= MAX_DEPTH {
+            self.call_stack.pop_front();
+        }
+        self.call_stack.push_back(frame);
+    }
+
+    /// After a BL or BLX, which has branched and set LR.
+    pub(crate) fn note_call(&mut self) {
+        let frame = StackFrame::new(
+            FrameKind::Call,
+            self.pc(),
+            self.read_register(LR_INDEX) & !1,
+        );
+        self.push_frame(frame);
+    }
+
+    /// After a PUSH {lr} by the instruction at `address`. A push of an LR that the innermost
+    /// frame doesn't return to means a call that wasn't made with BL, and a second push of the
+    /// same LR a tail call, to the function containing `address` if the symbols say which.
+    pub(crate) fn note_lr_saved(&mut self, address: u32) {
+        let lr = self.read_register(LR_INDEX);
+        let return_address = lr & !1;
+        let entry = self
+            .debug_info
+            .as_ref()
+            .and_then(|debug_info| debug_info.function_at(address))
+            .map(|(_, offset)| address - offset);
+        if let Some(frame) = self.call_stack.back_mut() {
+            let returns_there = match frame.kind {
+                FrameKind::Call => frame.return_address == return_address,
+                FrameKind::Exception => lr >= EXC_RETURN,
+            };
+            if returns_there {
+                if let Some(entry) = entry.filter(|_| frame.lr_saved) {
+                    frame.function = entry;
+                }
+                frame.lr_saved = true;
+                return;
+            }
+        }
+        if lr >= EXC_RETURN {
+            return;
+        }
+        let mut frame = StackFrame::new(FrameKind::Call, address, return_address);
+        frame.lr_saved = true;
+        self.push_frame(frame);
+    }
+
+    /// After a POP {pc} or BX. Returning to a frame's return address leaves it and any frames
+    /// it called that didn't return normally. Other destinations are computed jumps, and
+    /// EXC_RETURN is left to `note_exception_return`.
+    pub(crate) fn note_return(&mut self) {
+        let destination = self.pc();
+        if destination >= EXC_RETURN {
+            return;
+        }
+        let frame = self.call_stack.iter().rposition(|frame| {
+            frame.kind == FrameKind::Exception || frame.return_address == destination
+        });
+        if let Some(index) = frame {
+            // Don't unwind out of an exception handler
+            if self.call_stack[index].kind == FrameKind::Call && index > 0 {
+                self.call_stack.truncate(index);
+            }
+        }
+    }
+
+    /// After taking an exception, with the interrupted PC stacked.
+    pub(crate) fn note_exception(&mut self, interrupted_pc: u32) {
+        let frame = StackFrame::new(FrameKind::Exception, self.pc(), interrupted_pc);
+        self.push_frame(frame);
+    }
+
+    /// After unstacking an exception frame: leaves the innermost handler.
+    pub(crate) fn note_exception_return(&mut self) {
+        if let Some(index) = self
+            .call_stack
+            .iter()
+            .rposition(|frame| frame.kind == FrameKind::Exception)
+        {
+            self.call_stack.truncate(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::DebugInfo;
+    use crate::PC_INDEX;
+
+    const CODE: u32 = Gamebuino::TEST_CODE;
+    const HANDLER: u32 = Gamebuino::TEST_HANDLER;
+
+    const F: u32 = CODE + 0x10;
+    const TAIL: u32 = F + 8;
+    const G: u32 = CODE + 0x20;
+    const H: u32 = CODE + 0x30;
+
+    /// `main` calls `f`, which tail calls `h` if entered at `TAIL`, or calls `g` otherwise.
+    fn code() -> Vec<u16> {
+        let mut code = vec![0; 0x40];
+        let mut place = |address: u32, halves: &[u16]| {
+            let offset = ((address - CODE) / 2) as usize;
+            code[offset..offset + halves.len()].copy_from_slice(halves);
+        };
+        place(
+            CODE,
+            &[
+                0xf000, 0xf806, // main: bl f
+                0xe7fe, // b .
+                0xf000, 0xf807, // bl TAIL
+                0xe7fe, // b .
+            ],
+        );
+        place(
+            F,
+            &[
+                0xb500, // f: push {lr}
+                0xf000, 0xf805, // bl g
+                0xbd00, // pop {pc}
+                0xb500, // TAIL: push {lr}
+                0xbc08, // pop {r3}
+                0x469e, // mov lr, r3
+                0xe007, // b h
+            ],
+        );
+        place(
+            G,
+            &[
+                0x2001, // g: movs r0, #1
+                0x4770, // bx lr
+            ],
+        );
+        place(
+            H,
+            &[
+                0xb500, // h: push {lr}
+                0xbd00, // pop {pc}
+            ],
+        );
+        code
+    }
+
+    /// `code`, with backtraces enabled and an interrupt handler that saves and restores LR.
+    /// Boxed, as the tests would otherwise hold more copies than debug builds' stacks fit.
+    fn program() -> Box<Gamebuino> {
+        let mut gamebuino = Box::new(Gamebuino::for_test(
+            &code(),
+            &[
+                0xb500, // handler: push {lr}
+                0xbd00, // pop {pc}
+            ],
+        ));
+        gamebuino.set_backtraces_enabled(true);
+        gamebuino
+    }
+
+    /// The call stack after each of `steps` steps, as (function, return address) per frame,
+    /// exception frames marked by a return address with the low bit set.
+    fn stacks(gamebuino: &mut Gamebuino, steps: usize) -> Vec<Vec<(u32, u32)>> {
+        (0..steps)
+            .map(|_| {
+                gamebuino.step();
+                gamebuino
+                    .call_stack()
+                    .iter()
+                    .map(|frame| match frame.kind {
+                        FrameKind::Call => (frame.function, frame.return_address),
+                        FrameKind::Exception => (frame.function, frame.return_address | 1),
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn calls_push_frames_and_returns_pop_them() {
+        let mut gamebuino = program();
+        let root = (CODE, gamebuino.read_register(LR_INDEX));
+        let f = (F, CODE + 4);
+        let g = (G, F + 6);
+        assert_eq!(
+            stacks(&mut gamebuino, 8),
+            [
+                vec![root],       // bl f, first half
+                vec![root, f],    // second half
+                vec![root, f],    // push {lr}
+                vec![root, f],    // bl g, first half
+                vec![root, f, g], // second half
+                vec![root, f, g], // movs r0, #1
+                vec![root, f],    // bx lr
+                vec![root],       // pop {pc}
+            ]
+        );
+        assert_eq!(gamebuino.pc(), CODE + 4);
+    }
+
+    #[test]
+    fn a_tail_call_replaces_the_frame() {
+        let mut gamebuino = program();
+        // Start at main's second call. The PC register reads a halfword ahead.
+        gamebuino.set_register(PC_INDEX, CODE + 6 + 2);
+        gamebuino.reset_call_stack();
+        gamebuino.debug_info = Some(DebugInfo::for_test(
+            &[("f", F, 0x10), ("g", G, 4), ("h", H, 4)],
+            "main.c",
+            &[],
+            H + 4,
+        ));
+        let root = (CODE + 6, gamebuino.read_register(LR_INDEX));
+        let tail = (TAIL, CODE + 10);
+        let h = (H, CODE + 10);
+        assert_eq!(
+            stacks(&mut gamebuino, 8),
+            [
+                vec![root],       // bl TAIL, first half
+                vec![root, tail], // second half
+                vec![root, tail], // push {lr}
+                vec![root, tail], // pop {r3}
+                vec![root, tail], // mov lr, r3
+                vec![root, tail], // b h
+                vec![root, h],    // push {lr}: the same LR again
+                vec![root],       // pop {pc}
+            ]
+        );
+    }
+
+    #[test]
+    fn exceptions_push_a_frame_until_they_return() {
+        let mut gamebuino = program();
+        let root = (CODE, gamebuino.read_register(LR_INDEX));
+        gamebuino.step();
+        gamebuino.step();
+        gamebuino.handle_interrupt(HANDLER);
+        let f = (F, CODE + 4);
+        let exception = (HANDLER, F | 1);
+        assert_eq!(
+            gamebuino.backtrace(),
+            [
+                format!(
+                    "#0   0x{:08x} in 0x{:08x}+0x0 <exception>",
+                    HANDLER, HANDLER
+                ),
+                format!("#1   0x{:08x} in 0x{:08x}+0x0", F, F),
+                format!("#2   0x{:08x} in 0x{:08x}+0x4", CODE + 4, CODE),
+            ]
+        );
+        assert_eq!(
+            stacks(&mut gamebuino, 3),
+            [
+                vec![root, f, exception], // push {lr}
+                vec![root, f],            // pop {pc}: EXC_RETURN
+                vec![root, f],            // push {lr}
+            ]
+        );
+        assert_eq!(gamebuino.pc(), F + 2);
+    }
+}
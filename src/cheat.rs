@@ -0,0 +1,222 @@
+//! A cheat engine: finds where a game keeps a value in SRAM by searching and narrowing the
+//! results as the value changes, then freezes it.
+
+use crate::Gamebuino;
+use wasm_bindgen::prelude::*;
+
+const SRAM_ADDRESS: u32 = 0x20000000;
+
+/// How `cheat_narrow` compares each result's current value, to the given value or to the
+/// value it had at the last search or narrowing.
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CheatFilter {
+    Equal,
+    NotEqual,
+    Increased,
+    Decreased,
+    Changed,
+    Unchanged,
+}
+
+#[derive(Clone, Copy)]
+struct Freeze {
+    address: u32,
+    size: u32,
+    value: u32,
+}
+
+pub(crate) struct CheatEngine {
+    size: u32,
+    // Addresses still matching, with their values when last compared
+    results: Vec<(u32, u32)>,
+    freezes: Vec<Freeze>,
+    // The frame the freezes were last applied on
+    frame_count: u32,
+}
+
+impl CheatEngine {
+    pub(crate) fn new() -> CheatEngine {
+        CheatEngine {
+            size: 1,
+            results: Vec::new(),
+            freezes: Vec::new(),
+            frame_count: 0,
+        }
+    }
+
+    /// Whether there are freezes to apply after `frame_count` frames.
+    pub(crate) fn is_due(&self, frame_count: u32) -> bool {
+        !self.freezes.is_empty() && frame_count != self.frame_count
+    }
+}
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// Starts a search for a `size` byte value, 1, 2 or 4, at aligned addresses in SRAM.
+    /// Returns the number of results.
+    pub fn cheat_search(&mut self, value: u32, size: u32) -> usize {
+        let size = match size {
+            2 | 4 => size,
+            _ => 1,
+        };
+        let results = (0..self.sram.len() as u32)
+            .step_by(size as usize)
+            .map(|offset| (SRAM_ADDRESS + offset, self.sram_value(offset, size)))
+            .filter(|&(_, current)| current == truncate(value, size))
+            .collect();
+        self.cheats.size = size;
+        self.cheats.results = results;
+        self.cheats.results.len()
+    }
+
+    /// Keeps the results whose value passes `filter`. `value` is only compared against by
+    /// `Equal` and `NotEqual`. Returns the number of results left.
+    pub fn cheat_narrow(&mut self, filter: CheatFilter, value: u32) -> usize {
+        let size = self.cheats.size;
+        let value = truncate(value, size);
+        let mut results = std::mem::take(&mut self.cheats.results);
+        results.retain_mut(|(address, previous)| {
+            let current = self.sram_value(*address - SRAM_ADDRESS, size);
+            let keep = match filter {
+                CheatFilter::Equal => current == value,
+                CheatFilter::NotEqual => current != value,
+                CheatFilter::Increased => current > *previous,
+                CheatFilter::Decreased => current < *previous,
+                CheatFilter::Changed => current != *previous,
+                CheatFilter::Unchanged => current == *previous,
+            };
+            *previous = current;
+            keep
+        });
+        self.cheats.results = results;
+        self.cheats.results.len()
+    }
+
+    /// Addresses of up to `max_count` results, lowest first.
+    pub fn cheat_results(&self, max_count: usize) -> Vec<u32> {
+        self.cheats
+            .results
+            .iter()
+            .take(max_count)
+            .map(|&(address, _)| address)
+            .collect()
+    }
+
+    pub fn cheat_result_count(&self) -> usize {
+        self.cheats.results.len()
+    }
+
+    /// Writes `value` to the `size` bytes, 1, 2 or 4, at `address` now and at the end of every
+    /// frame.
+    pub fn freeze(&mut self, address: u32, size: u32, value: u32) -> Result<(), String> {
+        if !matches!(size, 1 | 2 | 4) {
+            return Err(format!("cannot freeze {} bytes", size));
+        }
+        self.unfreeze(address);
+        self.cheats.freezes.push(Freeze {
+            address,
+            size,
+            value,
+        });
+        self.poke(address, size, value);
+        Ok(())
+    }
+
+    /// Returns whether the address was frozen.
+    pub fn unfreeze(&mut self, address: u32) -> bool {
+        let count = self.cheats.freezes.len();
+        self.cheats
+            .freezes
+            .retain(|freeze| freeze.address != address);
+        self.cheats.freezes.len() != count
+    }
+
+    /// Forgets the search results and unfreezes everything.
+    pub fn clear_cheats(&mut self) {
+        self.cheats = CheatEngine::new();
+    }
+}
+
+impl Gamebuino {
+    /// Called by `step` once a frame has been completed.
+    pub(crate) fn apply_freezes(&mut self) {
+        self.cheats.frame_count = self.screen.frame_count;
+        for i in 0..self.cheats.freezes.len() {
+            let freeze = self.cheats.freezes[i];
+            self.poke(freeze.address, freeze.size, freeze.value);
+        }
+    }
+
+    fn sram_value(&self, offset: u32, size: u32) -> u32 {
+        (0..size)
+            .map(|i| {
+                let byte = self.sram.get((offset + i) as usize).copied().unwrap_or(0);
+                (byte as u32) << (8 * i)
+            })
+            .fold(0, |value, byte| value | byte)
+    }
+}
+
+fn truncate(value: u32, size: u32) -> u32 {
+    match size {
+        4 => value,
+        _ => value & ((1 << (8 * size)) - 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn narrowing_compares_against_the_last_values() {
+        let mut gamebuino = Gamebuino::new();
+        for &offset in &[0x10, 0x20, 0x31] {
+            gamebuino.sram[offset] = 7;
+        }
+        assert_eq!(gamebuino.cheat_search(7, 1), 3);
+
+        gamebuino.sram[0x10] = 8;
+        gamebuino.sram[0x20] = 6;
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::Changed, 0), 2);
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::Unchanged, 0), 2);
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::Increased, 0), 0);
+
+        assert_eq!(gamebuino.cheat_search(7, 1), 1);
+        gamebuino.sram[0x31] = 9;
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::Increased, 0), 1);
+        assert_eq!(gamebuino.cheat_results(10), vec![SRAM_ADDRESS + 0x31]);
+        gamebuino.sram[0x31] = 3;
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::Decreased, 0), 1);
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::NotEqual, 0x103), 0);
+    }
+
+    #[test]
+    fn searches_aligned_values_of_the_given_size() {
+        let mut gamebuino = Gamebuino::new();
+        gamebuino.sram[0x40] = 0x34;
+        gamebuino.sram[0x41] = 0x12;
+        gamebuino.sram[0x51] = 0x34;
+        gamebuino.sram[0x52] = 0x12;
+        assert_eq!(gamebuino.cheat_search(0x51234, 2), 1);
+        assert_eq!(gamebuino.cheat_results(10), vec![SRAM_ADDRESS + 0x40]);
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::Equal, 0x1234), 1);
+
+        gamebuino.sram[0x41] = 0x13;
+        assert_eq!(gamebuino.cheat_narrow(CheatFilter::Equal, 0x1234), 0);
+    }
+
+    #[test]
+    fn freezes_only_whole_values() {
+        let mut gamebuino = Gamebuino::new();
+        assert!(gamebuino.freeze(0x4100, 8, 0).is_err());
+        assert!(gamebuino.freeze(SRAM_ADDRESS, 3, 0).is_err());
+        assert!(gamebuino.cheats.freezes.is_empty());
+
+        gamebuino.freeze(SRAM_ADDRESS, 2, 0xabcd).unwrap();
+        assert_eq!(&gamebuino.sram[..2], &[0xcd, 0xab]);
+        assert!(gamebuino.unfreeze(SRAM_ADDRESS));
+        assert!(!gamebuino.unfreeze(SRAM_ADDRESS));
+    }
+}
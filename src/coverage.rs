@@ -0,0 +1,312 @@
+//! Code coverage over the decoded program: which instructions ran, and which ways conditional
+//! branches went.
+
+use crate::disassembler;
+use crate::instruction::Instruction;
+use crate::Gamebuino;
+use std::collections::BTreeMap;
+use wasm_bindgen::prelude::*;
+
+/// One bit per entry of `Gamebuino::instructions`, lowest bit of the first byte first.
+struct Bitmap(Vec<u8>);
+
+impl Bitmap {
+    fn new(bits: usize) -> Bitmap {
+        Bitmap(vec![0; bits.div_ceil(8)])
+    }
+
+    fn set(&mut self, index: usize) {
+        if let Some(byte) = self.0.get_mut(index / 8) {
+            *byte |= 1 << (index % 8);
+        }
+    }
+
+    fn get(&self, index: usize) -> bool {
+        self.0
+            .get(index / 8)
+            .is_some_and(|byte| byte & (1 << (index % 8)) != 0)
+    }
+}
+
+pub(crate) struct Coverage {
+    executed: Bitmap,
+    taken: Bitmap,
+    not_taken: Bitmap,
+}
+
+impl Coverage {
+    fn new(instruction_count: usize) -> Coverage {
+        Coverage {
+            executed: Bitmap::new(instruction_count),
+            taken: Bitmap::new(instruction_count),
+            not_taken: Bitmap::new(instruction_count),
+        }
+    }
+}
+
+/// Lines of one source file: whether each ran, and its conditional branches.
+#[derive(Default)]
+struct FileCoverage {
+    lines: BTreeMap<u32, bool>,
+    // Per line, each branch instruction's taken and not taken, or None if it never ran
+    branches: BTreeMap<u32, Vec<Option<(bool, bool)>>>,
+    functions: Vec<(u32, String, bool)>,
+}
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// Starts recording coverage of the loaded program afresh.
+    pub fn start_coverage(&mut self) {
+        self.coverage = Some(Box::new(Coverage::new(self.instructions.len())));
+    }
+
+    pub fn stop_coverage(&mut self) {
+        self.coverage = None;
+    }
+
+    /// Address of the instruction the first bit of the coverage bitmaps is for. Each following
+    /// bit is for the halfword after.
+    pub fn coverage_start_address(&self) -> u32 {
+        self.program_offset
+    }
+
+    /// Which instructions have run.
+    pub fn coverage_bitmap(&self) -> Vec<u8> {
+        self.coverage
+            .as_ref()
+            .map_or(Vec::new(), |coverage| coverage.executed.0.clone())
+    }
+
+    /// Which conditional branches have been taken.
+    pub fn coverage_taken_bitmap(&self) -> Vec<u8> {
+        self.coverage
+            .as_ref()
+            .map_or(Vec::new(), |coverage| coverage.taken.0.clone())
+    }
+
+    /// Which conditional branches have fallen through.
+    pub fn coverage_not_taken_bitmap(&self) -> Vec<u8> {
+        self.coverage
+            .as_ref()
+            .map_or(Vec::new(), |coverage| coverage.not_taken.0.clone())
+    }
+
+    /// The coverage as an lcov tracefile, if an ELF with line information is loaded. A line
+    /// counts as run once any of its instructions has, so hit counts are 0 or 1.
+    pub fn coverage_lcov(&self) -> String {
+        let (coverage, debug_info) = match (self.coverage.as_ref(), self.debug_info.as_ref()) {
+            (Some(coverage), Some(debug_info)) if debug_info.has_lines() => (coverage, debug_info),
+            _ => return String::new(),
+        };
+
+        let mut files: BTreeMap<&str, FileCoverage> = BTreeMap::new();
+        // Walk instruction starts, skipping the second halves of 32-bit instructions
+        let mut next_index = 0;
+        while let Some(&instruction) = self.instructions.get(next_index) {
+            let index = next_index;
+            next_index += disassembler::instruction_size(instruction) as usize / 2;
+            if let Instruction::Bl { first: false, .. } = instruction {
+                continue;
+            }
+            let address = self.program_offset + index as u32 * 2;
+            let (file, line) = match debug_info.line_at(address) {
+                Some(location) => location,
+                None => continue,
+            };
+            let file = files.entry(file).or_default();
+            let executed = coverage.executed.get(index);
+            *file.lines.entry(line).or_insert(false) |= executed;
+            if is_conditional_branch(instruction) {
+                let outcome = Some((coverage.taken.get(index), coverage.not_taken.get(index)))
+                    .filter(|_| executed);
+                file.branches.entry(line).or_default().push(outcome);
+            }
+        }
+        for function in debug_info.functions() {
+            if let Some((file, line)) = debug_info.line_at(function.address) {
+                let index = (function.address.wrapping_sub(self.program_offset) >> 1) as usize;
+                let executed = coverage.executed.get(index);
+                files.entry(file).or_default().functions.push((
+                    line,
+                    function.name.clone(),
+                    executed,
+                ));
+            }
+        }
+
+        let mut output = String::new();
+        for (name, file) in files {
+            output.push_str(&format!("TN:\nSF:{}\n", name));
+            for (line, function, _) in &file.functions {
+                output.push_str(&format!("FN:{},{}\n", line, function));
+            }
+            for (_, function, executed) in &file.functions {
+                output.push_str(&format!("FNDA:{},{}\n", *executed as u32, function));
+            }
+            let functions_hit = file.functions.iter().filter(|f| f.2).count();
+            output.push_str(&format!(
+                "FNF:{}\nFNH:{}\n",
+                file.functions.len(),
+                functions_hit
+            ));
+
+            let mut branches_found = 0;
+            let mut branches_hit = 0;
+            for (line, branches) in &file.branches {
+                for (block, outcome) in branches.iter().enumerate() {
+                    let counts = match outcome {
+                        Some((taken, not_taken)) => [*taken, *not_taken].map(|hit| {
+                            branches_hit += hit as u32;
+                            (hit as u32).to_string()
+                        }),
+                        None => ["-".to_string(), "-".to_string()],
+                    };
+                    for (branch, count) in counts.iter().enumerate() {
+                        output.push_str(&format!("BRDA:{},{},{},{}\n", line, block, branch, count));
+                    }
+                    branches_found += 2;
+                }
+            }
+            output.push_str(&format!("BRF:{}\nBRH:{}\n", branches_found, branches_hit));
+
+            for (line, executed) in &file.lines {
+                output.push_str(&format!("DA:{},{}\n", line, *executed as u32));
+            }
+            let lines_hit = file.lines.values().filter(|&&executed| executed).count();
+            output.push_str(&format!(
+                "LF:{}\nLH:{}\nend_of_record\n",
+                file.lines.len(),
+                lines_hit
+            ));
+        }
+        output
+    }
+}
+
+impl Gamebuino {
+    /// Called by `step` right after the instruction at `pc` has run, before an interrupt can
+    /// move the PC.
+    pub(crate) fn record_coverage(&mut self, pc: u32, instruction: Instruction) {
+        let index = (pc.wrapping_sub(self.program_offset) >> 1) as usize;
+        let taken = self.pc() != pc.wrapping_add(2);
+        if let Some(coverage) = self.coverage.as_mut() {
+            coverage.executed.set(index);
+            if is_conditional_branch(instruction) {
+                if taken {
+                    coverage.taken.set(index);
+                } else {
+                    coverage.not_taken.set(index);
+                }
+            }
+        }
+    }
+}
+
+fn is_conditional_branch(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::Beq { .. }
+            | Instruction::Bne { .. }
+            | Instruction::Bcs { .. }
+            | Instruction::Bcc { .. }
+            | Instruction::Bmi { .. }
+            | Instruction::Bpl { .. }
+            | Instruction::Bvs { .. }
+            | Instruction::Bcv { .. }
+            | Instruction::Bhi { .. }
+            | Instruction::Bls { .. }
+            | Instruction::Bge { .. }
+            | Instruction::Blt { .. }
+            | Instruction::Bgt { .. }
+            | Instruction::Ble { .. }
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::DebugInfo;
+
+    const START: u32 = 0x4000;
+
+    /// Two functions: `main` runs a conditional branch and calls `helper`, which never runs.
+    fn gamebuino() -> Gamebuino {
+        let code: [u16; 8] = [
+            0x2000, // movs r0, #0          main.c:10
+            0x2800, // cmp r0, #0
+            0xd000, // beq.n 0x4008         main.c:11
+            0xf000, // bl 0x400c            main.c:12
+            0xf801, 0xe7fe, // b.n 0x400a           main.c:13
+            0x4770, // bx lr                main.c:20, helper
+            0xbf00, // nop
+        ];
+        let program: Vec<u8> = code.iter().flat_map(|half| half.to_le_bytes()).collect();
+        let mut gamebuino = Gamebuino::new();
+        gamebuino.load_program(&program, START);
+        gamebuino.debug_info = Some(DebugInfo::for_test(
+            &[("main", START, 12), ("helper", START + 12, 4)],
+            "main.c",
+            &[
+                (START, 10),
+                (START + 4, 11),
+                (START + 6, 12),
+                (START + 10, 13),
+                (START + 12, 20),
+            ],
+            START + 16,
+        ));
+        gamebuino
+    }
+
+    #[test]
+    fn lcov_without_coverage_is_empty() {
+        assert_eq!(gamebuino().coverage_lcov(), "");
+    }
+
+    #[test]
+    fn lcov_records_lines_functions_and_branches() {
+        let mut gamebuino = gamebuino();
+        gamebuino.start_coverage();
+        let coverage = gamebuino.coverage.as_mut().unwrap();
+        // Up to the BL, with the branch falling through
+        for index in [0, 1, 2, 3] {
+            coverage.executed.set(index);
+        }
+        coverage.not_taken.set(2);
+
+        assert_eq!(
+            gamebuino.coverage_lcov(),
+            "TN:\n\
+             SF:main.c\n\
+             FN:10,main\n\
+             FN:20,helper\n\
+             FNDA:1,main\n\
+             FNDA:0,helper\n\
+             FNF:2\n\
+             FNH:1\n\
+             BRDA:11,0,0,0\n\
+             BRDA:11,0,1,1\n\
+             BRF:2\n\
+             BRH:1\n\
+             DA:10,1\n\
+             DA:11,1\n\
+             DA:12,1\n\
+             DA:13,0\n\
+             DA:20,0\n\
+             LF:5\n\
+             LH:3\n\
+             end_of_record\n"
+        );
+    }
+
+    #[test]
+    fn lcov_skips_second_halves() {
+        let mut gamebuino = gamebuino();
+        gamebuino.start_coverage();
+        // Only the second half of the BL, which isn't an instruction of its own
+        gamebuino.coverage.as_mut().unwrap().executed.set(4);
+        let lcov = gamebuino.coverage_lcov();
+        assert!(lcov.contains("DA:12,0\n"), "{}", lcov);
+        assert!(lcov.contains("LH:0\n"), "{}", lcov);
+    }
+}
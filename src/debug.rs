@@ -0,0 +1,358 @@
+//! Breakpoints and watchpoints that stop `Gamebuino::run` and `Gamebuino::run_until_frame`.
+
+use crate::{Gamebuino, PC_INDEX};
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+impl WatchKind {
+    fn matches(self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+/// Watches `length` bytes from `address` for accesses of the given kind.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub length: u32,
+    pub kind: WatchKind,
+}
+
+/// An access that matched a watchpoint. `address` is where the access of `size` bytes started,
+/// which may be before the watched range for a wider access overlapping it.
+#[derive(Clone, Copy, Debug)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub address: u32,
+    pub size: u32,
+    pub write: bool,
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum StopKind {
+    TicksExhausted,
+    Breakpoint,
+    Watchpoint,
+    /// `run_until_frame` saw a full frame pushed to the screen.
+    FrameCompleted,
+}
+
+/// Why `run` or `run_until_frame` returned.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct StopReason {
+    kind: StopKind,
+    pc: u32,
+    hit: Option<WatchHit>,
+    value: u32,
+    ticks: u32,
+}
+
+#[wasm_bindgen]
+impl StopReason {
+    pub fn kind(&self) -> StopKind {
+        self.kind
+    }
+
+    /// Address of the next instruction to run.
+    pub fn pc(&self) -> u32 {
+        self.pc
+    }
+
+    /// For a watchpoint, the address accessed.
+    pub fn address(&self) -> u32 {
+        self.hit.map_or(0, |hit| hit.address)
+    }
+
+    /// For a watchpoint, the value at the address accessed once the instruction has run, i.e.
+    /// the value read or written.
+    pub fn value(&self) -> u32 {
+        self.value
+    }
+
+    /// For a watchpoint, whether the access was a write.
+    pub fn is_write(&self) -> bool {
+        self.hit.is_some_and(|hit| hit.write)
+    }
+
+    /// Number of ticks run before stopping.
+    pub fn ticks(&self) -> u32 {
+        self.ticks
+    }
+}
+
+impl StopReason {
+    pub fn watch_hit(&self) -> Option<WatchHit> {
+        self.hit
+    }
+
+    pub(crate) fn new(kind: StopKind, pc: u32) -> StopReason {
+        StopReason {
+            kind,
+            pc,
+            hit: None,
+            value: 0,
+            ticks: 0,
+        }
+    }
+
+    pub(crate) fn with_ticks(self, ticks: u64) -> StopReason {
+        StopReason {
+            ticks: ticks as u32,
+            ..self
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// Stops `run` before the instruction at `address` executes, including when `run` starts
+    /// there, unless it is resuming from a stop at this breakpoint.
+    pub fn add_breakpoint(&mut self, address: u32) {
+        self.breakpoints.insert(address & !1);
+    }
+
+    /// Returns whether the breakpoint was set.
+    pub fn remove_breakpoint(&mut self, address: u32) -> bool {
+        self.breakpoints.remove(&(address & !1))
+    }
+
+    /// Stops `run` after an instruction accesses `length` bytes from `address` in the given way.
+    pub fn add_watchpoint(&mut self, address: u32, length: u32, kind: WatchKind) {
+        let watchpoint = Watchpoint {
+            address,
+            length,
+            kind,
+        };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+        self.update_memory_observation();
+    }
+
+    /// Returns whether the watchpoint was set.
+    pub fn remove_watchpoint(&mut self, address: u32, length: u32, kind: WatchKind) -> bool {
+        let watchpoint = Watchpoint {
+            address,
+            length,
+            kind,
+        };
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|&w| w != watchpoint);
+        self.update_memory_observation();
+        self.watchpoints.len() != count
+    }
+
+    pub fn clear_breakpoints_and_watchpoints(&mut self) {
+        self.breakpoints.clear();
+        self.watchpoints.clear();
+        self.update_memory_observation();
+        self.watch_hit.set(None);
+    }
+}
+
+impl Gamebuino {
+    /// Address of the next instruction to run. The PC register is kept one instruction ahead.
+    pub fn pc(&self) -> u32 {
+        self.read_register(PC_INDEX).wrapping_sub(2)
+    }
+
+    /// Checked by `run` and `run_until_frame` before the first step: a breakpoint on the
+    /// instruction they start from, unless the last run stopped for it and nothing has run since,
+    /// in which case resuming runs it.
+    pub(crate) fn check_start(&mut self) -> Option<StopReason> {
+        let pc = self.pc();
+        let resuming = self.breakpoint_stop.take() == Some((pc, self.tick_count));
+        if !resuming && self.breakpoints.contains(&pc) {
+            self.breakpoint_stop = Some((pc, self.tick_count));
+            return Some(StopReason::new(StopKind::Breakpoint, pc));
+        }
+        None
+    }
+
+    /// Checked by `run` and `run_until_frame` after each step: a watched access by the
+    /// instruction just run, or a breakpoint on the next one.
+    pub(crate) fn check_stop(&mut self) -> Option<StopReason> {
+        let pc = self.pc();
+        if let Some(hit) = self.watch_hit.take() {
+            return Some(StopReason {
+                kind: StopKind::Watchpoint,
+                pc,
+                hit: Some(hit),
+                value: self.debugger_read(hit.address, hit.size),
+                ticks: 0,
+            });
+        }
+        if !self.breakpoints.is_empty() && self.breakpoints.contains(&pc) {
+            self.breakpoint_stop = Some((pc, self.tick_count));
+            return Some(StopReason::new(StopKind::Breakpoint, pc));
+        }
+        None
+    }
+
+    /// Forgets any watched access made before resuming.
+    pub(crate) fn clear_watch_hit(&mut self) {
+        self.watch_hit.set(None);
+    }
+
+    /// Called by the memory accessors for every access of `size` bytes while watchpoints are
+    /// set.
+    pub(crate) fn check_watchpoints(&self, address: u32, size: u32, write: bool) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+        let end = address.wrapping_add(size);
+        let hit = self.watchpoints.iter().find(|w| {
+            w.kind.matches(write) && address < w.address.wrapping_add(w.length) && w.address < end
+        });
+        if let Some(&watchpoint) = hit {
+            self.watch_hit.set(Some(WatchHit {
+                watchpoint,
+                address,
+                size,
+                write,
+            }));
+        }
+    }
+
+    /// Reads memory on behalf of a debugger, without triggering watchpoints or being traced.
+    pub(crate) fn debugger_read(&self, address: u32, size: u32) -> u32 {
+        match size {
+            4 => self.load_word(address),
+            2 => self.load_half_word(address) as u32,
+            _ => self.load_byte(address) as u32,
+        }
+    }
+
+    /// Writes memory on behalf of a debugger, without triggering watchpoints or being traced.
+    pub(crate) fn debugger_write(&mut self, address: u32, size: u32, value: u32) {
+        match size {
+            4 => self.store_word(address, value),
+            2 => self.store_half_word(address, value),
+            _ => self.store_byte(address, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COUNTER: u32 = 0x20000100;
+
+    /// Stores an incrementing counter to `COUNTER` and reads it back, forever.
+    fn gamebuino() -> Gamebuino {
+        Gamebuino::for_test(
+            &[
+                0x2001, // movs r0, #1
+                0x4902, // ldr r1, [pc, #8]
+                0x6008, // loop: str r0, [r1]
+                0x680a, // ldr r2, [r1]
+                0x3001, // adds r0, #1
+                0xe7fb, // b loop
+                0x0100, // COUNTER
+                0x2000, //
+            ],
+            &[],
+        )
+    }
+
+    const LOOP: u32 = Gamebuino::TEST_CODE + 4;
+
+    #[test]
+    fn breakpoints_stop_before_the_instruction_runs() {
+        let mut gamebuino = gamebuino();
+        gamebuino.add_breakpoint(LOOP + 4);
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::Breakpoint);
+        assert_eq!(stop.pc(), LOOP + 4);
+        assert_eq!(stop.ticks(), 4);
+        assert_eq!(gamebuino.read_register(2), 1);
+
+        // Resuming runs the instruction stopped at, then stops there the next time round. The
+        // taken branch takes two ticks.
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!(
+            (stop.kind(), stop.pc(), stop.ticks()),
+            (StopKind::Breakpoint, LOOP + 4, 5)
+        );
+        assert_eq!(gamebuino.read_register(2), 2);
+
+        assert!(gamebuino.remove_breakpoint(LOOP + 4));
+        assert!(!gamebuino.remove_breakpoint(LOOP + 4));
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!((stop.kind(), stop.ticks()), (StopKind::TicksExhausted, 100));
+    }
+
+    #[test]
+    fn breakpoints_where_run_starts_are_reported() {
+        let mut gamebuino = gamebuino();
+        gamebuino.add_breakpoint(Gamebuino::TEST_CODE);
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::Breakpoint);
+        assert_eq!((stop.pc(), stop.ticks()), (Gamebuino::TEST_CODE, 0));
+        assert_eq!(gamebuino.read_register(0), 0);
+
+        let stop = gamebuino.run_until_frame(100, 0xff);
+        assert_eq!((stop.kind(), stop.ticks()), (StopKind::TicksExhausted, 100));
+    }
+
+    #[test]
+    fn watchpoints_stop_after_the_access_with_its_value() {
+        let mut gamebuino = gamebuino();
+        gamebuino.add_watchpoint(COUNTER, 4, WatchKind::Write);
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::Watchpoint);
+        assert_eq!(stop.pc(), LOOP + 2);
+        assert_eq!(
+            (stop.address(), stop.value(), stop.is_write()),
+            (COUNTER, 1, true)
+        );
+
+        // The read back isn't a write
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!((stop.pc(), stop.value(), stop.ticks()), (LOOP + 2, 2, 5));
+    }
+
+    #[test]
+    fn watchpoints_match_overlapping_accesses_of_their_kind() {
+        let mut gamebuino = gamebuino();
+        gamebuino.add_watchpoint(COUNTER + 3, 1, WatchKind::Read);
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::Watchpoint);
+        assert_eq!(stop.pc(), LOOP + 4);
+        let hit = stop.watch_hit().unwrap();
+        assert_eq!((hit.address, hit.size, hit.write), (COUNTER, 4, false));
+        assert_eq!(hit.watchpoint.address, COUNTER + 3);
+
+        assert!(gamebuino.remove_watchpoint(COUNTER + 3, 1, WatchKind::Read));
+        gamebuino.add_watchpoint(COUNTER + 4, 4, WatchKind::Access);
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::TicksExhausted);
+    }
+
+    #[test]
+    fn debugger_accesses_dont_hit_watchpoints() {
+        let mut gamebuino = gamebuino();
+        gamebuino.add_watchpoint(COUNTER, 4, WatchKind::Access);
+        gamebuino.poke_word(COUNTER, 7);
+        assert_eq!(gamebuino.debugger_read(COUNTER, 4), 7);
+        let _ = gamebuino.disassemble(Gamebuino::TEST_CODE, 8);
+
+        let stop = gamebuino.run(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::Watchpoint);
+        assert_eq!((stop.pc(), stop.value()), (LOOP + 2, 1));
+    }
+}
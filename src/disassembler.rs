@@ -0,0 +1,260 @@
+//! Renders decoded instructions as UAL assembly.
+
+use crate::instruction::Instruction;
+
+const REGISTER_NAMES: [&str; 16] = [
+    "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7", "r8", "r9", "r10", "r11", "r12", "sp", "lr",
+    "pc",
+];
+
+pub fn register_name(register: u8) -> &'static str {
+    REGISTER_NAMES[register as usize & 0xf]
+}
+
+/// Number of bytes the instruction takes, including the second half of 32-bit instructions,
+/// which are decoded into their own entries too.
+pub fn instruction_size(instruction: Instruction) -> u32 {
+    match instruction {
+        Instruction::Bl { first: true, .. } | Instruction::Dmb => 4,
+        _ => 2,
+    }
+}
+
+/// Formats `instruction`, found at `address` with the halfword `opcode`, in UAL syntax.
+/// Branch targets are resolved, and `read_word` provides the literal pool values of PC-relative
+/// loads.
+pub fn format_instruction(
+    instruction: Instruction,
+    address: u32,
+    opcode: u16,
+    read_word: impl Fn(u32) -> u32,
+) -> String {
+    // The PC reads as the instruction address plus 4
+    let pc = address.wrapping_add(4);
+    let r = register_name;
+    let memory = |rb: u8, offset: u32| {
+        if offset == 0 {
+            format!("[{}]", r(rb))
+        } else {
+            format!("[{}, #{}]", r(rb), offset)
+        }
+    };
+    let branch =
+        |mnemonic: &str, offset: u32| format!("{} 0x{:08x}", mnemonic, pc.wrapping_add(offset));
+
+    match instruction {
+        Instruction::LslImm { rs, rd, offset: 0 } => format!("movs {}, {}", r(rd), r(rs)),
+        Instruction::LslImm { rs, rd, offset } => format!("lsls {}, {}, #{}", r(rd), r(rs), offset),
+        Instruction::LsrImm { rs, rd, offset } => {
+            format!("lsrs {}, {}, #{}", r(rd), r(rs), shift_amount(offset))
+        }
+        Instruction::AsrImm { rs, rd, offset } => {
+            format!("asrs {}, {}, #{}", r(rd), r(rs), shift_amount(offset))
+        }
+        Instruction::LslReg { rs, rd } => format!("lsls {}, {}", r(rd), r(rs)),
+        Instruction::LsrReg { rs, rd } => format!("lsrs {}, {}", r(rd), r(rs)),
+        Instruction::AsrReg { rs, rd } => format!("asrs {}, {}", r(rd), r(rs)),
+        // The high register form doesn't set flags
+        Instruction::AddReg { rs, rd, rn } if rd >= 8 || rs >= 8 || rn >= 8 => {
+            format!("add {}, {}", r(rd), r(rs))
+        }
+        Instruction::AddReg { rs, rd, rn } => format!("adds {}, {}, {}", r(rd), r(rs), r(rn)),
+        Instruction::AddImm { rs, rd, offset } if rs == rd => {
+            format!("adds {}, #{}", r(rd), offset)
+        }
+        Instruction::AddImm { rs, rd, offset } => {
+            format!("adds {}, {}, #{}", r(rd), r(rs), offset)
+        }
+        Instruction::AddSp { rd, offset } if rd == crate::SP_INDEX => {
+            if (offset as i32) < 0 {
+                format!("sub sp, #{}", (offset as i32).unsigned_abs())
+            } else {
+                format!("add sp, #{}", offset)
+            }
+        }
+        Instruction::AddSp { rd, offset } => format!("add {}, sp, #{}", r(rd), offset),
+        Instruction::AddPc { rd, offset } => {
+            format!("adr {}, 0x{:08x}", r(rd), (pc & !0b11).wrapping_add(offset))
+        }
+        Instruction::Adc { rs, rd } => format!("adcs {}, {}", r(rd), r(rs)),
+        Instruction::SubReg { rs, rd, rn } => format!("subs {}, {}, {}", r(rd), r(rs), r(rn)),
+        Instruction::SubImm { rs, rd, offset } if rs == rd => {
+            format!("subs {}, #{}", r(rd), offset)
+        }
+        Instruction::SubImm { rs, rd, offset } => {
+            format!("subs {}, {}, #{}", r(rd), r(rs), offset)
+        }
+        Instruction::Sbc { rs, rd } => format!("sbcs {}, {}", r(rd), r(rs)),
+        Instruction::Neg { rs, rd } => format!("rsbs {}, {}, #0", r(rd), r(rs)),
+        Instruction::Mul { rs, rd } => format!("muls {}, {}, {}", r(rd), r(rs), r(rd)),
+        Instruction::MovImm { rd, offset } => format!("movs {}, #{}", r(rd), offset),
+        Instruction::MovReg { rs, rd } => format!("mov {}, {}", r(rd), r(rs)),
+        Instruction::Mvn { rs, rd } => format!("mvns {}, {}", r(rd), r(rs)),
+        Instruction::CmpImm { rd, offset } => format!("cmp {}, #{}", r(rd), offset),
+        Instruction::CmpReg { rs, rd } => format!("cmp {}, {}", r(rd), r(rs)),
+        Instruction::Cmn { rs, rd } => format!("cmn {}, {}", r(rd), r(rs)),
+        Instruction::Tst { rs, rd } => format!("tst {}, {}", r(rd), r(rs)),
+        Instruction::And { rs, rd } => format!("ands {}, {}", r(rd), r(rs)),
+        Instruction::Bic { rs, rd } => format!("bics {}, {}", r(rd), r(rs)),
+        Instruction::Eor { rs, rd } => format!("eors {}, {}", r(rd), r(rs)),
+        Instruction::Oor { rs, rd } => format!("orrs {}, {}", r(rd), r(rs)),
+        Instruction::Bx { rs } => format!("bx {}", r(rs)),
+        Instruction::Blx { rm } => format!("blx {}", r(rm)),
+        Instruction::LdrPc {
+            rd,
+            immediate_value,
+        } => {
+            let literal = (pc & !0b11).wrapping_add(immediate_value);
+            format!(
+                "ldr {}, [pc, #{}] ; [0x{:08x}] = 0x{:08x}",
+                r(rd),
+                immediate_value,
+                literal,
+                read_word(literal)
+            )
+        }
+        Instruction::LdrReg { rb, ro, rd } => format!("ldr {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::LdrbReg { rb, ro, rd } => format!("ldrb {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::LdrhReg { rb, ro, rd } => format!("ldrh {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::Ldsb { rb, ro, rd } => format!("ldrsb {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::Ldsh { rb, ro, rd } => format!("ldrsh {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::LdrImm { rb, offset, rd } => format!("ldr {}, {}", r(rd), memory(rb, offset)),
+        Instruction::LdrbImm { rb, offset, rd } => {
+            format!("ldrb {}, {}", r(rd), memory(rb, offset))
+        }
+        Instruction::LdrhImm { rb, offset, rd } => {
+            format!("ldrh {}, {}", r(rd), memory(rb, offset))
+        }
+        Instruction::StrReg { rb, ro, rd } => format!("str {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::StrbReg { rb, ro, rd } => format!("strb {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::StrhReg { rb, ro, rd } => format!("strh {}, [{}, {}]", r(rd), r(rb), r(ro)),
+        Instruction::StrImm { rb, offset, rd } => format!("str {}, {}", r(rd), memory(rb, offset)),
+        Instruction::StrbImm { rb, offset, rd } => {
+            format!("strb {}, {}", r(rd), memory(rb, offset))
+        }
+        Instruction::StrhImm { rb, offset, rd } => {
+            format!("strh {}, {}", r(rd), memory(rb, offset))
+        }
+        // The base register is only written back when it isn't loaded
+        Instruction::Ldmia { rb, rlist } => {
+            let writeback = if rlist & (1 << rb) == 0 { "!" } else { "" };
+            format!("ldm {}{}, {}", r(rb), writeback, register_list(rlist, None))
+        }
+        Instruction::Stmia { rb, rlist } => {
+            format!("stm {}!, {}", r(rb), register_list(rlist, None))
+        }
+        Instruction::Sxth { rd, rm } => format!("sxth {}, {}", r(rd), r(rm)),
+        Instruction::Sxtb { rd, rm } => format!("sxtb {}, {}", r(rd), r(rm)),
+        Instruction::Uxth { rd, rm } => format!("uxth {}, {}", r(rd), r(rm)),
+        Instruction::Uxtb { rd, rm } => format!("uxtb {}, {}", r(rd), r(rm)),
+        Instruction::Rev { rd, rm } => format!("rev {}, {}", r(rd), r(rm)),
+        Instruction::Rev16 { rd, rm } => format!("rev16 {}, {}", r(rd), r(rm)),
+        Instruction::Push { rlist, lr } => {
+            format!("push {}", register_list(rlist, Some("lr").filter(|_| lr)))
+        }
+        Instruction::Pop { rlist, pc } => {
+            format!("pop {}", register_list(rlist, Some("pc").filter(|_| pc)))
+        }
+        Instruction::Beq { offset } => branch("beq", offset),
+        Instruction::Bne { offset } => branch("bne", offset),
+        Instruction::Bcs { offset } => branch("bcs", offset),
+        Instruction::Bcc { offset } => branch("bcc", offset),
+        Instruction::Bmi { offset } => branch("bmi", offset),
+        Instruction::Bpl { offset } => branch("bpl", offset),
+        Instruction::Bvs { offset } => branch("bvs", offset),
+        Instruction::Bcv { offset } => branch("bvc", offset),
+        Instruction::Bhi { offset } => branch("bhi", offset),
+        Instruction::Bls { offset } => branch("bls", offset),
+        Instruction::Bge { offset } => branch("bge", offset),
+        Instruction::Blt { offset } => branch("blt", offset),
+        Instruction::Bgt { offset } => branch("bgt", offset),
+        Instruction::Ble { offset } => branch("ble", offset),
+        Instruction::B { offset } => branch("b", offset),
+        Instruction::Bl {
+            offset1,
+            offset2,
+            first: true,
+        } => branch("bl", offset1.wrapping_add(offset2)),
+        Instruction::Dmb => "dmb sy".to_string(),
+        // The second half of a BL only makes sense together with the first
+        Instruction::Bl { first: false, .. } | Instruction::NotImplemented => {
+            format!(".inst.n 0x{:04x}", opcode)
+        }
+    }
+}
+
+/// Shifts right by 0 encode shifts by 32.
+fn shift_amount(offset: u8) -> u8 {
+    if offset == 0 {
+        32
+    } else {
+        offset
+    }
+}
+
+fn register_list(rlist: u8, extra: Option<&str>) -> String {
+    let names: Vec<&str> = (0..8)
+        .filter(|i| rlist & (1 << i) != 0)
+        .map(|i| REGISTER_NAMES[i])
+        .chain(extra)
+        .collect();
+    format!("{{{}}}", names.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::parse_instruction;
+
+    fn disassemble(address: u32, opcode: u16, following: u16) -> String {
+        let instruction = parse_instruction(opcode, following);
+        format_instruction(instruction, address, opcode, |address| {
+            assert_eq!(address, 0x4010);
+            0xdeadbeef
+        })
+    }
+
+    #[test]
+    fn formats_data_processing() {
+        assert_eq!(disassemble(0x4000, 0x2000, 0), "movs r0, #0");
+        assert_eq!(disassemble(0x4000, 0x1c48, 0), "adds r0, r1, #1");
+        assert_eq!(disassemble(0x4000, 0x0088, 0), "lsls r0, r1, #2");
+        assert_eq!(disassemble(0x4000, 0x4298, 0), "cmp r0, r3");
+        assert_eq!(disassemble(0x4000, 0x4685, 0), "mov sp, r0");
+        assert_eq!(disassemble(0x4000, 0xb082, 0), "sub sp, #8");
+        assert_eq!(disassemble(0x4000, 0xa901, 0), "add r1, sp, #4");
+    }
+
+    #[test]
+    fn formats_loads_and_stores() {
+        assert_eq!(
+            disassemble(0x4000, 0x4a03, 0),
+            "ldr r2, [pc, #12] ; [0x00004010] = 0xdeadbeef"
+        );
+        assert_eq!(disassemble(0x4000, 0x8843, 0), "ldrh r3, [r0, #2]");
+        assert_eq!(disassemble(0x4000, 0x5cd1, 0), "ldrb r1, [r2, r3]");
+        assert_eq!(disassemble(0x4000, 0x9a02, 0), "ldr r2, [sp, #8]");
+        // The base register is in the list, so it isn't written back
+        assert_eq!(disassemble(0x4000, 0xc90e, 0), "ldm r1, {r1, r2, r3}");
+        assert_eq!(disassemble(0x4000, 0xb510, 0), "push {r4, lr}");
+        assert_eq!(disassemble(0x4000, 0xbd10, 0), "pop {r4, pc}");
+    }
+
+    #[test]
+    fn resolves_branch_targets() {
+        assert_eq!(disassemble(0x4004, 0xd0fc, 0), "beq 0x00004000");
+        assert_eq!(disassemble(0x4000, 0xe7fe, 0), "b 0x00004000");
+        assert_eq!(disassemble(0x4000, 0xf000, 0xf801), "bl 0x00004006");
+        assert_eq!(disassemble(0x4000, 0x4770, 0), "bx lr");
+        assert_eq!(disassemble(0x4000, 0x4788, 0), "blx r1");
+    }
+
+    #[test]
+    fn sizes_32_bit_instructions() {
+        assert_eq!(instruction_size(parse_instruction(0xf000, 0xf801)), 4);
+        assert_eq!(instruction_size(parse_instruction(0xf3bf, 0x8f5f)), 4);
+        assert_eq!(instruction_size(parse_instruction(0x2000, 0)), 2);
+        assert_eq!(disassemble(0x4000, 0xf3bf, 0x8f5f), "dmb sy");
+        assert_eq!(disassemble(0x4000, 0xffff, 0xffff), ".inst.n 0xffff");
+    }
+}
@@ -0,0 +1,870 @@
+//! ELF executables: loadable segments, the symbol table and DWARF line information.
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const ELFCLASS32: u8 = 1;
+const ELFDATA2LSB: u8 = 1;
+const EM_ARM: u16 = 40;
+const PT_LOAD: u32 = 1;
+const SHT_SYMTAB: u32 = 2;
+const STT_FUNC: u8 = 2;
+
+/// Bytes to place at `address`. Zero fill past the end of the file contents is included for
+/// segments loaded where they run.
+pub struct Segment {
+    pub address: u32,
+    pub data: Vec<u8>,
+}
+
+pub struct Symbol {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+#[derive(Clone, Copy)]
+struct LineRow {
+    address: u32,
+    file: usize,
+    line: u32,
+    end_sequence: bool,
+}
+
+/// Function names and source lines by address.
+#[derive(Default)]
+pub struct DebugInfo {
+    functions: Vec<Symbol>,
+    files: Vec<String>,
+    rows: Vec<LineRow>,
+}
+
+pub struct ElfImage {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub debug_info: DebugInfo,
+}
+
+impl DebugInfo {
+    /// The function containing `address`, and the offset into it.
+    pub fn function_at(&self, address: u32) -> Option<(&str, u32)> {
+        let index = self
+            .functions
+            .partition_point(|f| f.address <= address)
+            .checked_sub(1)?;
+        let function = &self.functions[index];
+        let offset = address - function.address;
+        // Symbols without a size, e.g. from assembly, extend to the next one
+        if function.size != 0 && offset >= function.size {
+            return None;
+        }
+        Some((&function.name, offset))
+    }
+
+    /// The source file and line that `address` was compiled from.
+    pub fn line_at(&self, address: u32) -> Option<(&str, u32)> {
+        let index = self
+            .rows
+            .partition_point(|row| row.address <= address)
+            .checked_sub(1)?;
+        let row = self.rows[index];
+        if row.end_sequence || row.line == 0 {
+            return None;
+        }
+        Some((&self.files[row.file], row.line))
+    }
+
+    pub fn functions(&self) -> &[Symbol] {
+        &self.functions
+    }
+
+    /// Every address that starts a line, with its file and line.
+    pub fn lines(&self) -> impl Iterator<Item = (u32, &str, u32)> {
+        self.rows
+            .iter()
+            .filter(|row| !row.end_sequence && row.line != 0)
+            .map(move |row| (row.address, self.files[row.file].as_str(), row.line))
+    }
+
+    pub fn has_lines(&self) -> bool {
+        !self.rows.is_empty()
+    }
+}
+
+#[cfg(test)]
+impl DebugInfo {
+    /// Functions as (name, address, size), and one sequence of (address, line) rows in `file`
+    /// ending at `end`.
+    pub(crate) fn for_test(
+        functions: &[(&str, u32, u32)],
+        file: &str,
+        lines: &[(u32, u32)],
+        end: u32,
+    ) -> DebugInfo {
+        let row = |address, line, end_sequence| LineRow {
+            address,
+            file: 0,
+            line,
+            end_sequence,
+        };
+        DebugInfo {
+            functions: functions
+                .iter()
+                .map(|&(name, address, size)| Symbol {
+                    name: name.to_string(),
+                    address,
+                    size,
+                })
+                .collect(),
+            files: vec![file.to_string()],
+            rows: lines
+                .iter()
+                .map(|&(address, line)| row(address, line, false))
+                .chain(std::iter::once(row(end, 0, true)))
+                .collect(),
+        }
+    }
+}
+
+struct Section<'a> {
+    name: &'a [u8],
+    kind: u32,
+    link: u32,
+    data: &'a [u8],
+}
+
+pub fn parse(bytes: &[u8]) -> Result<ElfImage, String> {
+    if bytes.len() < 52 || &bytes[..4] != ELF_MAGIC {
+        return Err("not an ELF file".to_string());
+    }
+    if bytes[4] != ELFCLASS32 || bytes[5] != ELFDATA2LSB || u16_at(bytes, 18)? != EM_ARM {
+        return Err("not a 32-bit little-endian ARM ELF file".to_string());
+    }
+    let entry = u32_at(bytes, 24)?;
+
+    let program_headers = u32_at(bytes, 28)? as usize;
+    let program_header_size = u16_at(bytes, 42)? as usize;
+    let program_header_count = u16_at(bytes, 44)? as usize;
+    let mut segments = Vec::new();
+    for i in 0..program_header_count {
+        let header = table_entry(bytes, program_headers, i, program_header_size, 32)?;
+        if u32_at(header, 0)? != PT_LOAD {
+            continue;
+        }
+        let offset = u32_at(header, 4)? as usize;
+        // Where the segment runs (p_vaddr) and where it is loaded (p_paddr). They differ for
+        // initialised data, which the startup code copies from flash to SRAM.
+        let run_address = u32_at(header, 8)?;
+        let address = u32_at(header, 12)?;
+        let file_size = u32_at(header, 16)? as usize;
+        let memory_size = u32_at(header, 20)? as usize;
+        let mut data = slice(bytes, offset, file_size)?.to_vec();
+        // Zero fill, e.g. .bss, only belongs where the segment runs; a copy of .data in flash
+        // is followed by other code or data
+        if address == run_address {
+            data.resize(memory_size.max(file_size), 0);
+        }
+        if !data.is_empty() {
+            segments.push(Segment { address, data });
+        }
+    }
+
+    let sections = parse_sections(bytes)?;
+    let section = |name: &[u8]| {
+        sections
+            .iter()
+            .find(|section| section.name == name)
+            .map(|section| section.data)
+    };
+
+    let mut debug_info = DebugInfo::default();
+    if let Some(symtab) = sections.iter().find(|s| s.kind == SHT_SYMTAB) {
+        let strtab = sections
+            .get(symtab.link as usize)
+            .map_or(&[][..], |s| s.data);
+        debug_info.functions = parse_functions(symtab.data, strtab)?;
+    }
+    if let Some(debug_line) = section(b".debug_line") {
+        let strings = DwarfStrings {
+            debug_str: section(b".debug_str").unwrap_or(&[]),
+            debug_line_str: section(b".debug_line_str").unwrap_or(&[]),
+        };
+        parse_debug_line(debug_line, &strings, &mut debug_info)?;
+    }
+
+    Ok(ElfImage {
+        entry,
+        segments,
+        debug_info,
+    })
+}
+
+fn parse_sections(bytes: &[u8]) -> Result<Vec<Section<'_>>, String> {
+    let section_headers = u32_at(bytes, 32)? as usize;
+    let section_header_size = u16_at(bytes, 46)? as usize;
+    let section_count = u16_at(bytes, 48)? as usize;
+    let names_index = u16_at(bytes, 50)? as usize;
+    if section_headers == 0 {
+        return Ok(Vec::new());
+    }
+
+    let mut headers = Vec::with_capacity(section_count);
+    for i in 0..section_count {
+        let header = table_entry(bytes, section_headers, i, section_header_size, 40)?;
+        let name = u32_at(header, 0)? as usize;
+        let kind = u32_at(header, 4)?;
+        let offset = u32_at(header, 16)? as usize;
+        let size = u32_at(header, 20)? as usize;
+        let link = u32_at(header, 24)?;
+        // SHT_NOBITS sections take no room in the file
+        let data = if kind == 8 {
+            &[][..]
+        } else {
+            slice(bytes, offset, size)?
+        };
+        headers.push((name, kind, link, data));
+    }
+    let names = headers.get(names_index).map_or(&[][..], |header| header.3);
+    Ok(headers
+        .into_iter()
+        .map(|(name, kind, link, data)| Section {
+            name: c_string(names, name),
+            kind,
+            link,
+            data,
+        })
+        .collect())
+}
+
+fn parse_functions(symtab: &[u8], strtab: &[u8]) -> Result<Vec<Symbol>, String> {
+    const SYMBOL_SIZE: usize = 16;
+    let mut functions = Vec::new();
+    for symbol in symtab.chunks_exact(SYMBOL_SIZE) {
+        if symbol[12] & 0xf != STT_FUNC {
+            continue;
+        }
+        let name = c_string(strtab, u32_at(symbol, 0)? as usize);
+        functions.push(Symbol {
+            name: String::from_utf8_lossy(name).into_owned(),
+            // The low bit marks Thumb code
+            address: u32_at(symbol, 4)? & !1,
+            size: u32_at(symbol, 8)?,
+        });
+    }
+    functions.sort_by_key(|f| f.address);
+    functions.dedup_by_key(|f| f.address);
+    Ok(functions)
+}
+
+struct DwarfStrings<'a> {
+    debug_str: &'a [u8],
+    debug_line_str: &'a [u8],
+}
+
+/// Little-endian reader over DWARF data.
+struct Reader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn u8(&mut self) -> Result<u8, String> {
+        let value = *self.data.get(self.position).ok_or_else(truncated)?;
+        self.position += 1;
+        Ok(value)
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let value = u16_at(self.data, self.position)?;
+        self.position += 2;
+        Ok(value)
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let value = u32_at(self.data, self.position)?;
+        self.position += 4;
+        Ok(value)
+    }
+
+    fn skip(&mut self, count: usize) -> Result<(), String> {
+        slice(self.data, self.position, count)?;
+        self.position += count;
+        Ok(())
+    }
+
+    /// The position `count` bytes on, which must be within the data.
+    fn advanced_by(&self, count: usize) -> Result<usize, String> {
+        self.position
+            .checked_add(count)
+            .filter(|&position| position <= self.data.len())
+            .ok_or_else(truncated)
+    }
+
+    fn uleb128(&mut self) -> Result<u64, String> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as u64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+    }
+
+    fn sleb128(&mut self) -> Result<i64, String> {
+        let mut value = 0i64;
+        let mut shift = 0;
+        loop {
+            let byte = self.u8()?;
+            if shift < 64 {
+                value |= ((byte & 0x7f) as i64) << shift;
+            }
+            shift += 7;
+            if byte & 0x80 == 0 {
+                if shift < 64 && byte & 0x40 != 0 {
+                    value |= -1 << shift;
+                }
+                return Ok(value);
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], String> {
+        let rest = self.data.get(self.position..).ok_or_else(truncated)?;
+        let length = rest.iter().position(|&b| b == 0).ok_or_else(truncated)?;
+        self.position += length + 1;
+        Ok(&rest[..length])
+    }
+}
+
+/// Reads the line number programs of every compilation unit into `debug_info`.
+fn parse_debug_line<'a>(
+    data: &'a [u8],
+    strings: &DwarfStrings<'a>,
+    debug_info: &mut DebugInfo,
+) -> Result<(), String> {
+    let mut offset = 0;
+    while offset + 4 <= data.len() {
+        let unit_length = u32_at(data, offset)? as usize;
+        if unit_length >= 0xfffffff0 {
+            return Err("64-bit DWARF isn't supported".to_string());
+        }
+        let end = offset.checked_add(4 + unit_length).ok_or_else(truncated)?;
+        let unit = slice(data, 0, end)?;
+        parse_line_unit(
+            Reader {
+                data: unit,
+                position: offset + 4,
+            },
+            strings,
+            debug_info,
+        )?;
+        offset = end;
+    }
+    debug_info.rows.sort_by_key(|row| row.address);
+    Ok(())
+}
+
+fn parse_line_unit<'a>(
+    mut reader: Reader<'a>,
+    strings: &DwarfStrings<'a>,
+    debug_info: &mut DebugInfo,
+) -> Result<(), String> {
+    let version = reader.u16()?;
+    if !(2..=5).contains(&version) {
+        return Err(format!("DWARF version {} isn't supported", version));
+    }
+    if version >= 5 {
+        reader.skip(2)?; // address and segment selector sizes
+    }
+    let header_length = reader.u32()? as usize;
+    let program_start = reader.advanced_by(header_length)?;
+    let minimum_instruction_length = reader.u8()? as u32;
+    if version >= 4 {
+        reader.u8()?; // maximum operations per instruction, 1 for non-VLIW
+    }
+    reader.u8()?; // default is_stmt
+    let line_base = reader.u8()? as i8 as i64;
+    let line_range = reader.u8()?;
+    let opcode_base = reader.u8()?;
+    if line_range == 0 || opcode_base == 0 {
+        return Err("invalid line number program header".to_string());
+    }
+    let mut standard_opcode_lengths = Vec::new();
+    for _ in 1..opcode_base {
+        standard_opcode_lengths.push(reader.u8()?);
+    }
+
+    // Indices into `debug_info.files` for this unit's file numbers
+    let files = if version >= 5 {
+        let directories = read_entries(&mut reader, strings)?
+            .into_iter()
+            .map(|(path, _)| path)
+            .collect::<Vec<_>>();
+        read_entries(&mut reader, strings)?
+            .into_iter()
+            .map(|(path, directory)| join_path(directories.get(directory), &path))
+            .collect::<Vec<_>>()
+    } else {
+        let mut directories = Vec::new();
+        loop {
+            let directory = reader.string()?;
+            if directory.is_empty() {
+                break;
+            }
+            directories.push(String::from_utf8_lossy(directory).into_owned());
+        }
+        // File numbers start at 1; the compilation directory is directory 0
+        let mut files = vec![String::new()];
+        loop {
+            let name = reader.string()?;
+            if name.is_empty() {
+                break;
+            }
+            let directory = reader.uleb128()? as usize;
+            reader.uleb128()?; // modification time
+            reader.uleb128()?; // length
+            let name = String::from_utf8_lossy(name);
+            files.push(join_path(
+                directory.checked_sub(1).and_then(|d| directories.get(d)),
+                &name,
+            ));
+        }
+        files
+    };
+    let first_file = debug_info.files.len();
+    debug_info.files.extend(files);
+    let file_count = debug_info.files.len() - first_file;
+
+    reader.position = program_start;
+    // The file register starts at 1 in every version, though DWARF 5 numbers files from 0
+    let default_file = 1;
+    let mut address = 0u32;
+    let mut file = default_file;
+    let mut line = 1i64;
+    let emit = |debug_info: &mut DebugInfo, address: u32, file: usize, line: i64, end: bool| {
+        if file < file_count {
+            debug_info.rows.push(LineRow {
+                address,
+                file: first_file + file,
+                line: line.max(0) as u32,
+                end_sequence: end,
+            });
+        }
+    };
+
+    while reader.position < reader.data.len() {
+        let opcode = reader.u8()?;
+        if opcode >= opcode_base {
+            let adjusted = opcode - opcode_base;
+            address =
+                address.wrapping_add((adjusted / line_range) as u32 * minimum_instruction_length);
+            line += line_base + (adjusted % line_range) as i64;
+            emit(debug_info, address, file, line, false);
+            continue;
+        }
+        match opcode {
+            0 => {
+                let length = reader.uleb128()? as usize;
+                let end = reader.advanced_by(length)?;
+                match reader.u8()? {
+                    // DW_LNE_end_sequence
+                    1 => {
+                        emit(debug_info, address, file, line, true);
+                        address = 0;
+                        file = default_file;
+                        line = 1;
+                    }
+                    // DW_LNE_set_address
+                    2 => address = reader.u32()?,
+                    _ => {}
+                }
+                reader.position = end;
+            }
+            // DW_LNS_copy
+            1 => emit(debug_info, address, file, line, false),
+            // DW_LNS_advance_pc
+            2 => {
+                let advance = reader.uleb128()? as u32;
+                address = address.wrapping_add(advance.wrapping_mul(minimum_instruction_length));
+            }
+            // DW_LNS_advance_line
+            3 => line += reader.sleb128()?,
+            // DW_LNS_set_file
+            4 => file = reader.uleb128()? as usize,
+            // DW_LNS_const_add_pc
+            8 => {
+                let adjusted = 255 - opcode_base;
+                address = address
+                    .wrapping_add((adjusted / line_range) as u32 * minimum_instruction_length);
+            }
+            // DW_LNS_fixed_advance_pc
+            9 => address = address.wrapping_add(reader.u16()? as u32),
+            // Column, statement and block markers, and anything newer
+            _ => {
+                for _ in 0..standard_opcode_lengths[opcode as usize - 1] {
+                    reader.uleb128()?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Reads a DWARF 5 directory or file name table. Returns each entry's path and directory
+/// index.
+fn read_entries<'a>(
+    reader: &mut Reader<'a>,
+    strings: &DwarfStrings<'a>,
+) -> Result<Vec<(String, usize)>, String> {
+    const DW_LNCT_PATH: u64 = 1;
+    const DW_LNCT_DIRECTORY_INDEX: u64 = 2;
+
+    let format_count = reader.u8()?;
+    let mut format = Vec::new();
+    for _ in 0..format_count {
+        format.push((reader.uleb128()?, reader.uleb128()?));
+    }
+    let count = reader.uleb128()?;
+    let mut entries = Vec::new();
+    for _ in 0..count {
+        let mut path = String::new();
+        let mut directory = 0;
+        for &(content, form) in &format {
+            let value = read_form(reader, form, strings)?;
+            match (content, value) {
+                (DW_LNCT_PATH, FormValue::String(string)) => {
+                    path = String::from_utf8_lossy(string).into_owned()
+                }
+                (DW_LNCT_DIRECTORY_INDEX, FormValue::Number(index)) => directory = index as usize,
+                _ => {}
+            }
+        }
+        entries.push((path, directory));
+    }
+    Ok(entries)
+}
+
+enum FormValue<'a> {
+    String(&'a [u8]),
+    Number(u64),
+    Other,
+}
+
+fn read_form<'a>(
+    reader: &mut Reader<'a>,
+    form: u64,
+    strings: &DwarfStrings<'a>,
+) -> Result<FormValue<'a>, String> {
+    Ok(match form {
+        // DW_FORM_string
+        0x08 => FormValue::String(reader.string()?),
+        // DW_FORM_strp
+        0x0e => FormValue::String(c_string(strings.debug_str, reader.u32()? as usize)),
+        // DW_FORM_line_strp
+        0x1f => FormValue::String(c_string(strings.debug_line_str, reader.u32()? as usize)),
+        // DW_FORM_data1, data2, data4, data8 and udata
+        0x0b => FormValue::Number(reader.u8()? as u64),
+        0x05 => FormValue::Number(reader.u16()? as u64),
+        0x06 => FormValue::Number(reader.u32()? as u64),
+        0x07 => {
+            let low = reader.u32()? as u64;
+            FormValue::Number(low | (reader.u32()? as u64) << 32)
+        }
+        0x0f => FormValue::Number(reader.uleb128()?),
+        // DW_FORM_data16, e.g. MD5 checksums
+        0x1e => {
+            reader.skip(16)?;
+            FormValue::Other
+        }
+        // DW_FORM_block
+        0x09 => {
+            let length = reader.uleb128()? as usize;
+            reader.skip(length)?;
+            FormValue::Other
+        }
+        _ => return Err(format!("unsupported DWARF form 0x{:x}", form)),
+    })
+}
+
+fn join_path(directory: Option<&String>, name: &str) -> String {
+    match directory {
+        Some(directory) if !name.starts_with('/') && !directory.is_empty() => {
+            format!("{}/{}", directory, name)
+        }
+        _ => name.to_string(),
+    }
+}
+
+fn c_string(table: &[u8], offset: usize) -> &[u8] {
+    let rest = table.get(offset..).unwrap_or(&[]);
+    let length = rest.iter().position(|&b| b == 0).unwrap_or(rest.len());
+    &rest[..length]
+}
+
+fn truncated() -> String {
+    "truncated ELF file".to_string()
+}
+
+fn slice(bytes: &[u8], offset: usize, length: usize) -> Result<&[u8], String> {
+    offset
+        .checked_add(length)
+        .and_then(|end| bytes.get(offset..end))
+        .ok_or_else(truncated)
+}
+
+/// Entry `index` of a table of `entry_size` byte entries at `table`, of which the first
+/// `min_size` bytes are read.
+fn table_entry(
+    bytes: &[u8],
+    table: usize,
+    index: usize,
+    entry_size: usize,
+    min_size: usize,
+) -> Result<&[u8], String> {
+    let offset = index
+        .checked_mul(entry_size)
+        .and_then(|offset| offset.checked_add(table))
+        .ok_or_else(truncated)?;
+    slice(bytes, offset, entry_size.max(min_size))
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> Result<u16, String> {
+    let bytes = slice(bytes, offset, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> Result<u32, String> {
+    let bytes = slice(bytes, offset, 4)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT_ADDRESS: u32 = 0x4000;
+
+    /// A line number program for main.c: line 10 at 0x4000, line 11 at 0x4004, ending at
+    /// 0x4008.
+    fn debug_line() -> Vec<u8> {
+        let mut header = vec![2, 1, (-5i8) as u8, 14, 13];
+        header.extend_from_slice(&[0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1]);
+        header.extend_from_slice(b"src\0\0");
+        header.extend_from_slice(b"main.c\0\x01\0\0\0");
+
+        let mut program = vec![0, 5, 2];
+        program.extend_from_slice(&TEXT_ADDRESS.to_le_bytes());
+        program.extend_from_slice(&[3, 9, 1]); // advance_line 9, copy
+        program.extend_from_slice(&[2, 2, 19]); // advance_pc 2, special: line + 1
+        program.extend_from_slice(&[2, 2, 0, 1, 1]); // advance_pc 2, end_sequence
+
+        let mut unit = 3u16.to_le_bytes().to_vec();
+        unit.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        unit.extend(header);
+        unit.extend(program);
+        let mut data = (unit.len() as u32).to_le_bytes().to_vec();
+        data.extend(unit);
+        data
+    }
+
+    fn symbol(name: u32, value: u32, size: u32, info: u8) -> Vec<u8> {
+        let mut symbol = Vec::new();
+        for field in [name, value, size] {
+            symbol.extend_from_slice(&field.to_le_bytes());
+        }
+        symbol.extend_from_slice(&[info, 0, 1, 0]);
+        symbol
+    }
+
+    /// An executable with code in flash, .data loaded after it and run from SRAM, symbols and
+    /// line information.
+    fn fixture() -> Vec<u8> {
+        let text: Vec<u8> = (1..=12).collect();
+        let data = vec![0xd1, 0xd2, 0xd3, 0xd4];
+        let strtab = b"\0main\0helper\0table\0".to_vec();
+        let symtab = [
+            symbol(0, 0, 0, 0),
+            symbol(1, TEXT_ADDRESS | 1, 8, 0x12),
+            symbol(6, TEXT_ADDRESS + 8 + 1, 0, 0x12),
+            symbol(13, 0x20000000, 4, 0x11), // an object
+        ]
+        .concat();
+        let shstrtab = b"\0.shstrtab\0.symtab\0.strtab\0.debug_line\0.bss\0".to_vec();
+        let debug_line = debug_line();
+
+        let mut elf = vec![0; 52 + 2 * 32];
+        let place = |elf: &mut Vec<u8>, contents: &[u8]| {
+            let offset = elf.len() as u32;
+            elf.extend_from_slice(contents);
+            offset
+        };
+        let text_offset = place(&mut elf, &text);
+        let data_offset = place(&mut elf, &data);
+        // (name, type, link, offset, size)
+        let sections = [
+            (0, 0, 0, 0, 0),
+            (1, 3, 0, place(&mut elf, &shstrtab), shstrtab.len()),
+            (11, 2, 3, place(&mut elf, &symtab), symtab.len()),
+            (19, 3, 0, place(&mut elf, &strtab), strtab.len()),
+            (27, 1, 0, place(&mut elf, &debug_line), debug_line.len()),
+            // NOBITS, with an offset and size past the end of the file
+            (39, 8, 0, 0xffff_0000, 0x100),
+        ];
+        let section_headers = elf.len() as u32;
+        for (name, kind, link, offset, size) in sections {
+            for field in [name, kind, 0, 0, offset, size as u32, link, 0, 4, 0] {
+                elf.extend_from_slice(&field.to_le_bytes());
+            }
+        }
+
+        elf[..16].copy_from_slice(b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0");
+        let mut header = Vec::new();
+        header.extend_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+        header.extend_from_slice(&EM_ARM.to_le_bytes());
+        header.extend_from_slice(&1u32.to_le_bytes());
+        header.extend_from_slice(&(TEXT_ADDRESS | 1).to_le_bytes());
+        header.extend_from_slice(&52u32.to_le_bytes());
+        header.extend_from_slice(&section_headers.to_le_bytes());
+        header.extend_from_slice(&0x05000200u32.to_le_bytes());
+        for field in [52u16, 32, 2, 40, sections.len() as u16, 1] {
+            header.extend_from_slice(&field.to_le_bytes());
+        }
+        elf[16..52].copy_from_slice(&header);
+
+        // (offset, run address, load address, file size, memory size)
+        let segments = [
+            (text_offset, TEXT_ADDRESS, TEXT_ADDRESS, 12, 16),
+            (data_offset, 0x20000000, TEXT_ADDRESS + 16, 4, 12),
+        ];
+        for (i, &(offset, run_address, address, file_size, memory_size)) in
+            segments.iter().enumerate()
+        {
+            let fields = [
+                PT_LOAD,
+                offset,
+                run_address,
+                address,
+                file_size,
+                memory_size,
+                5,
+                4,
+            ];
+            for (j, field) in fields.iter().enumerate() {
+                let at = 52 + i * 32 + j * 4;
+                elf[at..at + 4].copy_from_slice(&field.to_le_bytes());
+            }
+        }
+        elf
+    }
+
+    #[test]
+    fn parses_segments() {
+        let image = parse(&fixture()).unwrap();
+        assert_eq!(image.entry, TEXT_ADDRESS | 1);
+        assert_eq!(image.segments.len(), 2);
+
+        let text = &image.segments[0];
+        assert_eq!(text.address, TEXT_ADDRESS);
+        let mut expected: Vec<u8> = (1..=12).collect();
+        expected.resize(16, 0);
+        assert_eq!(text.data, expected);
+
+        // .data is copied to SRAM by the startup code, so its .bss isn't placed in flash
+        let data = &image.segments[1];
+        assert_eq!(data.address, TEXT_ADDRESS + 16);
+        assert_eq!(data.data, [0xd1, 0xd2, 0xd3, 0xd4]);
+    }
+
+    #[test]
+    fn parses_functions() {
+        let debug_info = parse(&fixture()).unwrap().debug_info;
+        let names: Vec<&str> = debug_info
+            .functions()
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(names, ["main", "helper"]);
+        assert_eq!(debug_info.function_at(TEXT_ADDRESS - 2), None);
+        assert_eq!(debug_info.function_at(TEXT_ADDRESS + 6), Some(("main", 6)));
+        // Without a size, helper extends onwards
+        assert_eq!(
+            debug_info.function_at(TEXT_ADDRESS + 0x20),
+            Some(("helper", 0x18))
+        );
+    }
+
+    #[test]
+    fn parses_lines() {
+        let debug_info = parse(&fixture()).unwrap().debug_info;
+        assert!(debug_info.has_lines());
+        assert_eq!(debug_info.line_at(TEXT_ADDRESS), Some(("src/main.c", 10)));
+        assert_eq!(
+            debug_info.line_at(TEXT_ADDRESS + 2),
+            Some(("src/main.c", 10))
+        );
+        assert_eq!(
+            debug_info.line_at(TEXT_ADDRESS + 4),
+            Some(("src/main.c", 11))
+        );
+        assert_eq!(debug_info.line_at(TEXT_ADDRESS + 8), None);
+        let lines: Vec<(u32, &str, u32)> = debug_info.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                (TEXT_ADDRESS, "src/main.c", 10),
+                (TEXT_ADDRESS + 4, "src/main.c", 11)
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(parse(b"\x7fELF").is_err());
+        assert!(parse(&[0; 64]).is_err());
+        let mut elf = fixture();
+        elf[18] = 3; // EM_386
+        assert!(parse(&elf).is_err());
+    }
+
+    #[test]
+    fn rejects_offsets_past_the_end() {
+        let mut elf = fixture();
+        elf[28..32].copy_from_slice(&u32::MAX.to_le_bytes()); // program headers
+        assert!(parse(&elf).is_err());
+
+        let mut elf = fixture();
+        elf[32..36].copy_from_slice(&u32::MAX.to_le_bytes()); // section headers
+        assert!(parse(&elf).is_err());
+
+        let mut elf = fixture();
+        elf[42..46].copy_from_slice(&[0xff, 0xff, 0xff, 0xff]); // program header size, count
+        assert!(parse(&elf).is_err());
+
+        let mut elf = fixture();
+        let segment_size = 52 + 16;
+        elf[segment_size..segment_size + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&elf).is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_line_units() {
+        let image = fixture();
+        let debug_line = debug_line();
+        let start = image
+            .windows(debug_line.len())
+            .position(|window| window == debug_line.as_slice())
+            .unwrap();
+
+        let mut elf = image.clone();
+        elf[start..start + 4].copy_from_slice(&0xffff_ffefu32.to_le_bytes());
+        assert!(parse(&elf).is_err());
+
+        // header_length
+        let mut elf = image;
+        elf[start + 6..start + 10].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(parse(&elf).is_err());
+    }
+}
@@ -0,0 +1,605 @@
+//! GDB remote serial protocol stub, so that `arm-none-eabi-gdb` can attach to a running game.
+//!
+//! `GdbStub` only turns the bytes GDB sends into the bytes to send back, so any transport can
+//! carry them: `serve` listens on TCP in native builds, and in the browser a WebSocket bridge
+//! passes messages to `receive` and calls `run` while GDB has the game running.
+
+use crate::debug::{StopKind, StopReason, WatchHit, WatchKind};
+use crate::instruction::Instruction;
+use crate::{Gamebuino, PC_INDEX};
+use wasm_bindgen::prelude::*;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const PACKET_SIZE: usize = 0x1000;
+const REGISTER_COUNT: usize = 17;
+const XPSR_INDEX: usize = 16;
+
+// Without regnum attributes GDB numbers the registers in order, so xpsr is 16
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target version="1.0"><architecture>arm</architecture>"#,
+    r#"<feature name="org.gnu.gdb.arm.m-profile">"#,
+    r#"<reg name="r0" bitsize="32"/><reg name="r1" bitsize="32"/>"#,
+    r#"<reg name="r2" bitsize="32"/><reg name="r3" bitsize="32"/>"#,
+    r#"<reg name="r4" bitsize="32"/><reg name="r5" bitsize="32"/>"#,
+    r#"<reg name="r6" bitsize="32"/><reg name="r7" bitsize="32"/>"#,
+    r#"<reg name="r8" bitsize="32"/><reg name="r9" bitsize="32"/>"#,
+    r#"<reg name="r10" bitsize="32"/><reg name="r11" bitsize="32"/>"#,
+    r#"<reg name="r12" bitsize="32"/>"#,
+    r#"<reg name="sp" bitsize="32" type="data_ptr"/>"#,
+    r#"<reg name="lr" bitsize="32"/>"#,
+    r#"<reg name="pc" bitsize="32" type="code_ptr"/>"#,
+    r#"<reg name="xpsr" bitsize="32"/>"#,
+    r#"</feature></target>"#
+);
+
+enum Stop {
+    Signal(u8),
+    Breakpoint { hardware: bool },
+    Watchpoint(WatchHit),
+}
+
+#[wasm_bindgen]
+pub struct GdbStub {
+    input: Vec<u8>,
+    no_ack: bool,
+    running: bool,
+    attached: bool,
+    // Address, and whether GDB asked for a hardware breakpoint. Both kinds are set on the
+    // Gamebuino rather than patched into flash.
+    breakpoints: Vec<(u32, bool)>,
+    watchpoints: Vec<(u32, u32, WatchKind)>,
+}
+
+impl Default for GdbStub {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub {
+            input: Vec::new(),
+            no_ack: false,
+            running: false,
+            attached: true,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+        }
+    }
+
+    /// Whether GDB has resumed the game, in which case `run` should be called instead of
+    /// `Gamebuino::run`.
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// False once GDB has detached or killed the session.
+    pub fn is_attached(&self) -> bool {
+        self.attached
+    }
+
+    /// Handles bytes received from GDB. Returns the bytes to send back.
+    pub fn receive(&mut self, gamebuino: &mut Gamebuino, data: &[u8]) -> Vec<u8> {
+        self.input.extend_from_slice(data);
+        let mut output = Vec::new();
+        while let Some(&first) = self.input.first() {
+            match first {
+                b'$' => {
+                    let end = match self.input.iter().position(|&b| b == b'#') {
+                        Some(end) if self.input.len() >= end + 3 => end,
+                        _ => break,
+                    };
+                    let packet: Vec<u8> = self.input.drain(..end + 3).collect();
+                    let payload = &packet[1..end];
+                    let checksum = std::str::from_utf8(&packet[end + 1..])
+                        .ok()
+                        .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+                    if checksum != Some(checksum_of(payload)) {
+                        if !self.no_ack {
+                            output.push(b'-');
+                        }
+                        continue;
+                    }
+                    if !self.no_ack {
+                        output.push(b'+');
+                    }
+                    if let Some(reply) = self.handle_packet(gamebuino, &unescape(payload)) {
+                        output.extend(frame(&reply));
+                    }
+                }
+                0x03 => {
+                    self.input.remove(0);
+                    if self.running {
+                        self.running = false;
+                        output.extend(frame(&stop_reply(Stop::Signal(SIGINT))));
+                    }
+                }
+                // Acknowledgements, and anything outside a packet. Nothing is ever resent.
+                _ => {
+                    self.input.remove(0);
+                }
+            }
+        }
+        output
+    }
+
+    /// While the game is running under GDB, runs it for up to `max_ticks`. Returns the stop
+    /// reply to send to GDB if it hit a breakpoint or watchpoint.
+    pub fn run(&mut self, gamebuino: &mut Gamebuino, max_ticks: u32, button_data: u8) -> Vec<u8> {
+        if !self.running {
+            return Vec::new();
+        }
+        let reason = gamebuino.run(max_ticks as usize, button_data);
+        match self.stop_for(reason) {
+            Some(stop) => {
+                self.running = false;
+                frame(&stop_reply(stop))
+            }
+            None => Vec::new(),
+        }
+    }
+}
+
+impl GdbStub {
+    fn handle_packet(&mut self, gamebuino: &mut Gamebuino, packet: &[u8]) -> Option<Vec<u8>> {
+        let (&command, arguments) = match packet.split_first() {
+            Some(split) => split,
+            None => return Some(Vec::new()),
+        };
+        let text = String::from_utf8_lossy(arguments);
+        let reply = match command {
+            b'?' => stop_reply(Stop::Signal(SIGTRAP)),
+            b'g' => {
+                let mut reply = String::new();
+                for index in 0..REGISTER_COUNT {
+                    reply.push_str(&hex_word(read_register(gamebuino, index)));
+                }
+                reply.into_bytes()
+            }
+            b'G' => {
+                for (index, chunk) in arguments.chunks(8).take(REGISTER_COUNT).enumerate() {
+                    match parse_hex_word(chunk) {
+                        Some(value) => write_register(gamebuino, index, value),
+                        None => return Some(b"E01".to_vec()),
+                    }
+                }
+                b"OK".to_vec()
+            }
+            b'p' => match usize::from_str_radix(&text, 16) {
+                Ok(index) if index < REGISTER_COUNT => {
+                    hex_word(read_register(gamebuino, index)).into_bytes()
+                }
+                _ => b"E01".to_vec(),
+            },
+            b'P' => {
+                let parsed = text.split_once('=').and_then(|(index, value)| {
+                    let index = usize::from_str_radix(index, 16).ok()?;
+                    Some((index, parse_hex_word(value.as_bytes())?))
+                });
+                match parsed {
+                    Some((index, value)) if index < REGISTER_COUNT => {
+                        write_register(gamebuino, index, value);
+                        b"OK".to_vec()
+                    }
+                    _ => b"E01".to_vec(),
+                }
+            }
+            b'm' => match parse_address_length(&text) {
+                Some((address, length)) => {
+                    let length = length.min(PACKET_SIZE as u32 / 2);
+                    (0..length)
+                        .map(|i| {
+                            format!(
+                                "{:02x}",
+                                gamebuino.debugger_read(address.wrapping_add(i), 1)
+                            )
+                        })
+                        .collect::<String>()
+                        .into_bytes()
+                }
+                None => b"E01".to_vec(),
+            },
+            b'M' | b'X' => self.write_memory(gamebuino, command, arguments),
+            b's' | b'S' => {
+                self.resume_at(gamebuino, &text, command == b'S');
+                let stop = self.single_step(gamebuino);
+                stop_reply(stop)
+            }
+            b'c' | b'C' => {
+                self.resume_at(gamebuino, &text, command == b'C');
+                self.running = true;
+                return None;
+            }
+            b'v' => return self.handle_v_packet(gamebuino, &text),
+            b'Z' | b'z' => self.handle_point(gamebuino, command == b'Z', &text),
+            b'q' => self.handle_query(&text),
+            b'Q' if text == "StartNoAckMode" => {
+                self.no_ack = true;
+                b"OK".to_vec()
+            }
+            b'H' | b'T' => b"OK".to_vec(),
+            b'D' => {
+                self.attached = false;
+                for (address, _) in self.breakpoints.drain(..) {
+                    gamebuino.remove_breakpoint(address);
+                }
+                for (address, length, kind) in self.watchpoints.drain(..) {
+                    gamebuino.remove_watchpoint(address, length, kind);
+                }
+                b"OK".to_vec()
+            }
+            b'k' => {
+                self.attached = false;
+                self.running = false;
+                return None;
+            }
+            _ => Vec::new(),
+        };
+        Some(reply)
+    }
+
+    fn handle_query(&self, query: &str) -> Vec<u8> {
+        if query.starts_with("Supported") {
+            format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+;swbreak+;hwbreak+",
+                PACKET_SIZE
+            )
+            .into_bytes()
+        } else if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+            match parse_address_length(range) {
+                Some((offset, length)) => {
+                    let xml = TARGET_XML.as_bytes();
+                    let start = (offset as usize).min(xml.len());
+                    let end = (start + length as usize).min(xml.len());
+                    let mut reply = vec![if end == xml.len() { b'l' } else { b'm' }];
+                    reply.extend_from_slice(&xml[start..end]);
+                    reply
+                }
+                None => b"E01".to_vec(),
+            }
+        } else if query == "Attached" {
+            b"1".to_vec()
+        } else if query == "C" {
+            b"QC1".to_vec()
+        } else if query == "fThreadInfo" {
+            b"m1".to_vec()
+        } else if query == "sThreadInfo" {
+            b"l".to_vec()
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn handle_v_packet(&mut self, gamebuino: &mut Gamebuino, packet: &str) -> Option<Vec<u8>> {
+        if packet == "Cont?" {
+            return Some(b"vCont;c;C;s;S".to_vec());
+        }
+        let action = match packet.strip_prefix("Cont;") {
+            Some(actions) => actions.split(';').next().unwrap_or(""),
+            None => return Some(Vec::new()),
+        };
+        // There is a single thread, so the first action applies to it
+        match action.as_bytes().first() {
+            Some(b's') | Some(b'S') => Some(stop_reply(self.single_step(gamebuino))),
+            Some(b'c') | Some(b'C') => {
+                gamebuino.clear_watch_hit();
+                self.running = true;
+                None
+            }
+            _ => Some(b"E01".to_vec()),
+        }
+    }
+
+    fn handle_point(&mut self, gamebuino: &mut Gamebuino, insert: bool, packet: &str) -> Vec<u8> {
+        let mut fields = packet.split(',');
+        let kind = fields.next();
+        let address = fields.next().and_then(|a| u32::from_str_radix(a, 16).ok());
+        let length = fields
+            .next()
+            .and_then(|l| u32::from_str_radix(l.split(';').next().unwrap_or(l), 16).ok());
+        let (address, length) = match (address, length) {
+            (Some(address), Some(length)) => (address, length),
+            _ => return b"E01".to_vec(),
+        };
+
+        let watch_kind = match kind {
+            Some("0") | Some("1") => {
+                let breakpoint = (address, kind == Some("1"));
+                self.breakpoints.retain(|&b| b != breakpoint);
+                if insert {
+                    self.breakpoints.push(breakpoint);
+                    gamebuino.add_breakpoint(address);
+                } else if self.breakpoints.iter().all(|&(a, _)| a != address) {
+                    gamebuino.remove_breakpoint(address);
+                }
+                return b"OK".to_vec();
+            }
+            Some("2") => WatchKind::Write,
+            Some("3") => WatchKind::Read,
+            Some("4") => WatchKind::Access,
+            _ => return Vec::new(),
+        };
+        let watchpoint = (address, length, watch_kind);
+        self.watchpoints.retain(|&w| w != watchpoint);
+        if insert {
+            self.watchpoints.push(watchpoint);
+            gamebuino.add_watchpoint(address, length, watch_kind);
+        } else {
+            gamebuino.remove_watchpoint(address, length, watch_kind);
+        }
+        b"OK".to_vec()
+    }
+
+    fn write_memory(&self, gamebuino: &mut Gamebuino, command: u8, arguments: &[u8]) -> Vec<u8> {
+        let colon = match arguments.iter().position(|&b| b == b':') {
+            Some(colon) => colon,
+            None => return b"E01".to_vec(),
+        };
+        let (address, length) =
+            match parse_address_length(&String::from_utf8_lossy(&arguments[..colon])) {
+                Some(parsed) => parsed,
+                None => return b"E01".to_vec(),
+            };
+        let data = &arguments[colon + 1..];
+        let bytes: Vec<u8> = if command == b'X' {
+            data.to_vec()
+        } else {
+            match data
+                .chunks(2)
+                .map(parse_hex_byte)
+                .collect::<Option<Vec<u8>>>()
+            {
+                Some(bytes) => bytes,
+                None => return b"E01".to_vec(),
+            }
+        };
+        if bytes.len() != length as usize {
+            return b"E01".to_vec();
+        }
+        // Flash isn't writable by the program, so `load` and patching code go through
+        // `patch_flash`, which also decodes the new instructions
+        if address < 0x20000000 {
+            return match gamebuino.patch_flash(address, &bytes) {
+                Ok(()) => b"OK".to_vec(),
+                Err(_) => b"E01".to_vec(),
+            };
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            gamebuino.debugger_write(address.wrapping_add(i as u32), 1, byte as u32);
+        }
+        b"OK".to_vec()
+    }
+
+    /// Applies the optional resume address of `s`, `c`, `S` and `C`. The signal of `S` and `C`
+    /// is dropped, as there is nothing to deliver it to.
+    fn resume_at(&self, gamebuino: &mut Gamebuino, arguments: &str, with_signal: bool) {
+        let address = if with_signal {
+            arguments.split_once(';').map(|(_, address)| address)
+        } else {
+            Some(arguments).filter(|a| !a.is_empty())
+        };
+        if let Some(address) = address.and_then(|a| u32::from_str_radix(a, 16).ok()) {
+            write_register(gamebuino, PC_INDEX as usize, address);
+        }
+        gamebuino.clear_watch_hit();
+    }
+
+    /// Runs one instruction. The two halves of a BL are decoded as separate instructions, but
+    /// run together here so that GDB never sees the PC between them.
+    fn single_step(&mut self, gamebuino: &mut Gamebuino) -> Stop {
+        gamebuino.clear_watch_hit();
+        gamebuino.step();
+        if let Instruction::Bl { first: false, .. } = gamebuino.instruction_at(gamebuino.pc()) {
+            gamebuino.step();
+        }
+        gamebuino
+            .check_stop()
+            .and_then(|reason| self.stop_for(reason))
+            .unwrap_or(Stop::Signal(SIGTRAP))
+    }
+
+    fn stop_for(&self, reason: StopReason) -> Option<Stop> {
+        match reason.kind() {
+            StopKind::TicksExhausted | StopKind::FrameCompleted => None,
+            StopKind::Breakpoint => Some(Stop::Breakpoint {
+                hardware: self.breakpoints.contains(&(reason.pc(), true)),
+            }),
+            StopKind::Watchpoint => reason.watch_hit().map(Stop::Watchpoint),
+        }
+    }
+}
+
+/// Listens on `address` for one GDB connection and serves it until GDB detaches or
+/// disconnects.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn serve(gamebuino: &mut Gamebuino, address: &str) -> std::io::Result<()> {
+    use std::io::{ErrorKind, Read, Write};
+
+    // How long the game runs between checks for an interrupt from GDB
+    const RUN_SLICE_TICKS: u32 = crate::GOAL_TICKS_PER_SECOND as u32 / 100;
+
+    let listener = std::net::TcpListener::bind(address)?;
+    let (mut stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+
+    let mut stub = GdbStub::new();
+    let button_data = gamebuino.buttons.button_data;
+    let mut buffer = [0; PACKET_SIZE];
+    while stub.is_attached() {
+        stream.set_nonblocking(stub.is_running())?;
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(count) => {
+                let reply = stub.receive(gamebuino, &buffer[..count]);
+                stream.write_all(&reply)?;
+            }
+            Err(error) if error.kind() == ErrorKind::WouldBlock => {}
+            Err(error) => return Err(error),
+        }
+        let reply = stub.run(gamebuino, RUN_SLICE_TICKS, button_data);
+        stream.write_all(&reply)?;
+    }
+    Ok(())
+}
+
+/// Registers as GDB sees them. The emulator keeps the PC one instruction ahead.
+fn read_register(gamebuino: &Gamebuino, index: usize) -> u32 {
+    match index {
+        XPSR_INDEX => gamebuino.xpsr(),
+        i if i == PC_INDEX as usize => gamebuino.registers[i].wrapping_sub(2),
+        i => gamebuino.registers[i],
+    }
+}
+
+fn write_register(gamebuino: &mut Gamebuino, index: usize, value: u32) {
+    match index {
+        XPSR_INDEX => {
+            let flags = &mut gamebuino.cond_reg;
+            flags.n = value & (1 << 31) != 0;
+            flags.z = value & (1 << 30) != 0;
+            flags.c = value & (1 << 29) != 0;
+            flags.v = value & (1 << 28) != 0;
+        }
+        i if i == PC_INDEX as usize => gamebuino.registers[i] = (value & !1).wrapping_add(2),
+        i => gamebuino.registers[i] = value,
+    }
+}
+
+fn stop_reply(stop: Stop) -> Vec<u8> {
+    match stop {
+        Stop::Signal(signal) => format!("S{:02x}", signal),
+        Stop::Breakpoint { hardware: false } => format!("T{:02x}swbreak:;", SIGTRAP),
+        Stop::Breakpoint { hardware: true } => format!("T{:02x}hwbreak:;", SIGTRAP),
+        Stop::Watchpoint(hit) => {
+            let name = match hit.watchpoint.kind {
+                WatchKind::Write => "watch",
+                WatchKind::Read => "rwatch",
+                WatchKind::Access => "awatch",
+            };
+            // GDB looks the watchpoint up by the address it set
+            format!("T{:02x}{}:{:x};", SIGTRAP, name, hit.watchpoint.address)
+        }
+    }
+    .into_bytes()
+}
+
+fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(payload.len() + 4);
+    escaped.push(b'$');
+    for &byte in payload {
+        if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+            escaped.push(b'}');
+            escaped.push(byte ^ 0x20);
+        } else {
+            escaped.push(byte);
+        }
+    }
+    let checksum = checksum_of(&escaped[1..]);
+    escaped.extend(format!("#{:02x}", checksum).into_bytes());
+    escaped
+}
+
+fn unescape(payload: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(payload.len());
+    let mut bytes = payload.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'}' {
+            if let Some(&next) = bytes.next() {
+                output.push(next ^ 0x20);
+            }
+        } else {
+            output.push(byte);
+        }
+    }
+    output
+}
+
+fn checksum_of(payload: &[u8]) -> u8 {
+    payload
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// Registers are sent as little-endian bytes.
+fn hex_word(value: u32) -> String {
+    value
+        .to_le_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn parse_hex_word(hex: &[u8]) -> Option<u32> {
+    if hex.len() != 8 {
+        return None;
+    }
+    let mut bytes = [0; 4];
+    for (byte, digits) in bytes.iter_mut().zip(hex.chunks(2)) {
+        *byte = parse_hex_byte(digits)?;
+    }
+    Some(u32::from_le_bytes(bytes))
+}
+
+fn parse_hex_byte(digits: &[u8]) -> Option<u8> {
+    u8::from_str_radix(std::str::from_utf8(digits).ok()?, 16).ok()
+}
+
+fn parse_address_length(text: &str) -> Option<(u32, u32)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        u32::from_str_radix(address, 16).ok()?,
+        u32::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_wraps_at_256() {
+        assert_eq!(checksum_of(b""), 0);
+        assert_eq!(checksum_of(b"OK"), 0x9a);
+        assert_eq!(checksum_of(&[0xff, 0x02]), 0x01);
+    }
+
+    #[test]
+    fn frame_escapes_special_characters() {
+        assert_eq!(frame(b"OK"), b"$OK#9a");
+        // The checksum is of the escaped payload
+        assert_eq!(frame(b"a$b#c}d*"), b"$a}\x04b}\x03c}]d}\x0a#ec");
+        assert_eq!(checksum_of(b"a}\x04b}\x03c}]d}\x0a"), 0xec);
+    }
+
+    #[test]
+    fn unescape_undoes_frame() {
+        let payload: Vec<u8> = (0..=255).collect();
+        let framed = frame(&payload);
+        let escaped = &framed[1..framed.len() - 3];
+        assert_eq!(unescape(escaped), payload);
+    }
+
+    #[test]
+    fn hex_words_are_little_endian() {
+        assert_eq!(hex_word(0x12345678), "78563412");
+        assert_eq!(parse_hex_word(b"78563412"), Some(0x12345678));
+        assert_eq!(parse_hex_word(b"785634"), None);
+        assert_eq!(parse_hex_word(b"7856341g"), None);
+        assert_eq!(parse_address_length("20000000,4"), Some((0x20000000, 4)));
+    }
+
+    #[test]
+    fn receive_acknowledges_by_checksum() {
+        let mut gamebuino = Gamebuino::new();
+        let mut stub = GdbStub::new();
+        assert_eq!(stub.receive(&mut gamebuino, b"$?#00"), b"-");
+        assert_eq!(stub.receive(&mut gamebuino, b"$?#3f"), b"+$S05#b8");
+        // Packets can arrive split across messages
+        assert_eq!(stub.receive(&mut gamebuino, b"+$?#"), b"");
+        assert_eq!(stub.receive(&mut gamebuino, b"3f"), b"+$S05#b8");
+    }
+}
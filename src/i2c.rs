@@ -0,0 +1,589 @@
+use crate::register::SercomRegisters;
+use crate::GOAL_TICKS_PER_SECOND;
+
+/// A virtual device attached to the I2C expansion bus.
+pub trait I2cDevice {
+    /// 7-bit address the device answers to.
+    fn address(&self) -> u8;
+
+    /// Called on a START or repeated START addressed to this device.
+    fn start(&mut self, _read: bool) {}
+
+    /// Handles a byte sent by the master. Returns `false` to NACK it.
+    fn write(&mut self, value: u8) -> bool;
+
+    /// Supplies the next byte requested by the master.
+    fn read(&mut self) -> u8;
+
+    /// Called on a STOP condition.
+    fn stop(&mut self) {}
+
+    /// Called with the number of ticks elapsed since the previous call, before each address,
+    /// byte or command on the bus.
+    fn advance(&mut self, _ticks: u64) {}
+}
+
+/// I2C master model driven by a SERCOM configured in I2C master mode.
+pub(crate) struct I2cBus {
+    devices: Vec<Box<dyn I2cDevice>>,
+    selected: Option<usize>,
+    reading: bool,
+    last_tick: u64,
+}
+
+impl I2cBus {
+    const CMD_REPEATED_START: u8 = 0x1;
+    const CMD_READ: u8 = 0x2;
+    const CMD_STOP: u8 = 0x3;
+
+    pub fn new() -> I2cBus {
+        I2cBus {
+            devices: Vec::new(),
+            selected: None,
+            reading: false,
+            last_tick: 0,
+        }
+    }
+
+    pub fn attach(&mut self, device: Box<dyn I2cDevice>) {
+        self.devices.push(device);
+    }
+
+    /// Applies the ADDR, DATA and CTRLB.CMD writes latched by `sercom` to the bus.
+    pub fn update(&mut self, sercom: &mut SercomRegisters, tick_count: u64) {
+        if !sercom.is_i2c_master() {
+            sercom.address = None;
+            sercom.command = None;
+            sercom.sent = None;
+            return;
+        }
+
+        // Devices keep time with every address, byte and command, so that a clock read
+        // byte by byte moves on during the transfer
+        let elapsed = tick_count - self.last_tick;
+        self.last_tick = tick_count;
+        for device in self.devices.iter_mut() {
+            device.advance(elapsed);
+        }
+
+        if let Some(address) = sercom.address.take() {
+            self.start(address, sercom);
+        }
+
+        if let Some(value) = sercom.sent.take() {
+            let ack = !self.reading
+                && match self.selected {
+                    Some(index) => self.devices[index].write(value),
+                    None => false,
+                };
+            sercom.intflag = SercomRegisters::INTFLAG_MB;
+            sercom.status = SercomRegisters::STATUS_BUSSTATE_OWNER
+                | if ack {
+                    0
+                } else {
+                    SercomRegisters::STATUS_RXNACK
+                };
+        }
+
+        match sercom.command.take() {
+            Some(I2cBus::CMD_REPEATED_START) => {
+                // Re-send the last address; Wire never does this, but the datasheet allows it
+                if let Some(index) = self.selected {
+                    let address = self.devices[index].address() << 1 | self.reading as u8;
+                    self.start(address, sercom);
+                }
+            }
+            Some(I2cBus::CMD_READ) if self.reading => {
+                sercom.data = self.read_selected();
+                sercom.intflag = SercomRegisters::INTFLAG_SB;
+            }
+            Some(I2cBus::CMD_STOP) => {
+                if let Some(index) = self.selected.take() {
+                    self.devices[index].stop();
+                }
+                self.reading = false;
+                sercom.status = SercomRegisters::STATUS_BUSSTATE_IDLE;
+            }
+            _ => {}
+        }
+    }
+
+    fn start(&mut self, address: u8, sercom: &mut SercomRegisters) {
+        self.reading = address & 1 != 0;
+        self.selected = self
+            .devices
+            .iter()
+            .position(|device| device.address() == address >> 1);
+
+        if let Some(index) = self.selected {
+            self.devices[index].start(self.reading);
+        }
+
+        let ack = self.selected.is_some();
+        sercom.status = SercomRegisters::STATUS_BUSSTATE_OWNER
+            | if ack {
+                0
+            } else {
+                SercomRegisters::STATUS_RXNACK
+            };
+        if self.reading && ack {
+            sercom.data = self.read_selected();
+            sercom.intflag = SercomRegisters::INTFLAG_SB;
+        } else {
+            sercom.intflag = SercomRegisters::INTFLAG_MB;
+        }
+    }
+
+    fn read_selected(&mut self) -> u8 {
+        match self.selected {
+            Some(index) => self.devices[index].read(),
+            None => 0xff,
+        }
+    }
+}
+
+/// Microchip 24LC256 32 KiB serial EEPROM.
+pub struct Eeprom24lc256 {
+    address_pins: u8,
+    pointer: u16,
+    address_bytes: u8,
+    data: Vec<u8>,
+}
+
+impl Eeprom24lc256 {
+    const BASE_ADDRESS: u8 = 0x50;
+    const SIZE: usize = 0x8000;
+    const PAGE_SIZE: u16 = 64;
+
+    /// `address_pins` is the state of the A2..A0 pins.
+    pub fn new(address_pins: u8) -> Eeprom24lc256 {
+        Eeprom24lc256 {
+            address_pins: address_pins & 0b111,
+            pointer: 0,
+            address_bytes: 0,
+            data: vec![0xff; Eeprom24lc256::SIZE],
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn load(&mut self, contents: &[u8]) {
+        let len = contents.len().min(Eeprom24lc256::SIZE);
+        self.data[..len].copy_from_slice(&contents[..len]);
+    }
+}
+
+impl I2cDevice for Eeprom24lc256 {
+    fn address(&self) -> u8 {
+        Eeprom24lc256::BASE_ADDRESS | self.address_pins
+    }
+
+    fn start(&mut self, read: bool) {
+        if !read {
+            self.address_bytes = 0;
+        }
+    }
+
+    fn write(&mut self, value: u8) -> bool {
+        match self.address_bytes {
+            0 => {
+                self.pointer = ((value as u16) << 8) & 0x7f00;
+                self.address_bytes = 1;
+            }
+            1 => {
+                self.pointer |= value as u16;
+                self.address_bytes = 2;
+            }
+            _ => {
+                self.data[self.pointer as usize] = value;
+                // Page writes roll over within the current page
+                let page = self.pointer & !(Eeprom24lc256::PAGE_SIZE - 1);
+                self.pointer = page | ((self.pointer + 1) & (Eeprom24lc256::PAGE_SIZE - 1));
+            }
+        }
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.data[self.pointer as usize];
+        self.pointer = (self.pointer + 1) % Eeprom24lc256::SIZE as u16;
+        value
+    }
+}
+
+/// Maxim DS3231 real-time clock, keeping time from emulated ticks.
+pub struct Ds3231 {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    day: u8,
+    date: u8,
+    month: u8,
+    year: u8,
+    century: bool,
+    twelve_hour: bool,
+    registers: [u8; Ds3231::REGISTER_COUNT],
+    pointer: u8,
+    pointer_pending: bool,
+    tick_remainder: u64,
+}
+
+impl Ds3231 {
+    const ADDRESS: u8 = 0x68;
+    const REGISTER_COUNT: usize = 0x13;
+    const SECONDS: u8 = 0x00;
+    const MINUTES: u8 = 0x01;
+    const HOURS: u8 = 0x02;
+    const DAY: u8 = 0x03;
+    const DATE: u8 = 0x04;
+    const MONTH: u8 = 0x05;
+    const YEAR: u8 = 0x06;
+    const TEMP_MSB: u8 = 0x11;
+
+    pub fn new() -> Ds3231 {
+        let mut registers = [0; Ds3231::REGISTER_COUNT];
+        registers[Ds3231::TEMP_MSB as usize] = 25; // °C
+        Ds3231 {
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            day: 1,
+            date: 1,
+            month: 1,
+            year: 0,
+            century: false,
+            twelve_hour: false,
+            registers,
+            pointer: 0,
+            pointer_pending: false,
+            tick_remainder: 0,
+        }
+    }
+
+    /// Sets the current time. `year` is 0-99 and `day` is the user-defined day of week, 1-7.
+    #[allow(clippy::too_many_arguments)]
+    pub fn set_date_time(
+        &mut self,
+        year: u8,
+        month: u8,
+        date: u8,
+        day: u8,
+        hours: u8,
+        minutes: u8,
+        seconds: u8,
+    ) {
+        self.year = year % 100;
+        self.month = month.clamp(1, 12);
+        self.date = date.clamp(1, 31);
+        self.day = day.clamp(1, 7);
+        self.hours = hours % 24;
+        self.minutes = minutes % 60;
+        self.seconds = seconds % 60;
+        self.tick_remainder = 0;
+    }
+
+    fn days_in_month(&self) -> u8 {
+        match self.month {
+            2 if self.year & 3 == 0 => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        }
+    }
+
+    fn tick_second(&mut self) {
+        self.seconds += 1;
+        if self.seconds < 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes += 1;
+        if self.minutes < 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours += 1;
+        if self.hours < 24 {
+            return;
+        }
+        self.hours = 0;
+        self.day = self.day % 7 + 1;
+        self.date += 1;
+        if self.date <= self.days_in_month() {
+            return;
+        }
+        self.date = 1;
+        self.month += 1;
+        if self.month <= 12 {
+            return;
+        }
+        self.month = 1;
+        self.year += 1;
+        if self.year == 100 {
+            self.year = 0;
+            self.century = !self.century;
+        }
+    }
+
+    fn read_register(&self, register: u8) -> u8 {
+        match register {
+            Ds3231::SECONDS => to_bcd(self.seconds),
+            Ds3231::MINUTES => to_bcd(self.minutes),
+            Ds3231::HOURS => {
+                if self.twelve_hour {
+                    let pm = self.hours >= 12;
+                    let hours = match self.hours % 12 {
+                        0 => 12,
+                        hours => hours,
+                    };
+                    0x40 | (if pm { 0x20 } else { 0 }) | to_bcd(hours)
+                } else {
+                    to_bcd(self.hours)
+                }
+            }
+            Ds3231::DAY => self.day,
+            Ds3231::DATE => to_bcd(self.date),
+            Ds3231::MONTH => (if self.century { 0x80 } else { 0 }) | to_bcd(self.month),
+            Ds3231::YEAR => to_bcd(self.year),
+            _ => self.registers[register as usize],
+        }
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) {
+        match register {
+            Ds3231::SECONDS => {
+                self.seconds = from_bcd(value & 0x7f) % 60;
+                // Writing seconds resets the countdown chain
+                self.tick_remainder = 0;
+            }
+            Ds3231::MINUTES => self.minutes = from_bcd(value & 0x7f) % 60,
+            Ds3231::HOURS => {
+                self.twelve_hour = value & 0x40 != 0;
+                self.hours = if self.twelve_hour {
+                    let hours = from_bcd(value & 0x1f) % 12;
+                    if value & 0x20 != 0 {
+                        hours + 12
+                    } else {
+                        hours
+                    }
+                } else {
+                    from_bcd(value & 0x3f) % 24
+                };
+            }
+            Ds3231::DAY => self.day = (value & 0x07).max(1),
+            Ds3231::DATE => self.date = from_bcd(value & 0x3f).clamp(1, 31),
+            Ds3231::MONTH => {
+                self.century = value & 0x80 != 0;
+                self.month = from_bcd(value & 0x1f).clamp(1, 12);
+            }
+            Ds3231::YEAR => self.year = from_bcd(value) % 100,
+            // Temperature registers are read-only
+            0x11 | 0x12 => {}
+            _ => self.registers[register as usize] = value,
+        }
+    }
+}
+
+impl Default for Ds3231 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl I2cDevice for Ds3231 {
+    fn address(&self) -> u8 {
+        Ds3231::ADDRESS
+    }
+
+    fn start(&mut self, read: bool) {
+        self.pointer_pending = !read;
+    }
+
+    fn write(&mut self, value: u8) -> bool {
+        if self.pointer_pending {
+            self.pointer = value % Ds3231::REGISTER_COUNT as u8;
+            self.pointer_pending = false;
+        } else {
+            self.write_register(self.pointer, value);
+            self.pointer = (self.pointer + 1) % Ds3231::REGISTER_COUNT as u8;
+        }
+        true
+    }
+
+    fn read(&mut self) -> u8 {
+        let value = self.read_register(self.pointer);
+        self.pointer = (self.pointer + 1) % Ds3231::REGISTER_COUNT as u8;
+        value
+    }
+
+    fn advance(&mut self, ticks: u64) {
+        let ticks_per_second = GOAL_TICKS_PER_SECOND as u64;
+        self.tick_remainder += ticks;
+        while self.tick_remainder >= ticks_per_second {
+            self.tick_remainder -= ticks_per_second;
+            self.tick_second();
+        }
+    }
+}
+
+fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::register::Peripheral;
+    use crate::Gamebuino;
+
+    const TICKS_PER_SECOND: u64 = GOAL_TICKS_PER_SECOND as u64;
+
+    /// Drives the bus the way the Wire library drives SERCOM3.
+    struct Master {
+        bus: I2cBus,
+        sercom: SercomRegisters,
+        tick: u64,
+    }
+
+    impl Master {
+        fn new(device: Box<dyn I2cDevice>) -> Master {
+            let mut sercom = SercomRegisters::new();
+            // CTRLA.MODE = I2C master
+            sercom.handle_write_word(0x00, 0x5 << 2, &mut Gamebuino::new());
+            let mut bus = I2cBus::new();
+            bus.attach(device);
+            Master {
+                bus,
+                sercom,
+                tick: 0,
+            }
+        }
+
+        fn acked(&self) -> bool {
+            self.sercom.status & SercomRegisters::STATUS_RXNACK == 0
+        }
+
+        fn start(&mut self, address: u8, read: bool) -> bool {
+            self.sercom.address = Some(address << 1 | read as u8);
+            self.bus.update(&mut self.sercom, self.tick);
+            self.acked()
+        }
+
+        fn write(&mut self, address: u8, bytes: &[u8]) {
+            assert!(self.start(address, false));
+            for &byte in bytes {
+                self.sercom.sent = Some(byte);
+                self.bus.update(&mut self.sercom, self.tick);
+                assert!(self.acked());
+            }
+            self.command(I2cBus::CMD_STOP);
+        }
+
+        fn read(&mut self, address: u8, count: usize) -> Vec<u8> {
+            assert!(self.start(address, true));
+            let mut bytes = vec![self.sercom.data];
+            while bytes.len() < count {
+                self.command(I2cBus::CMD_READ);
+                bytes.push(self.sercom.data);
+            }
+            self.command(I2cBus::CMD_STOP);
+            bytes
+        }
+
+        fn command(&mut self, command: u8) {
+            self.sercom.command = Some(command);
+            self.bus.update(&mut self.sercom, self.tick);
+        }
+    }
+
+    #[test]
+    fn eeprom_answers_on_its_pin_address() {
+        let mut master = Master::new(Box::new(Eeprom24lc256::new(0b101)));
+        assert!(!master.start(0x50, false));
+        master.command(I2cBus::CMD_STOP);
+        assert!(master.start(0x55, false));
+    }
+
+    #[test]
+    fn eeprom_page_writes_wrap_within_the_page() {
+        let mut master = Master::new(Box::new(Eeprom24lc256::new(0)));
+        master.write(0x50, &[0x7f, 0x3e, 1, 2, 3, 4]);
+
+        master.write(0x50, &[0x7f, 0x3e]);
+        assert_eq!(master.read(0x50, 3), [1, 2, 0xff]);
+        // A read carries on from where the last one stopped
+        assert_eq!(master.read(0x50, 1), [0xff]);
+        master.write(0x50, &[0x7f, 0x00]);
+        assert_eq!(master.read(0x50, 3), [3, 4, 0xff]);
+    }
+
+    #[test]
+    fn eeprom_reads_wrap_at_the_end_of_memory() {
+        let mut eeprom = Eeprom24lc256::new(0);
+        eeprom.load(&[9]);
+        let mut master = Master::new(Box::new(eeprom));
+        master.write(0x50, &[0x7f, 0xff, 8]);
+        // The high address bit is ignored
+        master.write(0x50, &[0xff, 0xff]);
+        assert_eq!(master.read(0x50, 2), [8, 9]);
+    }
+
+    #[test]
+    fn ds3231_rolls_over_in_bcd() {
+        let mut master = Master::new(Box::new(Ds3231::new()));
+        // 23:59:59 on Sunday 31/12/99
+        master.write(0x68, &[0x00, 0x59, 0x59, 0x23, 7, 0x31, 0x12, 0x99]);
+        master.write(0x68, &[0x00]);
+        assert_eq!(
+            master.read(0x68, 7),
+            [0x59, 0x59, 0x23, 7, 0x31, 0x12, 0x99]
+        );
+
+        master.tick += TICKS_PER_SECOND - 1;
+        master.write(0x68, &[0x00]);
+        assert_eq!(master.read(0x68, 1), [0x59]);
+        master.tick += 1;
+        master.write(0x68, &[0x00]);
+        // The century bit toggles when the year wraps
+        assert_eq!(master.read(0x68, 7), [0, 0, 0, 1, 0x01, 0x81, 0x00]);
+    }
+
+    #[test]
+    fn ds3231_keeps_leap_days_and_twelve_hour_time() {
+        let mut master = Master::new(Box::new(Ds3231::new()));
+        // 11:59:59 PM on 28/02/24, then 28/02/23
+        master.write(
+            0x68,
+            &[0x00, 0x59, 0x59, 0x40 | 0x20 | 0x11, 3, 0x28, 0x02, 0x24],
+        );
+        master.tick += TICKS_PER_SECOND;
+        master.write(0x68, &[0x02]);
+        assert_eq!(master.read(0x68, 4), [0x40 | 0x12, 4, 0x29, 0x02]);
+
+        master.write(0x68, &[0x00, 0x59, 0x59, 0x23, 3, 0x28, 0x02, 0x23]);
+        master.tick += TICKS_PER_SECOND;
+        master.write(0x68, &[0x02]);
+        assert_eq!(master.read(0x68, 4), [0x00, 4, 0x01, 0x03]);
+    }
+
+    #[test]
+    fn ds3231_advances_during_a_transfer() {
+        let mut master = Master::new(Box::new(Ds3231::new()));
+        master.write(0x68, &[0x00]);
+        assert!(master.start(0x68, true));
+        assert_eq!(master.sercom.data, 0x00);
+        master.tick += TICKS_PER_SECOND;
+        // Read round to the seconds register again
+        for _ in 0..Ds3231::REGISTER_COUNT {
+            master.command(I2cBus::CMD_READ);
+        }
+        assert_eq!(master.sercom.data, 0x01);
+        master.command(I2cBus::CMD_STOP);
+    }
+}
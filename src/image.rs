@@ -0,0 +1,235 @@
+//! Encoders for the 32-bit pixels the screen produces: red in the low byte, then green, blue
+//! and alpha.
+
+/// Encodes pixels as a truecolor PNG, each pixel repeated `scale` times in both directions.
+pub fn encode_png(pixels: &[u32], width: usize, height: usize, scale: usize) -> Vec<u8> {
+    let scale = scale.max(1);
+    let (out_width, out_height) = (width * scale, height * scale);
+
+    // Each scanline starts with filter type 0 (none)
+    let mut raw = Vec::with_capacity((out_width * 3 + 1) * out_height);
+    for y in 0..out_height {
+        raw.push(0);
+        for x in 0..out_width {
+            let pixel = pixels[(y / scale) * width + x / scale];
+            raw.extend_from_slice(&[pixel as u8, (pixel >> 8) as u8, (pixel >> 16) as u8]);
+        }
+    }
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(out_width as u32).to_be_bytes());
+    header.extend_from_slice(&(out_height as u32).to_be_bytes());
+    header.extend_from_slice(&[8, 2, 0, 0, 0]); // 8-bit RGB, deflate, no filter, no interlace
+    write_png_chunk(&mut png, b"IHDR", &header);
+    write_png_chunk(&mut png, b"IDAT", &zlib_stored(&raw));
+    write_png_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+/// Encodes pixels as a bottom-up 24-bit BMP, each pixel repeated `scale` times in both
+/// directions.
+pub fn encode_bmp(pixels: &[u32], width: usize, height: usize, scale: usize) -> Vec<u8> {
+    let scale = scale.max(1);
+    let (out_width, out_height) = (width * scale, height * scale);
+    let row_size = (out_width * 3 + 3) & !3;
+    let data_offset = 14 + 40;
+    let file_size = data_offset + row_size * out_height;
+
+    let mut bmp = Vec::with_capacity(file_size);
+    bmp.extend_from_slice(b"BM");
+    bmp.extend_from_slice(&(file_size as u32).to_le_bytes());
+    bmp.extend_from_slice(&[0; 4]);
+    bmp.extend_from_slice(&(data_offset as u32).to_le_bytes());
+
+    bmp.extend_from_slice(&40u32.to_le_bytes()); // BITMAPINFOHEADER
+    bmp.extend_from_slice(&(out_width as i32).to_le_bytes());
+    bmp.extend_from_slice(&(out_height as i32).to_le_bytes());
+    bmp.extend_from_slice(&1u16.to_le_bytes()); // planes
+    bmp.extend_from_slice(&24u16.to_le_bytes()); // bits per pixel
+    bmp.extend_from_slice(&0u32.to_le_bytes()); // BI_RGB
+    bmp.extend_from_slice(&((row_size * out_height) as u32).to_le_bytes());
+    bmp.extend_from_slice(&2835i32.to_le_bytes()); // 72 DPI
+    bmp.extend_from_slice(&2835i32.to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes());
+    bmp.extend_from_slice(&0u32.to_le_bytes());
+
+    for y in (0..out_height).rev() {
+        for x in 0..out_width {
+            let pixel = pixels[(y / scale) * width + x / scale];
+            bmp.extend_from_slice(&[(pixel >> 16) as u8, (pixel >> 8) as u8, pixel as u8]);
+        }
+        bmp.resize(bmp.len() + row_size - out_width * 3, 0);
+    }
+    bmp
+}
+
+fn write_png_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// Wraps data in a zlib stream made of uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 0xffff;
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(MAX_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+
+    const RED: u32 = 0xff0000ff;
+    const GREEN: u32 = 0xff00ff00;
+    const BLUE: u32 = 0xffff0000;
+    const WHITE: u32 = 0xffffffff;
+
+    fn be32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn le32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Splits a PNG into its chunks, checking each CRC.
+    fn png_chunks(png: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut chunks = Vec::new();
+        let mut offset = 8;
+        while offset < png.len() {
+            let length = be32(png, offset) as usize;
+            let kind: [u8; 4] = png[offset + 4..offset + 8].try_into().unwrap();
+            let data = &png[offset + 8..offset + 8 + length];
+            let crc = be32(png, offset + 8 + length);
+            assert_eq!(crc, crc32(&png[offset + 4..offset + 8 + length]));
+            chunks.push((kind, data));
+            offset += 12 + length;
+        }
+        chunks
+    }
+
+    /// Undoes `zlib_stored`, checking the block lengths and checksum.
+    fn unzlib_stored(stream: &[u8]) -> Vec<u8> {
+        assert_eq!(&stream[..2], &[0x78, 0x01]);
+        let mut data = Vec::new();
+        let mut offset = 2;
+        loop {
+            let last = stream[offset] & 1 != 0;
+            let length = u16::from_le_bytes([stream[offset + 1], stream[offset + 2]]);
+            let complement = u16::from_le_bytes([stream[offset + 3], stream[offset + 4]]);
+            assert_eq!(length, !complement);
+            offset += 5;
+            data.extend_from_slice(&stream[offset..offset + length as usize]);
+            offset += length as usize;
+            if last {
+                break;
+            }
+        }
+        assert_eq!(be32(stream, offset), adler32(&data));
+        assert_eq!(offset + 4, stream.len());
+        data
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        assert_eq!(adler32(b""), 1);
+    }
+
+    #[test]
+    fn zlib_stored_round_trips() {
+        assert_eq!(unzlib_stored(&zlib_stored(&[])), Vec::<u8>::new());
+        let data: Vec<u8> = (0..150_000u32).map(|i| (i * 7) as u8).collect();
+        assert_eq!(unzlib_stored(&zlib_stored(&data)), data);
+    }
+
+    #[test]
+    fn png_scales_pixels() {
+        let png = encode_png(&[RED, GREEN, BLUE, WHITE], 2, 2, 2);
+        assert_eq!(&png[..8], &[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a]);
+
+        let chunks = png_chunks(&png);
+        let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+        assert_eq!(kinds, [b"IHDR", b"IDAT", b"IEND"]);
+        let header = chunks[0].1;
+        assert_eq!((be32(header, 0), be32(header, 4)), (4, 4));
+        assert_eq!(&header[8..], &[8, 2, 0, 0, 0]);
+
+        let raw = unzlib_stored(chunks[1].1);
+        let row = |colors: [[u8; 3]; 2]| {
+            let mut row = vec![0];
+            for color in colors {
+                row.extend_from_slice(&color);
+                row.extend_from_slice(&color);
+            }
+            row
+        };
+        let top = row([[0xff, 0, 0], [0, 0xff, 0]]);
+        let bottom = row([[0, 0, 0xff], [0xff, 0xff, 0xff]]);
+        assert_eq!(raw, [&top[..], &top, &bottom, &bottom].concat());
+    }
+
+    #[test]
+    fn bmp_is_bottom_up_bgr_with_padded_rows() {
+        let bmp = encode_bmp(&[RED, GREEN, BLUE, WHITE], 2, 2, 1);
+        assert_eq!(&bmp[..2], b"BM");
+        assert_eq!(le32(&bmp, 2) as usize, bmp.len());
+        assert_eq!(le32(&bmp, 10), 54);
+        assert_eq!((le32(&bmp, 18), le32(&bmp, 22)), (2, 2));
+        // Rows of 6 bytes padded to 8, the bottom row first
+        assert_eq!(bmp.len(), 54 + 2 * 8);
+        assert_eq!(
+            &bmp[54..],
+            &[
+                0xff, 0, 0, 0xff, 0xff, 0xff, 0, 0, // blue, white
+                0, 0, 0xff, 0, 0xff, 0, 0, 0, // red, green
+            ]
+        );
+    }
+}
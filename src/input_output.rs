@@ -9,18 +9,64 @@ pub struct St7735 {
     y: u8,
     arg_index: u8,
     last_command: u8,
-    tmp_data: u8,
+    pixel_bytes: [u8; 3],
+    pixel_byte_count: usize,
+    madctl: u8,
+    colmod: u8,
+    inverted: bool,
+    display_on: bool,
+    sleeping: bool,
+    scrolling: bool,
+    top_fixed_area: u16,
+    scroll_area: u16,
+    bottom_fixed_area: u16,
+    scroll_start: u16,
+    gram: [u32; St7735::GRAM_WIDTH * St7735::GRAM_HEIGHT],
     image_data: [u32; St7735::WIDTH * St7735::HEIGHT],
+    frame_data: [u32; St7735::WIDTH * St7735::HEIGHT],
+    pub frame_count: u32,
+    pub double_buffered: bool,
 }
 
 impl St7735 {
+    const SWRESET: u8 = 0x01; // Software reset command
+    const SLPIN: u8 = 0x10; // Sleep in command
+    const SLPOUT: u8 = 0x11; // Sleep out command
+    const NORON: u8 = 0x13; // Normal display mode on command
+    const INVOFF: u8 = 0x20; // Display inversion off command
+    const INVON: u8 = 0x21; // Display inversion on command
+    const DISPOFF: u8 = 0x28; // Display off command
+    const DISPON: u8 = 0x29; // Display on command
     const CASET: u8 = 0x2a; // Column address set command
     const RASET: u8 = 0x2b; // Row address set command
     const RAMWR: u8 = 0x2c; // Memory write command
-    const WIDTH: usize = 160; // Screen width in pixels
-    const HEIGHT: usize = 128; // Screen height in pixels
+    const RAMRD: u8 = 0x2e; // Memory read command
+    const VSCRDEF: u8 = 0x33; // Vertical scrolling definition command
+    const VSCSAD: u8 = 0x37; // Vertical scroll start address command
+    const MADCTL: u8 = 0x36; // Memory data access control command
+    const COLMOD: u8 = 0x3a; // Interface pixel format command
+    pub const WIDTH: usize = 160; // Screen width in pixels
+    pub const HEIGHT: usize = 128; // Screen height in pixels
+    const GRAM_WIDTH: usize = 128; // Controller columns, portrait
+    const GRAM_HEIGHT: usize = 160; // Controller rows, portrait
+
+    const MADCTL_MY: u8 = 0x80; // Row address order
+    const MADCTL_MX: u8 = 0x40; // Column address order
+    const MADCTL_MV: u8 = 0x20; // Row/column exchange
+    const MADCTL_BGR: u8 = 0x08; // BGR color filter order
+
+    // Landscape orientation set up by the bootloader and the Gamebuino library
+    const MADCTL_DEFAULT: u8 = St7735::MADCTL_MY | St7735::MADCTL_MV;
+
+    const COLMOD_12_BIT: u8 = 0b011;
+    const COLMOD_16_BIT: u8 = 0b101;
+    const COLMOD_18_BIT: u8 = 0b110;
+
+    const BLANK_COLOR: u32 = 0xffffffff; // Normally white panel with no drive
 
     pub fn new() -> St7735 {
+        // Games are started without the bootloader, which would have left the display awake
+        // and on in landscape, so start in that state rather than the power-on defaults.
         St7735 {
             x_start: 0,
             x_end: 0,
@@ -30,47 +76,70 @@ impl St7735 {
             y: 0,
             arg_index: 0,
             last_command: 0,
-            tmp_data: 0,
+            pixel_bytes: [0; 3],
+            pixel_byte_count: 0,
+            madctl: St7735::MADCTL_DEFAULT,
+            colmod: St7735::COLMOD_16_BIT,
+            inverted: false,
+            display_on: true,
+            sleeping: false,
+            scrolling: false,
+            top_fixed_area: 0,
+            scroll_area: St7735::GRAM_HEIGHT as u16,
+            bottom_fixed_area: 0,
+            scroll_start: 0,
+            gram: [0; St7735::GRAM_WIDTH * St7735::GRAM_HEIGHT],
             image_data: [0; St7735::WIDTH * St7735::HEIGHT],
+            frame_data: [0; St7735::WIDTH * St7735::HEIGHT],
+            frame_count: 0,
+            double_buffered: false,
         }
     }
 
+    /// The last complete frame.
+    pub fn frame(&self) -> &[u32] {
+        &self.frame_data
+    }
+
+    /// The frame `image_pointer` points to.
+    pub fn image(&self) -> &[u32] {
+        if self.double_buffered {
+            &self.frame_data
+        } else {
+            &self.image_data
+        }
+    }
+
+    /// The last complete frame when double buffered, otherwise the panel as it is being drawn.
     pub fn image_pointer(&self) -> *const u32 {
-        self.image_data.as_ptr()
+        self.image().as_ptr()
     }
 
-    pub fn byte_received(&mut self, value: u8, porta: &PortRegisters, portb: &PortRegisters) {
+    pub fn byte_received(
+        &mut self,
+        value: u8,
+        porta: &PortRegisters,
+        portb: &PortRegisters,
+        sercom4: &mut SercomRegisters,
+    ) {
         if porta.out_value & (1 << 22) != 0 {
             return;
         }
         if portb.out_value & 0b100000000000000000000000 != 0 {
             match self.last_command {
                 St7735::RAMWR => {
-                    if self.arg_index % 2 == 0 {
-                        self.tmp_data = value;
-                    } else {
-                        let pixel_data = ((self.tmp_data as u32) << 8) | value as u32;
-                        let r = (0b1111100000000000 & pixel_data) >> 8;
-                        let g = (0b0000011111100000 & pixel_data) >> 3;
-                        let b = (0b0000000000011111 & pixel_data) << 3;
-                        let color = (255 << 24) | // alpha
-                                    (b   << 16) | // blue
-                                    (g   <<  8) | // green
-                                     r; // red
-                        let base_index = self.y as usize * St7735::WIDTH + self.x as usize;
-
-                        if base_index < St7735::WIDTH * St7735::HEIGHT {
-                            self.image_data[base_index] = color;
-                        }
-
-                        self.x += 1;
-                        if self.x > self.x_end {
-                            self.x = self.x_start;
-                            self.y += 1;
-                            if self.y > self.y_end {
-                                self.y = self.y_end;
-                            }
-                        }
+                    self.pixel_bytes[self.pixel_byte_count] = value;
+                    self.pixel_byte_count += 1;
+                    self.write_pixels();
+                }
+                // The first byte clocked out is a dummy read, then 18-bit pixels
+                St7735::RAMRD if self.arg_index > 0 => {
+                    let color = self.gram_color();
+                    sercom4.data = (color >> (8 * self.pixel_byte_count)) as u8 & 0xfc;
+                    self.pixel_byte_count += 1;
+                    if self.pixel_byte_count == 3 {
+                        self.pixel_byte_count = 0;
+                        self.advance_address();
                     }
                 }
                 St7735::CASET => {
@@ -89,12 +158,260 @@ impl St7735 {
                         self.y_end = value;
                     }
                 }
+                St7735::VSCRDEF => match self.arg_index {
+                    0 => self.top_fixed_area = (value as u16) << 8,
+                    1 => self.top_fixed_area |= value as u16,
+                    2 => self.scroll_area = (value as u16) << 8,
+                    3 => self.scroll_area |= value as u16,
+                    4 => self.bottom_fixed_area = (value as u16) << 8,
+                    5 => {
+                        self.bottom_fixed_area |= value as u16;
+                        self.present();
+                    }
+                    _ => {}
+                },
+                St7735::VSCSAD => {
+                    if self.arg_index == 0 {
+                        self.scroll_start = (value as u16) << 8;
+                    } else if self.arg_index == 1 {
+                        self.scroll_start |= value as u16;
+                        self.scrolling = true;
+                        self.present();
+                    }
+                }
+                St7735::MADCTL if self.arg_index == 0 => {
+                    self.madctl = value;
+                }
+                St7735::COLMOD if self.arg_index == 0 => {
+                    self.colmod = value & 0b111;
+                }
                 _ => {}
             }
-            self.arg_index += 1;
+            self.arg_index = self.arg_index.saturating_add(1);
         } else {
             self.last_command = value;
             self.arg_index = 0;
+            self.pixel_byte_count = 0;
+            self.command_received(value);
+        }
+    }
+
+    fn command_received(&mut self, command: u8) {
+        match command {
+            St7735::SWRESET => {
+                self.scrolling = false;
+                self.madctl = 0;
+                self.colmod = St7735::COLMOD_18_BIT;
+                self.inverted = false;
+                self.display_on = false;
+                self.sleeping = true;
+                self.present();
+            }
+            St7735::SLPIN => {
+                self.sleeping = true;
+                self.present();
+            }
+            St7735::SLPOUT => {
+                self.sleeping = false;
+                self.present();
+            }
+            St7735::NORON => {
+                self.scrolling = false;
+                self.present();
+            }
+            St7735::INVOFF => {
+                self.inverted = false;
+                self.present();
+            }
+            St7735::INVON => {
+                self.inverted = true;
+                self.present();
+            }
+            St7735::DISPOFF => {
+                self.display_on = false;
+                self.present();
+            }
+            St7735::DISPON => {
+                self.display_on = true;
+                self.present();
+            }
+            St7735::RAMWR | St7735::RAMRD => {
+                self.x = self.x_start;
+                self.y = self.y_start;
+            }
+            _ => {}
+        }
+    }
+
+    /// Decodes the pixels accumulated in `pixel_bytes` according to COLMOD.
+    fn write_pixels(&mut self) {
+        match self.colmod {
+            St7735::COLMOD_12_BIT => {
+                // Two pixels packed in three bytes: RRRRGGGG BBBBRRRR GGGGBBBB
+                if self.pixel_byte_count == 2 {
+                    let [b0, b1, _] = self.pixel_bytes;
+                    self.write_pixel(b0 & 0xf0, (b0 & 0x0f) << 4, b1 & 0xf0);
+                } else if self.pixel_byte_count == 3 {
+                    let [_, b1, b2] = self.pixel_bytes;
+                    self.write_pixel((b1 & 0x0f) << 4, b2 & 0xf0, (b2 & 0x0f) << 4);
+                    self.pixel_byte_count = 0;
+                }
+            }
+            St7735::COLMOD_18_BIT => {
+                if self.pixel_byte_count == 3 {
+                    let [r, g, b] = self.pixel_bytes;
+                    self.write_pixel(r & 0xfc, g & 0xfc, b & 0xfc);
+                    self.pixel_byte_count = 0;
+                }
+            }
+            _ => {
+                if self.pixel_byte_count == 2 {
+                    let pixel_data =
+                        ((self.pixel_bytes[0] as u32) << 8) | self.pixel_bytes[1] as u32;
+                    let r = (0b1111100000000000 & pixel_data) >> 8;
+                    let g = (0b0000011111100000 & pixel_data) >> 3;
+                    let b = (0b0000000000011111 & pixel_data) << 3;
+                    self.write_pixel(r as u8, g as u8, b as u8);
+                    self.pixel_byte_count = 0;
+                }
+            }
+        }
+    }
+
+    fn write_pixel(&mut self, r: u8, g: u8, b: u8) {
+        let (r, b) = if self.madctl & St7735::MADCTL_BGR != 0 {
+            (b, r)
+        } else {
+            (r, b)
+        };
+        let color = (255 << 24) | // alpha
+                    ((b as u32) << 16) | // blue
+                    ((g as u32) <<  8) | // green
+                     r as u32; // red
+
+        if let Some(index) = self.gram_index() {
+            self.gram[index] = color;
+            self.present_pixel(index);
+        }
+
+        self.advance_address();
+    }
+
+    /// Color at the address counter, in the same component order it was written.
+    fn gram_color(&self) -> u32 {
+        let color = match self.gram_index() {
+            Some(index) => self.gram[index],
+            None => 0,
+        };
+        if self.madctl & St7735::MADCTL_BGR != 0 {
+            (color & 0xff00ff00) | ((color >> 16) & 0xff) | ((color & 0xff) << 16)
+        } else {
+            color
+        }
+    }
+
+    fn advance_address(&mut self) {
+        self.x = self.x.wrapping_add(1);
+        if self.x > self.x_end {
+            self.x = self.x_start;
+            self.y = self.y.wrapping_add(1);
+            if self.y > self.y_end {
+                self.y = self.y_start;
+                if self.is_full_window() {
+                    self.frame_completed();
+                }
+            }
+        }
+    }
+
+    fn is_full_window(&self) -> bool {
+        let (columns, rows) = if self.madctl & St7735::MADCTL_MV != 0 {
+            (St7735::GRAM_HEIGHT, St7735::GRAM_WIDTH)
+        } else {
+            (St7735::GRAM_WIDTH, St7735::GRAM_HEIGHT)
+        };
+        self.x_start == 0
+            && self.y_start == 0
+            && self.x_end as usize >= columns - 1
+            && self.y_end as usize >= rows - 1
+    }
+
+    /// Snapshots the panel for the host once the game has pushed a whole frame.
+    fn frame_completed(&mut self) {
+        self.frame_data.copy_from_slice(&self.image_data);
+        self.frame_count = self.frame_count.wrapping_add(1);
+    }
+
+    /// Maps the column/row address counters through MADCTL to a GRAM index.
+    fn gram_index(&self) -> Option<usize> {
+        let column = self.x as usize;
+        let row = self.y as usize;
+        let exchange = self.madctl & St7735::MADCTL_MV != 0;
+        let (mut gram_x, mut gram_y) = if exchange {
+            (row, column)
+        } else {
+            (column, row)
+        };
+        if gram_x >= St7735::GRAM_WIDTH || gram_y >= St7735::GRAM_HEIGHT {
+            return None;
+        }
+        if self.madctl & St7735::MADCTL_MX != 0 {
+            gram_x = St7735::GRAM_WIDTH - 1 - gram_x;
+        }
+        if self.madctl & St7735::MADCTL_MY != 0 {
+            gram_y = St7735::GRAM_HEIGHT - 1 - gram_y;
+        }
+        Some(gram_y * St7735::GRAM_WIDTH + gram_x)
+    }
+
+    /// Whether the VSCRDEF areas are valid, which the scroll offset depends on.
+    fn scroll_active(&self) -> bool {
+        self.scrolling
+            && self.scroll_area > 0
+            && self.top_fixed_area as usize
+                + self.scroll_area as usize
+                + self.bottom_fixed_area as usize
+                == St7735::GRAM_HEIGHT
+    }
+
+    /// Panel line on which a GRAM row is displayed, taking vertical scrolling into account.
+    fn scan_line(&self, gram_y: usize) -> usize {
+        let top = self.top_fixed_area as usize;
+        let area = self.scroll_area as usize;
+        if !self.scroll_active() || gram_y < top || gram_y >= top + area {
+            return gram_y;
+        }
+        // VSCSAD gives the GRAM row shown on the first line of the scroll area
+        let offset = (self.scroll_start as usize + area - top % area) % area;
+        top + (gram_y - top + area - offset) % area
+    }
+
+    /// Copies one GRAM pixel to where the panel shows it.
+    fn present_pixel(&mut self, gram_index: usize) {
+        // The panel is mounted so that GRAM row 0 is the right edge of the screen
+        let gram_x = gram_index % St7735::GRAM_WIDTH;
+        let gram_y = gram_index / St7735::GRAM_WIDTH;
+        let x = St7735::WIDTH - 1 - self.scan_line(gram_y);
+        let y = gram_x;
+        self.image_data[y * St7735::WIDTH + x] = self.output_color(self.gram[gram_index]);
+    }
+
+    /// Recomposes the whole panel after a display mode change, which takes effect immediately.
+    /// The host sees the change, but it isn't counted as a frame since the game drew nothing.
+    fn present(&mut self) {
+        for index in 0..self.gram.len() {
+            self.present_pixel(index);
+        }
+        self.frame_data.copy_from_slice(&self.image_data);
+    }
+
+    fn output_color(&self, color: u32) -> u32 {
+        if self.sleeping || !self.display_on {
+            St7735::BLANK_COLOR
+        } else if self.inverted {
+            color ^ 0x00ffffff
+        } else {
+            color
         }
     }
 }
@@ -121,3 +438,192 @@ impl Buttons {
         sercom4.data = self.button_data;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a command and its arguments as the Gamebuino library does over SERCOM4: chip
+    /// select low, and D/C low for the command then high for the arguments.
+    fn send(screen: &mut St7735, command: u8, args: &[u8]) {
+        let porta = PortRegisters::new();
+        let mut portb = PortRegisters::new();
+        let mut sercom4 = SercomRegisters::new();
+        screen.byte_received(command, &porta, &portb, &mut sercom4);
+        portb.out_value = 1 << 23;
+        for &arg in args {
+            screen.byte_received(arg, &porta, &portb, &mut sercom4);
+        }
+    }
+
+    fn set_window(screen: &mut St7735, x: (u8, u8), y: (u8, u8)) {
+        send(screen, St7735::CASET, &[0, x.0, 0, x.1]);
+        send(screen, St7735::RASET, &[0, y.0, 0, y.1]);
+    }
+
+    /// Writes `count` 16-bit pixels of one color to the window.
+    fn fill(screen: &mut St7735, color: u16, count: usize) {
+        let pixels: Vec<u8> = (0..count).flat_map(|_| color.to_be_bytes()).collect();
+        send(screen, St7735::RAMWR, &pixels);
+    }
+
+    const RED: u32 = 0xff0000f8;
+    const GREEN: u32 = 0xff00fc00;
+    const BLUE: u32 = 0xfff80000;
+
+    fn pixel_at(screen: &St7735, x: usize, y: usize) -> u32 {
+        screen.image()[y * St7735::WIDTH + x]
+    }
+
+    fn plot(screen: &mut St7735, x: u8, y: u8, color: u16) {
+        set_window(screen, (x, x), (y, y));
+        fill(screen, color, 1);
+    }
+
+    #[test]
+    fn madctl_maps_addresses_to_the_panel() {
+        let my = St7735::MADCTL_MY;
+        let mx = St7735::MADCTL_MX;
+        let mv = St7735::MADCTL_MV;
+        for &(madctl, x, y) in &[
+            (0, 157, 1),
+            (mx, 157, 126),
+            (my, 2, 1),
+            (mv, 158, 2),
+            (my | mv, 1, 2),
+            (mx | my | mv, 1, 125),
+        ] {
+            let mut screen = St7735::new();
+            send(&mut screen, St7735::MADCTL, &[madctl]);
+            plot(&mut screen, 1, 2, 0xf800);
+            assert_eq!(pixel_at(&screen, x, y), RED, "MADCTL {:02x}", madctl);
+            assert_eq!(
+                screen.image().iter().filter(|&&pixel| pixel != 0).count(),
+                1
+            );
+        }
+
+        // Columns past the portrait GRAM width don't exist without MV
+        let mut screen = St7735::new();
+        send(&mut screen, St7735::MADCTL, &[0]);
+        plot(&mut screen, 130, 2, 0xf800);
+        assert!(screen.image().iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn colmod_selects_the_pixel_format() {
+        let mut screen = St7735::new();
+        send(&mut screen, St7735::COLMOD, &[0x03]);
+        set_window(&mut screen, (0, 1), (0, 0));
+        send(&mut screen, St7735::RAMWR, &[0xf0, 0x0f, 0xf0]);
+        assert_eq!(pixel_at(&screen, 0, 0), 0xff0000f0);
+        assert_eq!(pixel_at(&screen, 1, 0), 0xff00f0f0);
+
+        send(&mut screen, St7735::COLMOD, &[0x06]);
+        set_window(&mut screen, (0, 0), (0, 0));
+        send(&mut screen, St7735::RAMWR, &[0xfc, 0x81, 0x07]);
+        assert_eq!(pixel_at(&screen, 0, 0), 0xff0480fc);
+
+        // The Gamebuino library sends 0x55; only the MCU interface format matters
+        send(&mut screen, St7735::COLMOD, &[0x55]);
+        plot(&mut screen, 0, 0, 0x07e0);
+        assert_eq!(pixel_at(&screen, 0, 0), GREEN);
+
+        send(
+            &mut screen,
+            St7735::MADCTL,
+            &[St7735::MADCTL_DEFAULT | St7735::MADCTL_BGR],
+        );
+        plot(&mut screen, 0, 0, 0xf800);
+        assert_eq!(pixel_at(&screen, 0, 0), BLUE);
+    }
+
+    #[test]
+    fn window_writes_wrap_to_the_window_start() {
+        let mut screen = St7735::new();
+        set_window(&mut screen, (10, 11), (5, 6));
+        let pixels: Vec<u8> = [0xf800u16, 0x07e0, 0x07e0, 0x07e0, 0x001f]
+            .iter()
+            .flat_map(|color| color.to_be_bytes())
+            .collect();
+        send(&mut screen, St7735::RAMWR, &pixels);
+        assert_eq!(pixel_at(&screen, 10, 5), BLUE);
+        assert_eq!(pixel_at(&screen, 11, 5), GREEN);
+        assert_eq!(pixel_at(&screen, 11, 6), GREEN);
+        assert_eq!(pixel_at(&screen, 12, 5), 0);
+        assert_eq!(pixel_at(&screen, 10, 7), 0);
+    }
+
+    #[test]
+    fn scrolling_maps_the_start_address_to_the_first_scrolled_line() {
+        let mut screen = St7735::new();
+        // Columns map to GRAM rows from the right edge of the panel
+        plot(&mut screen, 139, 0, 0xf800); // GRAM row 20
+        plot(&mut screen, 140, 0, 0x07e0); // GRAM row 19
+        plot(&mut screen, 156, 0, 0x001f); // GRAM row 3, in the top fixed area
+
+        send(&mut screen, St7735::VSCRDEF, &[0, 8, 0, 144, 0, 8]);
+        send(&mut screen, St7735::VSCSAD, &[0, 20]);
+        assert_eq!(pixel_at(&screen, 159 - 8, 0), RED);
+        assert_eq!(pixel_at(&screen, 159 - 151, 0), GREEN);
+        assert_eq!(pixel_at(&screen, 156, 0), BLUE);
+        assert_eq!(pixel_at(&screen, 139, 0), 0);
+
+        send(&mut screen, St7735::NORON, &[]);
+        assert_eq!(pixel_at(&screen, 139, 0), RED);
+        assert_eq!(pixel_at(&screen, 140, 0), GREEN);
+    }
+
+    #[test]
+    fn scrolling_ignores_areas_that_dont_cover_the_panel() {
+        let mut screen = St7735::new();
+        plot(&mut screen, 139, 0, 0xf800);
+        send(
+            &mut screen,
+            St7735::VSCRDEF,
+            &[0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        send(&mut screen, St7735::VSCSAD, &[0, 20]);
+        assert_eq!(pixel_at(&screen, 139, 0), RED);
+    }
+
+    #[test]
+    fn only_full_window_writes_complete_frames() {
+        let mut screen = St7735::new();
+        set_window(&mut screen, (0, 159), (0, 126));
+        fill(&mut screen, 0xf800, 160 * 127);
+        assert_eq!(screen.frame_count, 0);
+
+        set_window(&mut screen, (0, 159), (0, 127));
+        fill(&mut screen, 0xf800, 160 * 128 - 1);
+        assert_eq!(screen.frame_count, 0);
+        fill(&mut screen, 0xf800, 160 * 128);
+        assert_eq!(screen.frame_count, 1);
+        assert!(screen.frame().iter().all(|&pixel| pixel == 0xff0000f8));
+    }
+
+    #[test]
+    fn display_mode_changes_show_without_counting_frames() {
+        let mut screen = St7735::new();
+        for &command in &[
+            St7735::INVON,
+            St7735::DISPOFF,
+            St7735::DISPON,
+            St7735::SLPIN,
+            St7735::SLPOUT,
+            St7735::NORON,
+            St7735::INVOFF,
+            St7735::SWRESET,
+        ] {
+            send(&mut screen, command, &[]);
+        }
+        send(&mut screen, St7735::VSCRDEF, &[0, 0, 0, 160, 0, 0]);
+        send(&mut screen, St7735::VSCSAD, &[0, 10]);
+        assert_eq!(screen.frame_count, 0);
+
+        let mut screen = St7735::new();
+        send(&mut screen, St7735::INVON, &[]);
+        assert_eq!(screen.frame_count, 0);
+        assert!(screen.frame().iter().all(|&pixel| pixel == 0x00ffffff));
+    }
+}
@@ -0,0 +1,293 @@
+//! Read-only views of the emulated state, for memory viewers and other tooling.
+
+use crate::register::{DmacRegisters, PortRegisters, SercomRegisters};
+use crate::{Gamebuino, GOAL_TICKS_PER_SECOND, LR_INDEX, SP_INDEX};
+use wasm_bindgen::prelude::*;
+
+const XPSR_THUMB: u32 = 1 << 24;
+
+/// The registers and flags at one point in time.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct CpuState {
+    registers: [u32; 16],
+    cond_register: u32,
+    xpsr: u32,
+    exception_depth: u32,
+}
+
+#[wasm_bindgen]
+impl CpuState {
+    /// R0 to R15. The PC reads as the address of the next instruction to run.
+    pub fn register(&self, index: usize) -> u32 {
+        self.registers.get(index).copied().unwrap_or(0)
+    }
+
+    pub fn sp(&self) -> u32 {
+        self.registers[SP_INDEX as usize]
+    }
+
+    pub fn lr(&self) -> u32 {
+        self.registers[LR_INDEX as usize]
+    }
+
+    pub fn pc(&self) -> u32 {
+        self.registers[15]
+    }
+
+    /// The flags as `CondRegister` packs them: C, N, V and Z from the lowest bit up.
+    pub fn cond_register(&self) -> u32 {
+        self.cond_register
+    }
+
+    /// The flags in the N, Z, C and V bits of xPSR, with the Thumb bit set.
+    pub fn xpsr(&self) -> u32 {
+        self.xpsr
+    }
+
+    pub fn n(&self) -> bool {
+        self.xpsr & (1 << 31) != 0
+    }
+
+    pub fn z(&self) -> bool {
+        self.xpsr & (1 << 30) != 0
+    }
+
+    pub fn c(&self) -> bool {
+        self.xpsr & (1 << 29) != 0
+    }
+
+    pub fn v(&self) -> bool {
+        self.xpsr & (1 << 28) != 0
+    }
+
+    /// Number of exception handlers running, innermost interrupting the others.
+    pub fn exception_depth(&self) -> u32 {
+        self.exception_depth
+    }
+}
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum PeripheralId {
+    Dmac,
+    PortA,
+    PortB,
+    Sercom3,
+    Sercom4,
+    Sercom5,
+}
+
+impl PeripheralId {
+    fn address_range(self) -> (u32, u32) {
+        match self {
+            PeripheralId::Dmac => (DmacRegisters::DMAC_START_ADDR, DmacRegisters::DMAC_END_ADDR),
+            PeripheralId::PortA => (
+                PortRegisters::PORTA_START_ADDR,
+                PortRegisters::PORTA_END_ADDR,
+            ),
+            PeripheralId::PortB => (
+                PortRegisters::PORTB_START_ADDR,
+                PortRegisters::PORTB_END_ADDR,
+            ),
+            PeripheralId::Sercom3 => (
+                SercomRegisters::SERCOM3_START_ADDR,
+                SercomRegisters::SERCOM3_END_ADDR,
+            ),
+            PeripheralId::Sercom4 => (
+                SercomRegisters::SERCOM4_START_ADDR,
+                SercomRegisters::SERCOM4_END_ADDR,
+            ),
+            PeripheralId::Sercom5 => (
+                SercomRegisters::SERCOM5_START_ADDR,
+                SercomRegisters::SERCOM5_END_ADDR,
+            ),
+        }
+    }
+}
+
+/// TC5, the audio sample clock. Its registers aren't readable, so this is what the emulator
+/// keeps of them.
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct Tc5State {
+    sample_rate: u32,
+    period_ticks: u32,
+    ticks_to_overflow: i32,
+    evctrl: u16,
+    interrupt_pending: bool,
+}
+
+#[wasm_bindgen]
+impl Tc5State {
+    /// The rate set through CC0.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Emulated ticks between overflows.
+    pub fn period_ticks(&self) -> u32 {
+        self.period_ticks
+    }
+
+    pub fn ticks_to_overflow(&self) -> i32 {
+        self.ticks_to_overflow
+    }
+
+    pub fn evctrl(&self) -> u16 {
+        self.evctrl
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupt_pending
+    }
+}
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// The 32 KB of SRAM, mapped from 0x20000000.
+    pub fn sram_pointer(&self) -> *const u8 {
+        self.sram.as_ptr()
+    }
+
+    pub fn sram_size(&self) -> usize {
+        self.sram.len()
+    }
+
+    /// The 256 KB of flash, mapped from 0.
+    pub fn flash_pointer(&self) -> *const u8 {
+        self.flash.as_ptr()
+    }
+
+    pub fn flash_size(&self) -> usize {
+        self.flash.len()
+    }
+
+    /// Reads `length` bytes from `address` as the CPU would, peripherals included, but without
+    /// triggering watchpoints.
+    pub fn read_memory(&self, address: u32, length: u32) -> Vec<u8> {
+        (0..length)
+            .map(|i| self.debugger_read(address.wrapping_add(i), 1) as u8)
+            .collect()
+    }
+
+    pub fn cpu_state(&self) -> CpuState {
+        let mut registers = self.registers;
+        registers[15] = self.pc();
+        CpuState {
+            registers,
+            cond_register: self.cond_reg.to_word(),
+            xpsr: self.xpsr(),
+            exception_depth: self.exception_depth,
+        }
+    }
+
+    /// The base address of a peripheral's registers.
+    pub fn peripheral_address(&self, peripheral: PeripheralId) -> u32 {
+        peripheral.address_range().0
+    }
+
+    /// The words of a peripheral's registers, from its base address, as the CPU would read them.
+    pub fn peripheral_registers(&self, peripheral: PeripheralId) -> Vec<u32> {
+        let (start, end) = peripheral.address_range();
+        (start..=end)
+            .step_by(4)
+            .map(|address| self.debugger_read(address, 4))
+            .collect()
+    }
+
+    /// The words at CHCTRLA, CHCTRLB and CHINTENCLR, which holds the channel's interrupt and
+    /// status bytes, as they would read with CHID set to `channel`.
+    pub fn dmac_channel_registers(&self, channel: u8) -> Vec<u32> {
+        self.dmac_registers.channel_registers(channel).to_vec()
+    }
+
+    pub fn tc5_state(&self) -> Tc5State {
+        Tc5State {
+            sample_rate: self.sample_rate,
+            period_ticks: self.tc5_countdown as u32,
+            ticks_to_overflow: self.tc5_trigger as i32,
+            evctrl: self.tc5_evctrl,
+            interrupt_pending: self.tc5_interrupt,
+        }
+    }
+
+    /// Exception numbers of the interrupts waiting to be taken, in the order they will be.
+    pub fn pending_interrupts(&self) -> Vec<u32> {
+        [
+            (self.dmac_interrupt, 22),
+            (self.systick_trigger <= 0, 15),
+            (self.tc5_interrupt, 36),
+            (self.dac_interrupt, 41),
+            (self.evsys_interrupt, 24),
+            (self.rtc_interrupt, 19),
+        ]
+        .iter()
+        .filter(|&&(pending, _)| pending)
+        .map(|&(_, exception_number)| exception_number)
+        .collect()
+    }
+
+    /// Emulated ticks until the next SysTick interrupt.
+    pub fn systick_ticks_remaining(&self) -> u32 {
+        self.systick_trigger.clamp(0, GOAL_TICKS_PER_SECOND) as u32
+    }
+}
+
+impl Gamebuino {
+    /// The flags in xPSR layout. The exception number isn't kept, so IPSR reads as 0.
+    pub fn xpsr(&self) -> u32 {
+        let flags = &self.cond_reg;
+        (flags.n as u32) << 31
+            | (flags.z as u32) << 30
+            | (flags.c as u32) << 29
+            | (flags.v as u32) << 28
+            | XPSR_THUMB
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xpsr_packs_each_flag_into_its_bit() {
+        let mut gamebuino = Gamebuino::for_test(&[0xe7fe], &[]);
+        assert_eq!(gamebuino.cpu_state().xpsr(), XPSR_THUMB);
+        // CondRegister's C, N, V and Z bits, and the xPSR bit each lands in
+        for &(flag, bit) in &[(1, 29), (2, 31), (4, 28), (8, 30)] {
+            gamebuino.cond_reg.set_word(flag);
+            let state = gamebuino.cpu_state();
+            assert_eq!(state.cond_register(), flag);
+            assert_eq!(state.xpsr(), 1 << bit | XPSR_THUMB);
+            assert_eq!(
+                [state.n(), state.z(), state.c(), state.v()],
+                [bit == 31, bit == 30, bit == 29, bit == 28]
+            );
+        }
+        gamebuino.cond_reg.set_word(0b1111);
+        assert_eq!(gamebuino.cpu_state().xpsr(), 0xf0000000 | XPSR_THUMB);
+    }
+
+    #[test]
+    fn pending_interrupts_are_listed_in_the_order_they_are_taken() {
+        let mut gamebuino = Gamebuino::for_test(&[0xe7fe], &[0xe7fe]);
+        assert!(gamebuino.pending_interrupts().is_empty());
+        gamebuino.rtc_interrupt = true;
+        gamebuino.evsys_interrupt = true;
+        gamebuino.dac_interrupt = true;
+        gamebuino.tc5_interrupt = true;
+        gamebuino.systick_trigger = 0;
+        gamebuino.dmac_interrupt = true;
+        let mut pending = vec![22, 15, 36, 41, 24, 19];
+        assert_eq!(gamebuino.pending_interrupts(), pending);
+
+        // Each step takes the first, preempting the handler of the one before
+        while !pending.is_empty() {
+            gamebuino.step();
+            pending.remove(0);
+            assert_eq!(gamebuino.pending_interrupts(), pending);
+        }
+        assert_eq!(gamebuino.cpu_state().exception_depth(), 6);
+    }
+}
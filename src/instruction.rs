@@ -292,7 +292,7 @@ pub enum Instruction {
 pub fn parse_instruction(instruction: u16, following_instruction: u16) -> Instruction {
     if instruction & 0b1110000000000000 == 0b0000000000000000 {
         let rs = ((instruction & 0b0000000000111000) >> 3) as u8;
-        let rd = ((instruction & 0b0000000000000111) >> 0) as u8;
+        let rd = (instruction & 0b0000000000000111) as u8;
         if (instruction & 0b0001100000000000) != 0b0001100000000000 {
             let opcode = (instruction & 0b0001100000000000) >> 11;
             let offset = ((instruction & 0b0000011111000000) >> 6) as u8;
@@ -464,16 +464,8 @@ pub fn parse_instruction(instruction: u16, following_instruction: u16) -> Instru
                 offset: offset << 2,
                 rd,
             },
-            0b10 => Instruction::StrbImm {
-                rb,
-                offset: offset,
-                rd,
-            },
-            0b11 => Instruction::LdrbImm {
-                rb,
-                offset: offset,
-                rd,
-            },
+            0b10 => Instruction::StrbImm { rb, offset, rd },
+            0b11 => Instruction::LdrbImm { rb, offset, rd },
             _ => Instruction::NotImplemented,
         }
     } else if (instruction & 0b1111000000000000) == 0b1000000000000000 {
@@ -555,7 +547,7 @@ pub fn parse_instruction(instruction: u16, following_instruction: u16) -> Instru
     } else if (instruction & 0b1111011000000000) == 0b1011010000000000 {
         let l = (instruction & 0b0000100000000000) != 0;
         let r = (instruction & 0b0000000100000000) != 0;
-        let rlist = 0xff & instruction as u8;
+        let rlist = instruction as u8;
         if !l {
             Instruction::Push { rlist, lr: r }
         } else {
@@ -564,7 +556,7 @@ pub fn parse_instruction(instruction: u16, following_instruction: u16) -> Instru
     } else if (instruction & 0b1111000000000000) == 0b1100000000000000 {
         let l = (instruction & 0b0000100000000000) != 0;
         let rb = ((instruction & 0b0000011100000000) >> 8) as u8;
-        let rlist = 0xff & instruction as u8;
+        let rlist = instruction as u8;
         if l {
             Instruction::Ldmia { rb, rlist }
         } else {
@@ -577,7 +569,7 @@ pub fn parse_instruction(instruction: u16, following_instruction: u16) -> Instru
         if offset & 0b10000000 != 0 {
             offset |= !0b11111111;
         }
-        offset = offset << 1;
+        offset <<= 1;
         match condition {
             0b0000 => Instruction::Beq { offset },
             0b0001 => Instruction::Bne { offset },
@@ -601,7 +593,7 @@ pub fn parse_instruction(instruction: u16, following_instruction: u16) -> Instru
         if offset & 0b10000000000 != 0 {
             offset |= !0b11111111111;
         }
-        offset = offset << 1;
+        offset <<= 1;
         Instruction::B { offset }
     } else if (instruction & 0b1111100000000000) == 0b1111000000000000
         && (following_instruction & 0b1111100000000000) == 0b1111100000000000
@@ -610,7 +602,7 @@ pub fn parse_instruction(instruction: u16, following_instruction: u16) -> Instru
         if (offset1 & 0b0000010000000000) != 0 {
             offset1 |= !0b0000011111111111;
         }
-        offset1 = offset1 << 12;
+        offset1 <<= 12;
         let offset2 = ((following_instruction & 0b0000011111111111) << 1) as u32;
         Instruction::Bl {
             offset1,
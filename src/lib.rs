@@ -1,7 +1,22 @@
+mod audio;
+pub mod backtrace;
+pub mod cheat;
+mod coverage;
+pub mod debug;
+mod disassembler;
+pub mod elf;
+pub mod gdb;
+pub mod i2c;
+mod image;
 mod input_output;
+pub mod inspect;
 mod instruction;
+mod patch;
+pub mod profiler;
 mod register;
+pub mod trace;
 mod utils;
+mod video;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
@@ -12,15 +27,24 @@ static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 extern crate js_sys;
 extern crate web_sys;
 
-// macro_rules! log {
-//     ( $( $t:tt )* ) => {
-//         web_sys::console::log_1(&format!( $( $t )* ).into());
-//     }
-// }
-
+use audio::{Resampler, SampleRing, WavRecorder};
+use backtrace::StackFrame;
+use cheat::CheatEngine;
+use coverage::Coverage;
+use debug::{StopKind, StopReason, WatchHit, Watchpoint};
+use elf::DebugInfo;
+use i2c::{Ds3231, Eeprom24lc256, I2cBus, I2cDevice};
 use input_output::{Buttons, St7735};
 use instruction::Instruction;
-use register::{CondRegister, DmacRegisters, Peripheral, PortRegisters, SercomRegisters, TcRegisters};
+use profiler::Profiler;
+use register::{
+    AdcRegisters, CondRegister, DacRegisters, DmacRegisters, EvsysRegisters, Peripheral,
+    PortRegisters, RtcRegisters, SercomRegisters, TcRegisters,
+};
+use std::cell::Cell;
+use std::collections::{HashSet, VecDeque};
+use trace::Tracer;
+use video::{Recorder, VideoFormat};
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
@@ -37,19 +61,60 @@ pub struct Gamebuino {
     dmac_vector: u32,
     dmac_interrupt: bool,
     dmac_registers: DmacRegisters,
+    dmac_running: bool,
+    dmac_triggers: u64,
+    dmac_events: u8,
+    dmac_beat_end_tick: Option<u64>,
     tc5_vector: u32,
     tc5_trigger: isize,
     tc5_countdown: isize,
+    tc5_interrupt: bool,
+    tc5_evctrl: u16,
+    dac_vector: u32,
+    dac_interrupt: bool,
+    dac: DacRegisters,
+    adc: AdcRegisters,
+    evsys_vector: u32,
+    evsys_interrupt: bool,
+    evsys: EvsysRegisters,
+    event_depth: u8,
+    rtc_vector: u32,
+    rtc_interrupt: bool,
+    rtc_trigger: isize,
+    rtc: RtcRegisters,
     porta_registers: PortRegisters,
     portb_registers: PortRegisters,
+    sercom3: SercomRegisters,
     sercom4: SercomRegisters,
     sercom5: SercomRegisters,
-    sound_data: [u16; 4096],
-    pub sound_samples: usize,
+    sound: SampleRing<u16>,
+    sound_data: [u16; SOUND_BUFFER_SIZE],
+    resampler: Option<Resampler>,
+    output_sound_data: Vec<f32>,
+    wav_recorder: Option<WavRecorder>,
     pub sample_rate: u32,
     screen: St7735,
     buttons: Buttons,
-    // log: bool,
+    i2c: I2cBus,
+    recorder: Option<Recorder>,
+    recorded_frame_count: u32,
+    breakpoints: HashSet<u32>,
+    // Where and when the last run stopped for a breakpoint, so that resuming runs it
+    breakpoint_stop: Option<(u32, u64)>,
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>,
+    debug_info: Option<DebugInfo>,
+    tracer: Option<Box<Tracer>>,
+    profiler: Option<Box<Profiler>>,
+    call_stack: VecDeque<StackFrame>,
+    // Whether calls and returns are followed, for backtraces or profiling
+    backtraces: bool,
+    track_calls: bool,
+    cheats: CheatEngine,
+    coverage: Option<Box<Coverage>>,
+    exception_depth: u32,
+    // Whether the memory accessors need to report accesses, for watchpoints or tracing
+    observe_memory: bool,
 }
 
 const PC_INDEX: u8 = 15;
@@ -59,6 +124,17 @@ const GOAL_TICKS_PER_SECOND: isize = 20000000;
 const SYSTICK_COUNTDOWN: isize = GOAL_TICKS_PER_SECOND / 1000;
 const DEFAULT_SAMPLE_RATE: u32 = 44100;
 const TC5_DEFAULT_COUNTDOWN: isize = GOAL_TICKS_PER_SECOND / DEFAULT_SAMPLE_RATE as isize;
+const SOUND_BUFFER_SIZE: usize = 16384;
+// Conversions and transfers complete instantly, so a chain of events that feeds back into its own
+// generator would never end; on hardware it would be spread out over time
+const MAX_EVENT_DEPTH: u8 = 8;
+const DMA_MEMORY_BEAT_TICKS: u64 = 1;
+
+impl Default for Gamebuino {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 #[wasm_bindgen]
 impl Gamebuino {
@@ -82,26 +158,60 @@ impl Gamebuino {
             dmac_vector: 0,
             dmac_interrupt: false,
             dmac_registers: DmacRegisters::new(),
+            dmac_running: false,
+            dmac_triggers: 0,
+            dmac_events: 0,
+            dmac_beat_end_tick: None,
             tc5_vector: 0,
             tc5_trigger: TC5_DEFAULT_COUNTDOWN,
             porta_registers: PortRegisters::new(),
             portb_registers: PortRegisters::new(),
+            sercom3: SercomRegisters::new(),
             sercom4: SercomRegisters::new(),
             sercom5: SercomRegisters::new(),
-            sound_data: [0; 4096],
-            sound_samples: 0,
+            sound: SampleRing::new(SOUND_BUFFER_SIZE),
+            sound_data: [0; SOUND_BUFFER_SIZE],
+            resampler: None,
+            output_sound_data: Vec::new(),
+            wav_recorder: None,
             sample_rate: DEFAULT_SAMPLE_RATE,
             tc5_countdown: TC5_DEFAULT_COUNTDOWN,
+            tc5_interrupt: false,
+            tc5_evctrl: 0,
+            dac_vector: 0,
+            dac_interrupt: false,
+            dac: DacRegisters::new(),
+            adc: AdcRegisters::new(),
+            evsys_vector: 0,
+            evsys_interrupt: false,
+            evsys: EvsysRegisters::new(),
+            event_depth: 0,
+            rtc_vector: 0,
+            rtc_interrupt: false,
+            rtc_trigger: 0,
+            rtc: RtcRegisters::new(),
             screen: St7735::new(),
             buttons: Buttons::new(),
-            // log: false,
+            i2c: I2cBus::new(),
+            recorder: None,
+            recorded_frame_count: 0,
+            breakpoints: HashSet::new(),
+            breakpoint_stop: None,
+            watchpoints: Vec::new(),
+            watch_hit: Cell::new(None),
+            debug_info: None,
+            tracer: None,
+            profiler: None,
+            call_stack: VecDeque::new(),
+            backtraces: false,
+            track_calls: false,
+            cheats: CheatEngine::new(),
+            coverage: None,
+            exception_depth: 0,
+            observe_memory: false,
         }
     }
 
-    // pub fn enable_logging(&mut self) {
-    //     self.log = true;
-    // }
-
     pub fn get_register(&self, i: usize) -> u32 {
         self.registers[i]
     }
@@ -110,9 +220,88 @@ impl Gamebuino {
         self.tick_count as u32
     }
 
+    /// Disassembles `count` instructions from `address`, one line each with the address and
+    /// opcode.
+    pub fn disassemble(&self, address: u32, count: u32) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut address = address & !1;
+        for _ in 0..count {
+            let instruction = self.instruction_at(address);
+            let opcode = self.load_half_word(address);
+            let size = disassembler::instruction_size(instruction);
+            let encoding = if size == 4 {
+                format!(
+                    "{:04x} {:04x}",
+                    opcode,
+                    self.load_half_word(address.wrapping_add(2))
+                )
+            } else {
+                format!("{:04x}", opcode)
+            };
+            let text = disassembler::format_instruction(instruction, address, opcode, |a| {
+                self.load_word(a)
+            });
+            lines.push(format!("{:08x}:  {:<9}  {}", address, encoding, text));
+            address = address.wrapping_add(size);
+        }
+        lines
+    }
+
+    /// Attaches a 24LC256 EEPROM to the I2C bus, with A2..A0 set to `address_pins`.
+    pub fn attach_24lc256(&mut self, address_pins: u8) {
+        self.i2c.attach(Box::new(Eeprom24lc256::new(address_pins)));
+    }
+
+    /// Attaches a DS3231 RTC to the I2C bus, set to the given date and time.
+    #[allow(clippy::too_many_arguments)]
+    pub fn attach_ds3231(
+        &mut self,
+        year: u8,
+        month: u8,
+        date: u8,
+        day: u8,
+        hours: u8,
+        minutes: u8,
+        seconds: u8,
+    ) {
+        let mut rtc = Ds3231::new();
+        rtc.set_date_time(year, month, date, day, hours, minutes, seconds);
+        self.i2c.attach(Box::new(rtc));
+    }
+
+    /// Sets the SAMD21 RTC calendar, which otherwise powers up at 2000-01-01 00:00:00. Replays
+    /// should use a fixed date here rather than `sync_rtc_to_host_clock`, so that they run the
+    /// same every time.
+    pub fn set_rtc_date_time(
+        &mut self,
+        year: u16,
+        month: u8,
+        day: u8,
+        hours: u8,
+        minutes: u8,
+        seconds: u8,
+    ) {
+        let year = year.saturating_sub(RtcRegisters::REFERENCE_YEAR);
+        self.rtc.set_date_time(
+            year as u32,
+            month as u32,
+            day as u32,
+            hours as u32,
+            minutes as u32,
+            seconds as u32,
+        );
+    }
+
+    /// Sets the SAMD21 RTC calendar to the host's local time, or UTC in native builds.
+    pub fn sync_rtc_to_host_clock(&mut self) {
+        let (year, month, day, hours, minutes, seconds) = host_date_time();
+        self.set_rtc_date_time(year, month, day, hours, minutes, seconds);
+    }
+
     pub fn load_program(&mut self, contents: &[u8], offset: u32) {
         self.program_offset = offset;
         self.instructions.clear();
+        self.debug_info = None;
 
         for (i, val) in contents.iter().enumerate() {
             self.flash[i + offset as usize] = *val;
@@ -125,8 +314,8 @@ impl Gamebuino {
                 continue;
             }
 
-            let instruction = self.fetch_half_word(offset + i * 2);
-            let following_instruction = self.fetch_half_word(offset + (i + 1) * 2);
+            let instruction = self.load_half_word(offset + i * 2);
+            let following_instruction = self.load_half_word(offset + (i + 1) * 2);
             let parsed = instruction::parse_instruction(instruction, following_instruction);
             self.instructions.push(parsed);
 
@@ -148,107 +337,583 @@ impl Gamebuino {
         self.reset();
     }
 
+    /// Loads an ELF executable: its segments go to flash or SRAM at their load addresses, and
+    /// its symbols and line information are kept for `symbolize`. The program starts at the
+    /// lowest flash address, where the vector table is.
+    pub fn load_elf(&mut self, contents: &[u8]) -> Result<(), String> {
+        let image = elf::parse(contents)?;
+
+        let mut flash_start = self.flash.len() as u32;
+        let mut flash_end = 0;
+        for segment in &image.segments {
+            let end = segment.address as u64 + segment.data.len() as u64;
+            if end <= self.flash.len() as u64 {
+                flash_start = flash_start.min(segment.address);
+                flash_end = flash_end.max(end as u32);
+            } else if segment.address < 0x20000000 || end > 0x20000000 + self.sram.len() as u64 {
+                return Err(format!(
+                    "segment at 0x{:08x} is outside flash and SRAM",
+                    segment.address
+                ));
+            }
+        }
+        if flash_start >= flash_end {
+            return Err("no segments to load into flash".to_string());
+        }
+
+        let mut program = vec![0xff; (flash_end - flash_start) as usize];
+        for segment in image.segments.iter().filter(|s| s.address < 0x20000000) {
+            let start = (segment.address - flash_start) as usize;
+            program[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        self.load_program(&program, flash_start);
+
+        for segment in image.segments.iter().filter(|s| s.address >= 0x20000000) {
+            let start = (segment.address - 0x20000000) as usize;
+            self.sram[start..start + segment.data.len()].copy_from_slice(&segment.data);
+        }
+        self.debug_info = Some(image.debug_info);
+        Ok(())
+    }
+
+    /// Describes `pc` as `function+0xoffset (file:line)`, with whichever parts the loaded ELF
+    /// has information for. Empty if there are none.
+    pub fn symbolize(&self, pc: u32) -> String {
+        let debug_info = match self.debug_info.as_ref() {
+            Some(debug_info) => debug_info,
+            None => return String::new(),
+        };
+        let function = debug_info
+            .function_at(pc)
+            .map(|(name, offset)| format!("{}+0x{:x}", name, offset));
+        let line = debug_info
+            .line_at(pc)
+            .map(|(file, line)| format!("{}:{}", file, line));
+        match (function, line) {
+            (Some(function), Some(line)) => format!("{} ({})", function, line),
+            (Some(function), None) => function,
+            (None, Some(line)) => line,
+            (None, None) => String::new(),
+        }
+    }
+
     fn reset(&mut self) {
-        self.set_register(SP_INDEX, self.fetch_word(0x0000 + self.program_offset));
+        self.set_register(SP_INDEX, self.load_word(self.program_offset));
         self.set_register(LR_INDEX, 0xffffffff);
         self.set_register(PC_INDEX, self.read_vector_table(1));
         self.increment_pc();
         self.systick_vector = self.read_vector_table(15);
         self.dmac_vector = self.read_vector_table(22);
         self.tc5_vector = self.read_vector_table(36);
+        self.dac_vector = self.read_vector_table(41);
+        self.evsys_vector = self.read_vector_table(24);
+        self.rtc_vector = self.read_vector_table(19);
+        if self.track_calls {
+            self.reset_call_stack();
+        }
     }
 
     fn read_vector_table(&self, exception_number: u32) -> u32 {
         let pointer_size = 4;
-        self.fetch_word(exception_number * pointer_size + self.program_offset) & !1
+        self.load_word(exception_number * pointer_size + self.program_offset) & !1
+    }
+
+    /// The instruction at `address` as `step` would run it: from the decoded program, or
+    /// decoded on the spot outside of it.
+    fn instruction_at(&self, address: u32) -> Instruction {
+        let index = address.wrapping_sub(self.program_offset) >> 1;
+        match self.instructions.get(index as usize) {
+            Some(&instruction) => instruction,
+            None => instruction::parse_instruction(
+                self.load_half_word(address),
+                self.load_half_word(address.wrapping_add(2)),
+            ),
+        }
     }
 
     pub fn step(&mut self) {
+        let addr = self.read_register(PC_INDEX) - 2;
+        let start_tick = self.tick_count;
+        let instruction = *self
+            .instructions
+            .get(((addr - self.program_offset) >> 1) as usize)
+            .unwrap();
+        if self.tracer.is_some() {
+            self.execute_traced(addr, instruction);
+        } else {
+            self.increment_pc();
+            self.execute_instruction(instruction);
+        }
+        if self.coverage.is_some() {
+            self.record_coverage(addr, instruction);
+        }
+
+        if self.recorder.is_some() && self.screen.frame_count != self.recorded_frame_count {
+            self.recorded_frame_count = self.screen.frame_count;
+            if let Some(recorder) = self.recorder.as_mut() {
+                recorder.add_frame(self.screen.frame(), self.tick_count);
+            }
+        }
+
+        if self.cheats.is_due(self.screen.frame_count) {
+            self.apply_freezes();
+        }
+
+        // Peripherals and interrupts are handled after the instruction rather than before the
+        // next one, so that between steps the PC is the instruction that will actually run next
+        if self.tc5_trigger <= 0 {
+            self.tc5_trigger += self.tc5_countdown;
+            self.tc5_overflow();
+        }
+
+        if self.rtc_trigger <= 0 {
+            self.rtc_trigger += self.rtc.period_ticks(GOAL_TICKS_PER_SECOND as u64) as isize;
+            if self.rtc.is_running() {
+                self.rtc_tick();
+            }
+        }
+
+        if self
+            .dmac_beat_end_tick
+            .is_some_and(|tick| tick <= self.tick_count)
+        {
+            self.run_dmac();
+        }
+
         if self.dmac_interrupt {
             self.dmac_interrupt = false;
             self.handle_interrupt(self.dmac_vector);
         } else if self.systick_trigger <= 0 {
             self.systick_trigger = SYSTICK_COUNTDOWN;
             self.handle_interrupt(self.systick_vector);
-        } else if self.tc5_trigger <= 0 {
-            self.tc5_trigger = self.tc5_countdown;
+        } else if self.tc5_interrupt {
+            self.tc5_interrupt = false;
             self.handle_interrupt(self.tc5_vector);
+        } else if self.dac_interrupt {
+            self.dac_interrupt = false;
+            self.handle_interrupt(self.dac_vector);
+        } else if self.evsys_interrupt {
+            self.evsys_interrupt = false;
+            self.handle_interrupt(self.evsys_vector);
+        } else if self.rtc_interrupt {
+            self.rtc_interrupt = false;
+            self.handle_interrupt(self.rtc_vector);
         }
 
-        let mut addr = self.read_register(PC_INDEX) - 2;
+        let mut next_addr = self.read_register(PC_INDEX) - 2;
 
-        while addr == 0xfffffff8 {
-            self.pop_stack(0);
-            self.pop_stack(1);
-            self.pop_stack(2);
-            self.pop_stack(3);
-            self.pop_stack(12);
-            self.pop_stack(LR_INDEX);
-            self.pop_stack(PC_INDEX);
-            let cnvz = self.fetch_word(self.read_register(SP_INDEX));
-            self.cond_reg.from_word(cnvz);
+        while next_addr == 0xfffffff8 {
+            for register in [0, 1, 2, 3, 12, LR_INDEX, PC_INDEX] {
+                self.pop_exception_stack(register);
+            }
+            let cnvz = self.load_word(self.read_register(SP_INDEX));
+            self.cond_reg.set_word(cnvz);
             self.set_register(SP_INDEX, self.read_register(SP_INDEX) + 4);
-            addr = self.read_register(PC_INDEX) - 2;
+            self.exception_depth = self.exception_depth.saturating_sub(1);
+            if self.track_calls {
+                self.note_exception_return();
+            }
+            next_addr = self.read_register(PC_INDEX) - 2;
         }
 
-        let instruction = *self
-            .instructions
-            .get(((addr - self.program_offset) >> 1) as usize)
-            .unwrap();
-        // if self.log {
-        //     log!(
-        //         "addr: {:04x}, instr: {:016b}, {:?}",
-        //         addr,
-        //         self.fetch_half_word(addr),
-        //         instruction
-        //     );
-        // }
-        self.increment_pc();
-        self.execute_instruction(instruction);
-        // if self.log {
-        //     log!("flags: {:?}\nregs: {:?}", self.cond_reg, self.registers);
-        // }
+        if self.profiler.is_some() {
+            self.profile_step(addr, start_tick);
+        }
+    }
+
+    /// TC5 is the audio sample clock. The Gamebuino library writes DAC.DATA from its overflow
+    /// interrupt, so unless the DAC converts on events the output is sampled on overflow, not
+    /// whenever the CPU happens to write.
+    fn tc5_overflow(&mut self) {
+        self.tc5_interrupt = true;
+
+        if self.tc5_evctrl & TcRegisters::EVCTRL_OVFEO != 0 {
+            self.generate_event(EvsysRegisters::GEN_TC5_OVF);
+        }
+        self.dma_trigger(DmacRegisters::TRIGGER_TC5_OVF);
+
+        if !self.dac.is_event_driven() {
+            self.sample_dac();
+        }
+    }
+
+    fn rtc_tick(&mut self) {
+        let (flags, interrupt) = self.rtc.tick_second();
+        if interrupt {
+            self.rtc_interrupt = true;
+        }
+        let (alarm_event, overflow_event) = self.rtc.events(flags);
+        if alarm_event {
+            self.generate_event(EvsysRegisters::GEN_RTC_CMP0);
+        }
+        if overflow_event {
+            self.generate_event(EvsysRegisters::GEN_RTC_OVF);
+        }
+    }
+
+    /// Sends an event from `generator` to the users the EVSYS routes it to.
+    fn generate_event(&mut self, generator: u8) {
+        let routed = self.evsys.route(generator);
+        self.deliver_event(routed);
+    }
+
+    fn deliver_event(&mut self, (users, interrupt): (u32, bool)) {
+        if interrupt {
+            self.evsys_interrupt = true;
+        }
+        if users == 0 || self.event_depth >= MAX_EVENT_DEPTH {
+            return;
+        }
+        self.event_depth += 1;
+        for user in 0..EvsysRegisters::USER_COUNT as u8 {
+            if users & (1 << user) != 0 {
+                self.handle_event(user);
+            }
+        }
+        self.event_depth -= 1;
+    }
+
+    fn handle_event(&mut self, user: u8) {
+        match user {
+            EvsysRegisters::USER_DMAC_CH0..=EvsysRegisters::USER_DMAC_CH3 => {
+                self.dmac_events |= 1 << (user - EvsysRegisters::USER_DMAC_CH0);
+                self.run_dmac();
+            }
+            EvsysRegisters::USER_TC5 => {
+                // TC5 is only modelled as a countdown, so retrigger is the only event action
+                let evact = self.tc5_evctrl & TcRegisters::EVCTRL_EVACT_MASK;
+                if self.tc5_evctrl & TcRegisters::EVCTRL_TCEI != 0
+                    && evact == TcRegisters::EVACT_RETRIGGER
+                {
+                    self.tc5_trigger = self.tc5_countdown;
+                }
+            }
+            EvsysRegisters::USER_ADC_START => {
+                let converted = self.adc.handle_start_event();
+                if converted {
+                    self.adc_result_ready();
+                }
+            }
+            EvsysRegisters::USER_DAC_START if self.dac.is_event_driven() => {
+                let (interrupt, empty_event) = self.dac.start_conversion();
+                if interrupt {
+                    self.dac_interrupt = true;
+                }
+                self.sample_dac();
+                if empty_event {
+                    self.generate_event(EvsysRegisters::GEN_DAC_EMPTY);
+                }
+                // DATABUF is free again
+                self.run_dmac();
+            }
+            _ => {}
+        }
+    }
+
+    fn adc_result_ready(&mut self) {
+        if self.adc.outputs_result_event() {
+            self.generate_event(EvsysRegisters::GEN_ADC_RESRDY);
+        }
+        self.dma_trigger(DmacRegisters::TRIGGER_ADC_RESRDY);
+    }
+
+    /// Whether the peripheral behind DMA trigger `source` requests a transfer for as long as it
+    /// has room, as opposed to a single trigger per occurrence.
+    fn dma_request_pending(&self, source: u8) -> bool {
+        let sercom_ready = |sercom: &SercomRegisters, flag: u8| sercom.intflag & flag != 0;
+        match source {
+            DmacRegisters::TRIGGER_SERCOM3_RX => {
+                sercom_ready(&self.sercom3, SercomRegisters::INTFLAG_RXC)
+            }
+            DmacRegisters::TRIGGER_SERCOM3_TX => {
+                sercom_ready(&self.sercom3, SercomRegisters::INTFLAG_DRE)
+            }
+            DmacRegisters::TRIGGER_SERCOM4_RX => {
+                sercom_ready(&self.sercom4, SercomRegisters::INTFLAG_RXC)
+            }
+            DmacRegisters::TRIGGER_SERCOM4_TX => {
+                sercom_ready(&self.sercom4, SercomRegisters::INTFLAG_DRE)
+            }
+            DmacRegisters::TRIGGER_SERCOM5_RX => {
+                sercom_ready(&self.sercom5, SercomRegisters::INTFLAG_RXC)
+            }
+            DmacRegisters::TRIGGER_SERCOM5_TX => {
+                sercom_ready(&self.sercom5, SercomRegisters::INTFLAG_DRE)
+            }
+            DmacRegisters::TRIGGER_DAC_EMPTY => self.dac.is_buffer_empty(),
+            _ => false,
+        }
+    }
+
+    /// How long a DMA beat takes: the time a SERCOM needs to shift a byte at its baud rate, or a
+    /// memory access.
+    fn dma_beat_ticks(&self, source: u8) -> u64 {
+        let sercom = match source {
+            DmacRegisters::TRIGGER_SERCOM3_RX | DmacRegisters::TRIGGER_SERCOM3_TX => &self.sercom3,
+            DmacRegisters::TRIGGER_SERCOM4_RX | DmacRegisters::TRIGGER_SERCOM4_TX => &self.sercom4,
+            DmacRegisters::TRIGGER_SERCOM5_RX | DmacRegisters::TRIGGER_SERCOM5_TX => &self.sercom5,
+            _ => return DMA_MEMORY_BEAT_TICKS,
+        };
+        sercom.spi_byte_ticks(GOAL_TICKS_PER_SECOND as u64)
+    }
+
+    fn dma_trigger(&mut self, source: u8) {
+        if self.dmac_registers.is_active() {
+            self.dmac_triggers |= 1 << source;
+            self.run_dmac();
+        }
+    }
+
+    /// Runs the DMAC on queued triggers and events, and carries out the beats due by now.
+    /// Triggers raised while it is already running, e.g. by a beat written to a peripheral, are
+    /// queued and handled once it has written its state back.
+    fn run_dmac(&mut self) {
+        if self.dmac_running {
+            return;
+        }
+        self.dmac_running = true;
+        loop {
+            let triggers = std::mem::take(&mut self.dmac_triggers);
+            let events = std::mem::take(&mut self.dmac_events);
+            let mut copied = self.dmac_registers;
+            self.dmac_beat_end_tick = copied.run(triggers, events, self);
+            self.dmac_registers = copied;
+            if self.dmac_triggers == 0 && self.dmac_events == 0 {
+                break;
+            }
+        }
+        self.dmac_running = false;
+    }
+
+    fn sample_dac(&mut self) {
+        if self.dac.is_enabled() {
+            let sample = self.dac.output;
+            self.sound.push(sample);
+            if let Some(resampler) = self.resampler.as_mut() {
+                resampler.push(sample, self.sample_rate);
+            }
+            if let Some(recorder) = self.wav_recorder.as_mut() {
+                recorder.push(sample, self.sample_rate);
+            }
+        }
     }
 
     fn handle_interrupt(&mut self, vector_address: u32) {
-        self.push_stack(self.cond_reg.to_word());
-        self.push_stack(self.read_register(PC_INDEX));
-        self.push_stack(self.read_register(LR_INDEX));
-        self.push_stack(self.read_register(12));
-        self.push_stack(self.read_register(3));
-        self.push_stack(self.read_register(2));
-        self.push_stack(self.read_register(1));
-        self.push_stack(self.read_register(0));
+        let interrupted_pc = self.pc();
+        self.push_exception_stack(self.cond_reg.to_word());
+        for register in [PC_INDEX, LR_INDEX, 12, 3, 2, 1, 0] {
+            self.push_exception_stack(self.read_register(register));
+        }
         self.set_register(PC_INDEX, vector_address);
         self.set_register(LR_INDEX, 0xfffffff9);
         self.increment_pc();
+        self.exception_depth += 1;
+        if self.track_calls {
+            self.note_exception(interrupted_pc);
+        }
+    }
+
+    /// Runs for `steps` ticks, or until a breakpoint or watchpoint is hit.
+    pub fn run(&mut self, steps: usize, button_data: u8) -> StopReason {
+        self.buttons.button_data = button_data;
+
+        // Hits from accesses made between runs are not the program's
+        self.clear_watch_hit();
+        if let Some(stop) = self.check_start() {
+            return stop;
+        }
+        let start = self.tick_count;
+        let goal = start + steps as u64;
+        while self.tick_count < goal {
+            self.step();
+            if let Some(stop) = self.check_stop() {
+                return stop.with_ticks(self.tick_count - start);
+            }
+        }
+        StopReason::new(StopKind::TicksExhausted, self.pc()).with_ticks(self.tick_count - start)
     }
 
-    pub fn run(&mut self, steps: usize, button_data: u8) {
+    /// Runs until the game has pushed a full frame to the screen, `max_ticks` have elapsed, or
+    /// a breakpoint or watchpoint is hit.
+    pub fn run_until_frame(&mut self, max_ticks: u32, button_data: u8) -> StopReason {
         self.buttons.button_data = button_data;
-        self.sound_samples = 0;
 
-        let goal = self.tick_count + steps as u64;
+        self.clear_watch_hit();
+        if let Some(stop) = self.check_start() {
+            return stop;
+        }
+        let start = self.tick_count;
+        let goal = start + max_ticks as u64;
+        let frame_count = self.screen.frame_count;
         while self.tick_count < goal {
             self.step();
+            if let Some(stop) = self.check_stop() {
+                return stop.with_ticks(self.tick_count - start);
+            }
+            if self.screen.frame_count != frame_count {
+                return StopReason::new(StopKind::FrameCompleted, self.pc())
+                    .with_ticks(self.tick_count - start);
+            }
         }
+        StopReason::new(StopKind::TicksExhausted, self.pc()).with_ticks(self.tick_count - start)
     }
 
     pub fn image_pointer(&self) -> *const u32 {
         self.screen.image_pointer()
     }
 
+    /// Presents only complete frames through `image_pointer` instead of the panel mid-update.
+    pub fn set_double_buffered(&mut self, enabled: bool) {
+        self.screen.double_buffered = enabled;
+    }
+
+    /// Number of complete frames the game has pushed to the screen.
+    pub fn frame_count(&self) -> u32 {
+        self.screen.frame_count
+    }
+
+    /// Starts recording completed frames as an animated GIF.
+    pub fn start_recording_gif(&mut self) {
+        self.start_recording(VideoFormat::Gif);
+    }
+
+    /// Starts recording completed frames as raw RGB565, 160 × 128 pixels each at 50 frames per
+    /// second, with no header.
+    pub fn start_recording_rgb565(&mut self) {
+        self.start_recording(VideoFormat::Rgb565);
+    }
+
+    /// Stops recording and returns the encoded video, or nothing if no recording was running.
+    pub fn stop_recording(&mut self) -> Vec<u8> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(self.tick_count),
+            None => Vec::new(),
+        }
+    }
+
+    fn start_recording(&mut self, format: VideoFormat) {
+        let mut recorder = Recorder::new(
+            format,
+            St7735::WIDTH,
+            St7735::HEIGHT,
+            self.tick_count,
+            GOAL_TICKS_PER_SECOND as u64,
+        );
+        // Start from the frame currently on screen
+        recorder.add_frame(self.screen.frame(), self.tick_count);
+        self.recorded_frame_count = self.screen.frame_count;
+        self.recorder = Some(recorder);
+    }
+
+    /// Encodes the screen as a PNG, scaled up by an integer factor.
+    pub fn screenshot_png(&self, scale: u32) -> Vec<u8> {
+        image::encode_png(
+            self.screen.image(),
+            St7735::WIDTH,
+            St7735::HEIGHT,
+            scale as usize,
+        )
+    }
+
+    /// Encodes the screen as a 24-bit BMP, scaled up by an integer factor.
+    pub fn screenshot_bmp(&self, scale: u32) -> Vec<u8> {
+        image::encode_bmp(
+            self.screen.image(),
+            St7735::WIDTH,
+            St7735::HEIGHT,
+            scale as usize,
+        )
+    }
+
+    /// Moves up to `max_samples` buffered DAC samples to `sound_data_pointer`, oldest first.
+    /// Returns the number of samples moved.
+    pub fn drain_sound(&mut self, max_samples: usize) -> usize {
+        let count = max_samples.min(self.sound_data.len());
+        self.sound.drain_into(&mut self.sound_data[..count])
+    }
+
     pub fn sound_data_pointer(&self) -> *const u16 {
         self.sound_data.as_ptr()
     }
 
+    /// Number of DAC samples waiting to be drained.
+    pub fn sound_samples(&self) -> usize {
+        self.sound.len()
+    }
+
+    /// Number of DAC samples dropped because the buffer was full.
+    pub fn sound_overflows(&self) -> u32 {
+        self.sound.overflows
+    }
+
+    /// Resamples audio to a fixed rate for `drain_output_sound`, independently of the rate the
+    /// game configures. A rate of 0 turns resampling off.
+    pub fn set_output_sample_rate(&mut self, sample_rate: u32) {
+        if sample_rate == 0 {
+            self.resampler = None;
+            self.output_sound_data = Vec::new();
+        } else if self.resampler.as_ref().map(|r| r.output_rate()) != Some(sample_rate) {
+            self.resampler = Some(Resampler::new(sample_rate, SOUND_BUFFER_SIZE));
+            self.output_sound_data = vec![0.0; SOUND_BUFFER_SIZE];
+        }
+    }
+
+    /// Moves up to `max_samples` resampled samples, between -1 and 1, to
+    /// `output_sound_pointer`. Returns the number of samples moved.
+    pub fn drain_output_sound(&mut self, max_samples: usize) -> usize {
+        match self.resampler.as_mut() {
+            Some(resampler) => {
+                let count = max_samples.min(self.output_sound_data.len());
+                resampler
+                    .output
+                    .drain_into(&mut self.output_sound_data[..count])
+            }
+            None => 0,
+        }
+    }
+
+    pub fn output_sound_pointer(&self) -> *const f32 {
+        self.output_sound_data.as_ptr()
+    }
+
+    /// Number of resampled samples waiting to be drained.
+    pub fn output_sound_samples(&self) -> usize {
+        self.resampler.as_ref().map_or(0, |r| r.output.len())
+    }
+
+    /// Starts recording DAC output for `stop_wav_recording`.
+    pub fn start_wav_recording(&mut self) {
+        self.wav_recorder = Some(WavRecorder::new());
+    }
+
+    /// Stops recording and returns the DAC output as a 16-bit PCM WAV file, or nothing if no
+    /// recording was running.
+    pub fn stop_wav_recording(&mut self) -> Vec<u8> {
+        match self.wav_recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Vec::new(),
+        }
+    }
+
     fn increment_pc(&mut self) {
         self.tick_count += 1;
         self.systick_trigger -= 1;
         self.tc5_trigger -= 1;
+        self.rtc_trigger -= 1;
         self.registers[PC_INDEX as usize] += 2;
     }
 
+    fn update_memory_observation(&mut self) {
+        self.observe_memory = !self.watchpoints.is_empty() || self.tracer.is_some();
+    }
+
+    fn observe_access(&self, address: u32, size: u32, write: bool) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, size, write);
+        }
+        self.trace_access(address, size, write);
+    }
+
     fn push_stack(&mut self, value: u32) {
         self.set_register(SP_INDEX, self.read_register(SP_INDEX) - 4);
         self.write_word(self.read_register(SP_INDEX), value);
@@ -259,7 +924,28 @@ impl Gamebuino {
         self.set_register(SP_INDEX, self.read_register(SP_INDEX) + 4);
     }
 
+    // Stacking on exception entry and return isn't an access by an instruction, so it doesn't
+    // trigger watchpoints or get traced
+    fn push_exception_stack(&mut self, value: u32) {
+        self.set_register(SP_INDEX, self.read_register(SP_INDEX) - 4);
+        self.store_word(self.read_register(SP_INDEX), value);
+    }
+
+    fn pop_exception_stack(&mut self, register: u8) {
+        self.set_register(register, self.load_word(self.read_register(SP_INDEX)));
+        self.set_register(SP_INDEX, self.read_register(SP_INDEX) + 4);
+    }
+
     fn fetch_word(&self, address: u32) -> u32 {
+        if self.observe_memory {
+            self.observe_access(address, 4, false);
+        }
+        self.load_word(address)
+    }
+
+    /// Reads memory without checking watchpoints or tracing the access, as `fetch_word` and
+    /// the other accessors do for the CPU.
+    fn load_word(&self, address: u32) -> u32 {
         let addr = address as usize;
         if addr < 0x20000000 {
             if addr >= 0x40000 {
@@ -282,12 +968,27 @@ impl Gamebuino {
                 DmacRegisters::DMAC_START_ADDR..=DmacRegisters::DMAC_END_ADDR => self
                     .dmac_registers
                     .handle_read_word(addr - DmacRegisters::DMAC_START_ADDR),
+                DacRegisters::DAC_START_ADDR..=DacRegisters::DAC_END_ADDR => self
+                    .dac
+                    .handle_read_word(addr - DacRegisters::DAC_START_ADDR),
+                AdcRegisters::ADC_START_ADDR..=AdcRegisters::ADC_END_ADDR => self
+                    .adc
+                    .handle_read_word(addr - AdcRegisters::ADC_START_ADDR),
+                EvsysRegisters::EVSYS_START_ADDR..=EvsysRegisters::EVSYS_END_ADDR => self
+                    .evsys
+                    .handle_read_word(addr - EvsysRegisters::EVSYS_START_ADDR),
+                RtcRegisters::RTC_START_ADDR..=RtcRegisters::RTC_END_ADDR => self
+                    .rtc
+                    .handle_read_word(addr - RtcRegisters::RTC_START_ADDR),
                 PortRegisters::PORTA_START_ADDR..=PortRegisters::PORTA_END_ADDR => self
                     .porta_registers
                     .handle_read_word(addr - PortRegisters::PORTA_START_ADDR),
                 PortRegisters::PORTB_START_ADDR..=PortRegisters::PORTB_END_ADDR => self
                     .portb_registers
                     .handle_read_word(addr - PortRegisters::PORTB_START_ADDR),
+                SercomRegisters::SERCOM3_START_ADDR..=SercomRegisters::SERCOM3_END_ADDR => self
+                    .sercom3
+                    .handle_read_word(addr - SercomRegisters::SERCOM3_START_ADDR),
                 SercomRegisters::SERCOM4_START_ADDR..=SercomRegisters::SERCOM4_END_ADDR => self
                     .sercom4
                     .handle_read_word(addr - SercomRegisters::SERCOM4_START_ADDR),
@@ -302,6 +1003,13 @@ impl Gamebuino {
     }
 
     fn fetch_half_word(&self, address: u32) -> u16 {
+        if self.observe_memory {
+            self.observe_access(address, 2, false);
+        }
+        self.load_half_word(address)
+    }
+
+    fn load_half_word(&self, address: u32) -> u16 {
         let addr = address as usize;
         if addr < 0x20000000 {
             if addr >= 0x40000 {
@@ -312,9 +1020,32 @@ impl Gamebuino {
             let addr = (addr - 0x20000000) % 0x8000;
             self.sram[addr] as u16 | (self.sram[addr + 1] as u16) << 8
         } else if addr < 0x60000000 {
+            let addr = addr as u32;
             match addr {
-                // hack for ADC RESULT
-                0x4200401A => (js_sys::Math::random() * (0xffff as f64)).floor() as u16,
+                DmacRegisters::DMAC_START_ADDR..=DmacRegisters::DMAC_END_ADDR => self
+                    .dmac_registers
+                    .handle_read_word(addr - DmacRegisters::DMAC_START_ADDR)
+                    as u16,
+                AdcRegisters::ADC_START_ADDR..=AdcRegisters::ADC_END_ADDR => self
+                    .adc
+                    .handle_read_word(addr - AdcRegisters::ADC_START_ADDR)
+                    as u16,
+                EvsysRegisters::EVSYS_START_ADDR..=EvsysRegisters::EVSYS_END_ADDR => self
+                    .evsys
+                    .handle_read_word(addr - EvsysRegisters::EVSYS_START_ADDR)
+                    as u16,
+                RtcRegisters::RTC_START_ADDR..=RtcRegisters::RTC_END_ADDR => self
+                    .rtc
+                    .handle_read_word(addr - RtcRegisters::RTC_START_ADDR)
+                    as u16,
+                DacRegisters::DAC_START_ADDR..=DacRegisters::DAC_END_ADDR => self
+                    .dac
+                    .handle_read_word(addr - DacRegisters::DAC_START_ADDR)
+                    as u16,
+                SercomRegisters::SERCOM3_START_ADDR..=SercomRegisters::SERCOM3_END_ADDR => self
+                    .sercom3
+                    .handle_read_word(addr - SercomRegisters::SERCOM3_START_ADDR)
+                    as u16,
                 _ => 0,
             }
         } else {
@@ -323,6 +1054,13 @@ impl Gamebuino {
     }
 
     fn fetch_byte(&self, address: u32) -> u8 {
+        if self.observe_memory {
+            self.observe_access(address, 1, false);
+        }
+        self.load_byte(address)
+    }
+
+    fn load_byte(&self, address: u32) -> u8 {
         let addr = address as usize;
         if addr < 0x20000000 {
             if addr >= 0x40000 {
@@ -335,10 +1073,24 @@ impl Gamebuino {
         } else if addr < 0x60000000 {
             let addr = addr as u32;
             match addr {
-                0x42004018 => 1, // hack for ADC INTFLAG RESRDY
+                AdcRegisters::ADC_START_ADDR..=AdcRegisters::ADC_END_ADDR => self
+                    .adc
+                    .handle_read_byte(addr - AdcRegisters::ADC_START_ADDR),
+                EvsysRegisters::EVSYS_START_ADDR..=EvsysRegisters::EVSYS_END_ADDR => self
+                    .evsys
+                    .handle_read_byte(addr - EvsysRegisters::EVSYS_START_ADDR),
+                RtcRegisters::RTC_START_ADDR..=RtcRegisters::RTC_END_ADDR => self
+                    .rtc
+                    .handle_read_byte(addr - RtcRegisters::RTC_START_ADDR),
                 DmacRegisters::DMAC_START_ADDR..=DmacRegisters::DMAC_END_ADDR => self
                     .dmac_registers
                     .handle_read_byte(addr - DmacRegisters::DMAC_START_ADDR),
+                DacRegisters::DAC_START_ADDR..=DacRegisters::DAC_END_ADDR => self
+                    .dac
+                    .handle_read_byte(addr - DacRegisters::DAC_START_ADDR),
+                SercomRegisters::SERCOM3_START_ADDR..=SercomRegisters::SERCOM3_END_ADDR => self
+                    .sercom3
+                    .handle_read_byte(addr - SercomRegisters::SERCOM3_START_ADDR),
                 SercomRegisters::SERCOM4_START_ADDR..=SercomRegisters::SERCOM4_END_ADDR => self
                     .sercom4
                     .handle_read_byte(addr - SercomRegisters::SERCOM4_START_ADDR),
@@ -353,6 +1105,14 @@ impl Gamebuino {
     }
 
     fn write_word(&mut self, address: u32, value: u32) {
+        if self.observe_memory {
+            self.observe_access(address, 4, true);
+        }
+        self.store_word(address, value)
+    }
+
+    /// Writes memory without checking watchpoints or tracing the access.
+    fn store_word(&mut self, address: u32, value: u32) {
         let addr = address as usize;
         if addr < 0x20000000 {
             // do nothing; not supporting writing to flash
@@ -366,9 +1126,38 @@ impl Gamebuino {
             let addr = addr as u32;
             match addr {
                 DmacRegisters::DMAC_START_ADDR..=DmacRegisters::DMAC_END_ADDR => {
+                    self.dmac_running = true;
                     let mut copied = self.dmac_registers;
                     copied.handle_write_word(addr - DmacRegisters::DMAC_START_ADDR, value, self);
                     self.dmac_registers = copied;
+                    self.dmac_running = false;
+                    self.run_dmac();
+                }
+                DacRegisters::DAC_START_ADDR..=DacRegisters::DAC_END_ADDR => {
+                    let mut copied = self.dac;
+                    copied.handle_write_word(addr - DacRegisters::DAC_START_ADDR, value, self);
+                    self.dac = copied;
+                }
+                AdcRegisters::ADC_START_ADDR..=AdcRegisters::ADC_END_ADDR => {
+                    let mut copied = self.adc;
+                    copied.handle_write_word(addr - AdcRegisters::ADC_START_ADDR, value, self);
+                    self.adc = copied;
+                    if self.adc.take_software_conversion() {
+                        self.adc_result_ready();
+                    }
+                }
+                EvsysRegisters::EVSYS_START_ADDR..=EvsysRegisters::EVSYS_END_ADDR => {
+                    let mut copied = self.evsys;
+                    copied.handle_write_word(addr - EvsysRegisters::EVSYS_START_ADDR, value, self);
+                    self.evsys = copied;
+                    if let Some(routed) = self.evsys.take_software_event() {
+                        self.deliver_event(routed);
+                    }
+                }
+                RtcRegisters::RTC_START_ADDR..=RtcRegisters::RTC_END_ADDR => {
+                    let mut copied = self.rtc;
+                    copied.handle_write_word(addr - RtcRegisters::RTC_START_ADDR, value, self);
+                    self.rtc = copied;
                 }
                 PortRegisters::PORTA_START_ADDR..=PortRegisters::PORTA_END_ADDR => {
                     let mut copied = self.porta_registers;
@@ -382,6 +1171,16 @@ impl Gamebuino {
                     self.portb_registers = copied;
                     // TODO port listeners
                 }
+                SercomRegisters::SERCOM3_START_ADDR..=SercomRegisters::SERCOM3_END_ADDR => {
+                    let mut copied = self.sercom3;
+                    copied.handle_write_word(
+                        addr - SercomRegisters::SERCOM3_START_ADDR,
+                        value,
+                        self,
+                    );
+                    self.sercom3 = copied;
+                    self.i2c.update(&mut self.sercom3, self.tick_count);
+                }
                 SercomRegisters::SERCOM4_START_ADDR..=SercomRegisters::SERCOM4_END_ADDR => {
                     let mut copied = self.sercom4;
                     copied.handle_write_word(
@@ -395,6 +1194,7 @@ impl Gamebuino {
                             value,
                             &self.porta_registers,
                             &self.portb_registers,
+                            &mut self.sercom4,
                         );
                         self.buttons
                             .byte_received(value, &self.portb_registers, &mut self.sercom4);
@@ -418,6 +1218,13 @@ impl Gamebuino {
     }
 
     fn write_half_word(&mut self, address: u32, value: u32) {
+        if self.observe_memory {
+            self.observe_access(address, 2, true);
+        }
+        self.store_half_word(address, value)
+    }
+
+    fn store_half_word(&mut self, address: u32, value: u32) {
         let addr = address as usize;
         if addr < 0x20000000 {
             // do nothing; not supporting writing to flash
@@ -434,15 +1241,79 @@ impl Gamebuino {
                 self.sample_rate = 48000000 / (value + 1);
 
                 self.tc5_countdown = GOAL_TICKS_PER_SECOND / self.sample_rate as isize;
-            } else if addr == 0x42004808 && self.sound_samples < self.sound_data.len() {
-                // Writes to DAC.DATA are for audio
-                self.sound_data[self.sound_samples] = value as u16;
-                self.sound_samples += 1;
+            } else if address == TcRegisters::TC5_EVCTRL_ADDRESS {
+                self.tc5_evctrl = value as u16;
+            } else {
+                let value = value as u16;
+                match address {
+                    DmacRegisters::DMAC_START_ADDR..=DmacRegisters::DMAC_END_ADDR => {
+                        self.dmac_running = true;
+                        let mut copied = self.dmac_registers;
+                        copied.handle_write_half_word(
+                            address - DmacRegisters::DMAC_START_ADDR,
+                            value,
+                            self,
+                        );
+                        self.dmac_registers = copied;
+                        self.dmac_running = false;
+                        self.run_dmac();
+                    }
+                    DacRegisters::DAC_START_ADDR..=DacRegisters::DAC_END_ADDR => {
+                        let mut copied = self.dac;
+                        copied.handle_write_half_word(
+                            address - DacRegisters::DAC_START_ADDR,
+                            value,
+                            self,
+                        );
+                        self.dac = copied;
+                    }
+                    AdcRegisters::ADC_START_ADDR..=AdcRegisters::ADC_END_ADDR => {
+                        let mut copied = self.adc;
+                        copied.handle_write_half_word(
+                            address - AdcRegisters::ADC_START_ADDR,
+                            value,
+                            self,
+                        );
+                        self.adc = copied;
+                        if self.adc.take_software_conversion() {
+                            self.adc_result_ready();
+                        }
+                    }
+                    EvsysRegisters::EVSYS_START_ADDR..=EvsysRegisters::EVSYS_END_ADDR => {
+                        let mut copied = self.evsys;
+                        copied.handle_write_half_word(
+                            address - EvsysRegisters::EVSYS_START_ADDR,
+                            value,
+                            self,
+                        );
+                        self.evsys = copied;
+                        if let Some(routed) = self.evsys.take_software_event() {
+                            self.deliver_event(routed);
+                        }
+                    }
+                    RtcRegisters::RTC_START_ADDR..=RtcRegisters::RTC_END_ADDR => {
+                        let mut copied = self.rtc;
+                        copied.handle_write_half_word(
+                            address - RtcRegisters::RTC_START_ADDR,
+                            value,
+                            self,
+                        );
+                        self.rtc = copied;
+                    }
+                    _ => {}
+                }
             }
         }
     }
 
     fn write_byte(&mut self, address: u32, value: u32) {
+        if self.observe_memory {
+            self.observe_access(address, 1, true);
+        }
+        self.store_byte(address, value)
+    }
+
+    fn store_byte(&mut self, address: u32, value: u32) {
         let addr = address as usize;
         if addr < 0x20000000 {
             // do nothing; not supporting writing to flash
@@ -453,6 +1324,7 @@ impl Gamebuino {
             let addr = addr as u32;
             match addr {
                 DmacRegisters::DMAC_START_ADDR..=DmacRegisters::DMAC_END_ADDR => {
+                    self.dmac_running = true;
                     let mut copied = self.dmac_registers;
                     copied.handle_write_byte(
                         addr - DmacRegisters::DMAC_START_ADDR,
@@ -460,6 +1332,62 @@ impl Gamebuino {
                         self,
                     );
                     self.dmac_registers = copied;
+                    self.dmac_running = false;
+                    self.run_dmac();
+                }
+                DacRegisters::DAC_START_ADDR..=DacRegisters::DAC_END_ADDR => {
+                    let mut copied = self.dac;
+                    copied.handle_write_byte(
+                        addr - DacRegisters::DAC_START_ADDR,
+                        value as u8,
+                        self,
+                    );
+                    self.dac = copied;
+                }
+                AdcRegisters::ADC_START_ADDR..=AdcRegisters::ADC_END_ADDR => {
+                    let mut copied = self.adc;
+                    copied.handle_write_byte(
+                        addr - AdcRegisters::ADC_START_ADDR,
+                        value as u8,
+                        self,
+                    );
+                    self.adc = copied;
+                    if self.adc.take_software_conversion() {
+                        self.adc_result_ready();
+                    }
+                }
+                EvsysRegisters::EVSYS_START_ADDR..=EvsysRegisters::EVSYS_END_ADDR => {
+                    let mut copied = self.evsys;
+                    copied.handle_write_byte(
+                        addr - EvsysRegisters::EVSYS_START_ADDR,
+                        value as u8,
+                        self,
+                    );
+                    self.evsys = copied;
+                }
+                RtcRegisters::RTC_START_ADDR..=RtcRegisters::RTC_END_ADDR => {
+                    let mut copied = self.rtc;
+                    copied.handle_write_byte(
+                        addr - RtcRegisters::RTC_START_ADDR,
+                        value as u8,
+                        self,
+                    );
+                    self.rtc = copied;
+                }
+                TcRegisters::TC5_EVCTRL_ADDRESS..=TcRegisters::TC5_EVCTRL_END_ADDRESS => {
+                    let shift = 8 * (addr - TcRegisters::TC5_EVCTRL_ADDRESS);
+                    self.tc5_evctrl =
+                        (self.tc5_evctrl & !(0xff << shift)) | (value as u16) << shift;
+                }
+                SercomRegisters::SERCOM3_START_ADDR..=SercomRegisters::SERCOM3_END_ADDR => {
+                    let mut copied = self.sercom3;
+                    copied.handle_write_byte(
+                        addr - SercomRegisters::SERCOM3_START_ADDR,
+                        value as u8,
+                        self,
+                    );
+                    self.sercom3 = copied;
+                    self.i2c.update(&mut self.sercom3, self.tick_count);
                 }
                 SercomRegisters::SERCOM4_START_ADDR..=SercomRegisters::SERCOM4_END_ADDR => {
                     let mut copied = self.sercom4;
@@ -474,6 +1402,7 @@ impl Gamebuino {
                             value,
                             &self.porta_registers,
                             &self.portb_registers,
+                            &mut self.sercom4,
                         );
                         self.buttons
                             .byte_received(value, &self.portb_registers, &mut self.sercom4);
@@ -675,11 +1604,17 @@ impl Gamebuino {
             Instruction::Bx { rs } => {
                 self.set_register(PC_INDEX, self.read_register(rs) & !1);
                 self.increment_pc();
+                if self.track_calls {
+                    self.note_return();
+                }
             }
             Instruction::Blx { rm } => {
                 self.set_register(LR_INDEX, (self.read_register(PC_INDEX) - 2) | 1);
                 self.set_register(PC_INDEX, self.read_register(rm) & !1);
                 self.increment_pc();
+                if self.track_calls {
+                    self.note_call();
+                }
             }
             Instruction::LdrPc {
                 rd,
@@ -773,14 +1708,14 @@ impl Gamebuino {
             Instruction::Sxth { rd, rm } => {
                 let mut result = self.read_register(rm) & 0xffff;
                 if (result & 0x8000) != 0 {
-                    result = (!0xffff) | result;
+                    result |= !0xffff;
                 }
                 self.set_register(rd, result);
             }
             Instruction::Sxtb { rd, rm } => {
                 let mut result = self.read_register(rm) & 0xff;
                 if (result & 0x80) != 0 {
-                    result = (!0xff) | result;
+                    result |= !0xff;
                 }
                 self.set_register(rd, result);
             }
@@ -808,6 +1743,9 @@ impl Gamebuino {
             Instruction::Push { rlist, lr } => {
                 if lr {
                     self.push_stack(self.read_register(LR_INDEX));
+                    if self.track_calls {
+                        self.note_lr_saved(self.read_register(PC_INDEX) - 4);
+                    }
                 }
                 for i in (0..8).rev() {
                     if rlist & (1 << i) != 0 {
@@ -825,94 +1763,97 @@ impl Gamebuino {
                     self.pop_stack(PC_INDEX);
                     self.set_register(PC_INDEX, self.read_register(PC_INDEX) & !1);
                     self.increment_pc();
+                    if self.track_calls {
+                        self.note_return();
+                    }
                 }
             }
             Instruction::Beq { offset } => {
                 if self.cond_reg.z {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bne { offset } => {
                 if !self.cond_reg.z {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bcs { offset } => {
                 if self.cond_reg.c {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bcc { offset } => {
                 if !self.cond_reg.c {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bmi { offset } => {
                 if self.cond_reg.n {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bpl { offset } => {
                 if !self.cond_reg.n {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bvs { offset } => {
                 if self.cond_reg.v {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bcv { offset } => {
                 if !self.cond_reg.v {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bhi { offset } => {
                 if self.cond_reg.c && !self.cond_reg.z {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bls { offset } => {
                 if !self.cond_reg.c || self.cond_reg.z {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bge { offset } => {
                 if self.cond_reg.n == self.cond_reg.v {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Blt { offset } => {
                 if self.cond_reg.n != self.cond_reg.v {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Bgt { offset } => {
                 if !self.cond_reg.z && (self.cond_reg.n == self.cond_reg.v) {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::Ble { offset } => {
                 if self.cond_reg.z || (self.cond_reg.n != self.cond_reg.v) {
-                    self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                    self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                     self.increment_pc();
                 }
             }
             Instruction::B { offset } => {
-                self.set_register(PC_INDEX, self.read_register(PC_INDEX) + offset);
+                self.set_register(PC_INDEX, self.read_register(PC_INDEX).wrapping_add(offset));
                 self.increment_pc();
             }
             Instruction::Bl {
@@ -921,12 +1862,15 @@ impl Gamebuino {
                 first,
             } => {
                 if first {
-                    self.set_register(LR_INDEX, self.read_register(PC_INDEX) + offset1);
+                    self.set_register(LR_INDEX, self.read_register(PC_INDEX).wrapping_add(offset1));
                 } else {
                     let next_instruction = self.read_register(PC_INDEX) - 2;
-                    self.set_register(PC_INDEX, self.read_register(LR_INDEX) + offset2);
+                    self.set_register(PC_INDEX, self.read_register(LR_INDEX).wrapping_add(offset2));
                     self.set_register(LR_INDEX, next_instruction | 1);
                     self.increment_pc();
+                    if self.track_calls {
+                        self.note_call();
+                    }
                 }
             }
             Instruction::Dmb => {
@@ -936,3 +1880,187 @@ impl Gamebuino {
         }
     }
 }
+
+impl Gamebuino {
+    /// Symbols and line information from the ELF loaded by `load_elf`.
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+
+    /// Attaches a virtual device to the I2C expansion bus (SERCOM3).
+    pub fn attach_i2c_device(&mut self, device: Box<dyn I2cDevice>) {
+        self.i2c.attach(device);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn host_date_time() -> (u16, u8, u8, u8, u8, u8) {
+    let now = js_sys::Date::new_0();
+    (
+        now.get_full_year() as u16,
+        now.get_month() as u8 + 1,
+        now.get_date() as u8,
+        now.get_hours() as u8,
+        now.get_minutes() as u8,
+        now.get_seconds() as u8,
+    )
+}
+
+// Native builds, such as a GDB server, have no JavaScript to ask, and std doesn't know the
+// time zone
+#[cfg(not(target_arch = "wasm32"))]
+fn host_date_time() -> (u16, u8, u8, u8, u8, u8) {
+    let elapsed = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs());
+    let (days, seconds) = (elapsed / 86400, elapsed % 86400);
+
+    // Days since 1970-01-01 to a civil date, counting in 400-year eras from 0000-03-01
+    let z = days + 719468;
+    let era = z / 146097;
+    let day_of_era = z % 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let march_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * march_month + 2) / 5 + 1;
+    let month = if march_month < 10 {
+        march_month + 3
+    } else {
+        march_month - 9
+    };
+    let year = era * 400 + year_of_era + (month <= 2) as u64;
+    (
+        year as u16,
+        month as u8,
+        day as u8,
+        (seconds / 3600) as u8,
+        (seconds / 60 % 60) as u8,
+        (seconds % 60) as u8,
+    )
+}
+
+#[cfg(test)]
+impl Gamebuino {
+    pub(crate) const TEST_CODE: u32 = 0x4100;
+    pub(crate) const TEST_HANDLER: u32 = 0x4200;
+
+    /// A program loaded at 0x4000 that starts running `code` at `TEST_CODE`, with the stack
+    /// at the top of SRAM and every interrupt handled by `handler` at `TEST_HANDLER`, or by a
+    /// plain return if it is empty.
+    pub(crate) fn for_test(code: &[u16], handler: &[u16]) -> Gamebuino {
+        let start = 0x4000;
+        let mut program = vec![0; 0x300];
+        let mut place = |address: u32, bytes: &[u8]| {
+            let offset = (address - start) as usize;
+            program[offset..offset + bytes.len()].copy_from_slice(bytes);
+        };
+        place(start, &0x20008000u32.to_le_bytes());
+        place(start + 4, &(Gamebuino::TEST_CODE | 1).to_le_bytes());
+        for exception_number in 2..48 {
+            let vector = Gamebuino::TEST_HANDLER | 1;
+            place(start + exception_number * 4, &vector.to_le_bytes());
+        }
+        let halves =
+            |code: &[u16]| -> Vec<u8> { code.iter().flat_map(|h| h.to_le_bytes()).collect() };
+        place(Gamebuino::TEST_CODE, &halves(code));
+        let handler = if handler.is_empty() {
+            &[0x4770]
+        } else {
+            handler
+        };
+        place(Gamebuino::TEST_HANDLER, &halves(handler));
+
+        let mut gamebuino = Gamebuino::new();
+        gamebuino.load_program(&program, start);
+        gamebuino
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Sends a command and its arguments to the screen as SERCOM4 would.
+    fn send_to_screen(gamebuino: &mut Gamebuino, command: u8, args: &[u8]) {
+        let send = |gamebuino: &mut Gamebuino, value: u8| {
+            gamebuino.screen.byte_received(
+                value,
+                &gamebuino.porta_registers,
+                &gamebuino.portb_registers,
+                &mut gamebuino.sercom4,
+            );
+        };
+        gamebuino.portb_registers.out_value = 0;
+        send(gamebuino, command);
+        gamebuino.portb_registers.out_value = 1 << 23;
+        for &arg in args {
+            send(gamebuino, arg);
+        }
+    }
+
+    #[test]
+    fn run_until_frame_stops_once_the_frame_is_complete() {
+        let mut gamebuino = Gamebuino::for_test(
+            &[
+                0x4803, // ldr r0, [pc, #12]
+                0x21f8, // movs r1, #0xf8
+                0x7001, // strb r1, [r0]
+                0x2100, // movs r1, #0
+                0x7001, // strb r1, [r0]
+                0xe7fe, // b .
+                0x0000, //
+                0x0000, //
+                0x1828, // SERCOM4 DATA
+                0x4200, //
+            ],
+            &[],
+        );
+        // The game has sent all but the last pixel of a full window
+        send_to_screen(&mut gamebuino, 0x2a, &[0, 0, 0, 159]);
+        send_to_screen(&mut gamebuino, 0x2b, &[0, 0, 0, 127]);
+        let pixels = vec![0; (St7735::WIDTH * St7735::HEIGHT - 1) * 2];
+        send_to_screen(&mut gamebuino, 0x2c, &pixels);
+        assert_eq!(gamebuino.frame_count(), 0);
+
+        let stop = gamebuino.run_until_frame(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::FrameCompleted);
+        assert_eq!(stop.ticks(), 5);
+        assert_eq!(stop.pc(), Gamebuino::TEST_CODE + 10);
+        assert_eq!(gamebuino.frame_count(), 1);
+        assert_eq!(gamebuino.screen.frame().last(), Some(&0xff0000f8));
+
+        let stop = gamebuino.run_until_frame(100, 0xff);
+        assert_eq!(stop.kind(), StopKind::TicksExhausted);
+        assert_eq!(stop.ticks(), 100);
+    }
+
+    #[test]
+    fn interrupts_are_entered_before_step_returns() {
+        let mut gamebuino = Gamebuino::for_test(
+            &[
+                0x2005, // movs r0, #5
+                0xe7fe, // b .
+            ],
+            &[
+                0x2101, // movs r1, #1
+                0x4770, // bx lr
+            ],
+        );
+        assert_eq!(gamebuino.pc(), Gamebuino::TEST_CODE);
+
+        gamebuino.tc5_interrupt = true;
+        gamebuino.step();
+        assert_eq!(gamebuino.read_register(0), 5);
+        assert_eq!(gamebuino.pc(), Gamebuino::TEST_HANDLER);
+        assert_eq!(gamebuino.exception_depth, 1);
+
+        gamebuino.step();
+        assert_eq!(gamebuino.read_register(1), 1);
+        // Returning from the handler unstacks before the step returns, too
+        gamebuino.step();
+        assert_eq!(gamebuino.pc(), Gamebuino::TEST_CODE + 2);
+        assert_eq!(gamebuino.exception_depth, 0);
+        assert_eq!(gamebuino.read_register(SP_INDEX), 0x20008000);
+    }
+}
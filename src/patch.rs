@@ -0,0 +1,188 @@
+//! Writing memory from outside the program: pokes, and patches to flash that take effect on
+//! the decoded instructions.
+
+use crate::instruction::{self, Instruction};
+use crate::Gamebuino;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// Writes a byte as the CPU would, or patches it into flash.
+    pub fn poke_byte(&mut self, address: u32, value: u8) {
+        self.poke(address, 1, value as u32);
+    }
+
+    pub fn poke_half(&mut self, address: u32, value: u16) {
+        self.poke(address, 2, value as u32);
+    }
+
+    pub fn poke_word(&mut self, address: u32, value: u32) {
+        self.poke(address, 4, value);
+    }
+
+    /// Overwrites flash from `address` and decodes the instructions there again, so that
+    /// patched code runs.
+    pub fn patch_flash(&mut self, address: u32, bytes: &[u8]) -> Result<(), String> {
+        let start = address as usize;
+        let end = start
+            .checked_add(bytes.len())
+            .filter(|&end| end <= self.flash.len())
+            .ok_or_else(|| format!("patch at 0x{:08x} is outside flash", address))?;
+        self.flash[start..end].copy_from_slice(bytes);
+        self.decode_instructions(address, end as u32);
+        Ok(())
+    }
+}
+
+impl Gamebuino {
+    /// Writes without triggering watchpoints or being traced, so that freezes applied between
+    /// instructions aren't taken for the program's accesses.
+    pub(crate) fn poke(&mut self, address: u32, size: u32, value: u32) {
+        if (address as usize) < self.flash.len() {
+            let bytes = value.to_le_bytes();
+            // A poke straddling the end of flash writes what fits
+            let length = (size as usize).min(self.flash.len() - address as usize);
+            let _ = self.patch_flash(address, &bytes[..length]);
+        } else {
+            self.debugger_write(address, size, value);
+        }
+    }
+
+    /// Decodes the cached instructions covering flash from `start` up to `end` again, along
+    /// with any BL whose other half is in that range.
+    fn decode_instructions(&mut self, start: u32, end: u32) {
+        let count = self.instructions.len();
+        let to_index = |address: u32| (address.saturating_sub(self.program_offset) >> 1) as usize;
+        let mut index = to_index(start);
+        let end_index = to_index(end.wrapping_add(1)).min(count);
+        if end <= self.program_offset || index >= count {
+            return;
+        }
+        if index > 0 && is_bl_half(self.instructions[index - 1], true) {
+            index -= 1;
+        }
+
+        // Past the patch, carry on while the old pairing of BL halves no longer holds
+        while index < count && (index < end_index || is_bl_half(self.instructions[index], false)) {
+            let address = self.program_offset + index as u32 * 2;
+            let parsed = instruction::parse_instruction(
+                self.flash_half_word(address),
+                self.flash_half_word(address + 2),
+            );
+            self.instructions[index] = parsed;
+            match parsed {
+                Instruction::Bl {
+                    offset1, offset2, ..
+                } if index + 1 < count => {
+                    self.instructions[index + 1] = Instruction::Bl {
+                        offset1,
+                        offset2,
+                        first: false,
+                    };
+                    index += 2;
+                }
+                _ => index += 1,
+            }
+        }
+    }
+
+    fn flash_half_word(&self, address: u32) -> u16 {
+        let byte = |address: u32| self.flash.get(address as usize).copied().unwrap_or(0) as u16;
+        byte(address) | byte(address + 1) << 8
+    }
+}
+
+fn is_bl_half(instruction: Instruction, first_half: bool) -> bool {
+    matches!(instruction, Instruction::Bl { first, .. } if first == first_half)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const START: u32 = 0x4000;
+
+    fn gamebuino() -> Gamebuino {
+        let code: [u16; 5] = [
+            0x2000, // movs r0, #0
+            0xf000, // bl 0x400a
+            0xf801, //
+            0x2101, // movs r1, #1
+            0x2202, // movs r2, #2
+        ];
+        let program: Vec<u8> = code.iter().flat_map(|half| half.to_le_bytes()).collect();
+        let mut gamebuino = Gamebuino::new();
+        gamebuino.load_program(&program, START);
+        gamebuino
+    }
+
+    fn bl_at(gamebuino: &Gamebuino, index: usize) -> (u32, u32) {
+        match (
+            gamebuino.instructions[index],
+            gamebuino.instructions[index + 1],
+        ) {
+            (
+                Instruction::Bl {
+                    offset1,
+                    offset2,
+                    first: true,
+                },
+                Instruction::Bl {
+                    offset1: second_offset1,
+                    offset2: second_offset2,
+                    first: false,
+                },
+            ) => {
+                assert_eq!((offset1, offset2), (second_offset1, second_offset2));
+                (offset1, offset2)
+            }
+            pair => panic!("no BL pair at {}: {:?}", index, pair),
+        }
+    }
+
+    #[test]
+    fn patching_the_first_half_of_a_bl_decodes_both_halves() {
+        let mut gamebuino = gamebuino();
+        assert_eq!(bl_at(&gamebuino, 1), (0, 2));
+        gamebuino.patch_flash(START + 2, &[0xff, 0xf7]).unwrap();
+        assert_eq!(bl_at(&gamebuino, 1), (0xfffff000, 2));
+        assert!(matches!(
+            gamebuino.instructions[3],
+            Instruction::MovImm { .. }
+        ));
+    }
+
+    #[test]
+    fn patching_the_second_half_of_a_bl_decodes_both_halves() {
+        let mut gamebuino = gamebuino();
+        gamebuino.patch_flash(START + 4, &[0x02, 0xf8]).unwrap();
+        assert_eq!(bl_at(&gamebuino, 1), (0, 4));
+        assert!(matches!(
+            gamebuino.instructions[0],
+            Instruction::MovImm { .. }
+        ));
+    }
+
+    #[test]
+    fn patching_a_bl_into_another_instruction_decodes_its_old_second_half() {
+        let mut gamebuino = gamebuino();
+        gamebuino.poke_half(START + 2, 0x2303);
+        assert!(matches!(
+            gamebuino.instructions[1],
+            Instruction::MovImm { .. }
+        ));
+        assert!(!matches!(gamebuino.instructions[2], Instruction::Bl { .. }));
+        assert!(matches!(
+            gamebuino.instructions[3],
+            Instruction::MovImm { .. }
+        ));
+    }
+
+    #[test]
+    fn patching_outside_flash_fails() {
+        let mut gamebuino = gamebuino();
+        let end = gamebuino.flash.len() as u32;
+        assert!(gamebuino.patch_flash(end - 1, &[0, 0]).is_err());
+        assert!(gamebuino.patch_flash(u32::MAX, &[0]).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Cycle profiler that attributes emulated ticks to PCs and, through the shadow call stack, to
+//! call stacks.
+
+use crate::backtrace::StackFrame;
+use crate::elf::DebugInfo;
+use crate::Gamebuino;
+use std::collections::{HashMap, VecDeque};
+use wasm_bindgen::prelude::*;
+
+struct Node {
+    parent: usize,
+    entry: u32,
+    children: HashMap<u32, usize>,
+    cycles: u64,
+}
+
+pub struct Profiler {
+    // Call tree; node 0 is the root, above the outermost frame
+    nodes: Vec<Node>,
+    // The call stack as of the last step, with the node of each frame
+    stack: Vec<(StackFrame, usize)>,
+    pc_cycles: HashMap<u32, u64>,
+    sample_interval: u64,
+    until_sample: u64,
+}
+
+impl Profiler {
+    pub fn new(sample_interval: u64) -> Profiler {
+        Profiler {
+            nodes: vec![Node {
+                parent: 0,
+                entry: 0,
+                children: HashMap::new(),
+                cycles: 0,
+            }],
+            stack: Vec::new(),
+            pc_cycles: HashMap::new(),
+            sample_interval,
+            until_sample: sample_interval,
+        }
+    }
+
+    /// Cycles spent on each instruction, by address.
+    pub fn pc_cycles(&self) -> &HashMap<u32, u64> {
+        &self.pc_cycles
+    }
+
+    pub fn clear(&mut self) {
+        self.pc_cycles.clear();
+        for node in &mut self.nodes {
+            node.cycles = 0;
+        }
+    }
+
+    /// Attributes `ticks` spent running the instruction at `pc`. Sampling only records where
+    /// execution is once per interval.
+    fn record(&mut self, pc: u32, ticks: u64) {
+        let weight = if self.sample_interval == 0 {
+            ticks
+        } else if self.until_sample > ticks {
+            self.until_sample -= ticks;
+            return;
+        } else {
+            // A slow instruction can span more than one sample
+            let overshoot = ticks - self.until_sample;
+            self.until_sample = self.sample_interval - overshoot % self.sample_interval;
+            self.sample_interval * (1 + overshoot / self.sample_interval)
+        };
+        *self.pc_cycles.entry(pc).or_insert(0) += weight;
+        let node = self.current_node();
+        self.nodes[node].cycles += weight;
+    }
+
+    fn current_node(&self) -> usize {
+        self.stack.last().map_or(0, |&(_, node)| node)
+    }
+
+    /// Follows the call stack to `call_stack`, which only differs from the last one at the top.
+    fn update_stack(&mut self, call_stack: &VecDeque<StackFrame>) {
+        if self.stack.len() == call_stack.len()
+            && self.stack.last().map(|&(frame, _)| frame) == call_stack.back().copied()
+        {
+            return;
+        }
+        let common = self
+            .stack
+            .iter()
+            .zip(call_stack)
+            .take_while(|((a, _), b)| a == *b)
+            .count();
+        self.stack.truncate(common);
+        for &frame in call_stack.range(common..) {
+            self.push(frame);
+        }
+    }
+
+    fn push(&mut self, frame: StackFrame) {
+        let parent = self.current_node();
+        let entry = frame.function;
+        let node = match self.nodes[parent].children.get(&entry) {
+            Some(&node) => node,
+            None => {
+                let node = self.nodes.len();
+                self.nodes.push(Node {
+                    parent,
+                    entry,
+                    children: HashMap::new(),
+                    cycles: 0,
+                });
+                self.nodes[parent].children.insert(entry, node);
+                node
+            }
+        };
+        self.stack.push((frame, node));
+    }
+
+    /// Entry addresses of the functions from the outermost down to `node`.
+    fn path(&self, mut node: usize) -> Vec<u32> {
+        let mut path = Vec::new();
+        while node != 0 {
+            path.push(self.nodes[node].entry);
+            node = self.nodes[node].parent;
+        }
+        path.reverse();
+        path
+    }
+
+    /// One line per call stack: function names from the root down, separated by semicolons,
+    /// followed by the cycles spent in the innermost one. This is the input format of
+    /// flamegraph.pl and speedscope.
+    pub fn collapsed_stacks(&self, debug_info: Option<&DebugInfo>) -> String {
+        let mut output = String::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 {
+                continue;
+            }
+            let mut names: Vec<String> = self
+                .path(index)
+                .into_iter()
+                .map(|entry| function_name(debug_info, entry))
+                .collect();
+            if names.is_empty() {
+                names.push("[unknown]".to_string());
+            }
+            output.push_str(&format!("{} {}\n", names.join(";"), node.cycles));
+        }
+        output
+    }
+
+    /// Tab-separated cycles per function: those spent in the function itself, by PC, and those
+    /// including its callees, by call stack.
+    pub fn function_table(&self, debug_info: &DebugInfo) -> String {
+        let mut functions: HashMap<String, (u64, u64)> = HashMap::new();
+        for (&pc, &cycles) in &self.pc_cycles {
+            let name = function_name(Some(debug_info), pc);
+            functions.entry(name).or_insert((0, 0)).0 += cycles;
+        }
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 {
+                continue;
+            }
+            let mut names: Vec<String> = self
+                .path(index)
+                .into_iter()
+                .map(|entry| function_name(Some(debug_info), entry))
+                .collect();
+            // Recursive functions count once per stack
+            names.sort();
+            names.dedup();
+            for name in names {
+                functions.entry(name).or_insert((0, 0)).1 += node.cycles;
+            }
+        }
+
+        let mut rows: Vec<(String, (u64, u64))> = functions.into_iter().collect();
+        rows.sort_by(|a, b| (b.1).0.cmp(&(a.1).0).then_with(|| a.0.cmp(&b.0)));
+        let mut output = String::from("function\tself_cycles\ttotal_cycles\n");
+        for (name, (self_cycles, total_cycles)) in rows {
+            output.push_str(&format!("{}\t{}\t{}\n", name, self_cycles, total_cycles));
+        }
+        output
+    }
+}
+
+fn function_name(debug_info: Option<&DebugInfo>, address: u32) -> String {
+    match debug_info.and_then(|debug_info| debug_info.function_at(address)) {
+        Some((name, _)) => name.to_string(),
+        None => format!("0x{:08x}", address),
+    }
+}
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// Starts profiling from the current point of execution. With a `sample_interval` of 0
+    /// every instruction is counted, otherwise where execution is once every that many ticks.
+    pub fn start_profiling(&mut self, sample_interval: u32) {
+        self.profiler = Some(Box::new(Profiler::new(sample_interval as u64)));
+        self.update_call_tracking();
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.update_stack(&self.call_stack);
+        }
+    }
+
+    pub fn stop_profiling(&mut self) {
+        self.profiler = None;
+        self.update_call_tracking();
+    }
+
+    /// Zeroes the counts, e.g. at the start of each frame, but keeps following the call stack.
+    pub fn clear_profile(&mut self) {
+        if let Some(profiler) = self.profiler.as_mut() {
+            profiler.clear();
+        }
+    }
+
+    /// The profile in collapsed stack format, for flame graphs.
+    pub fn profile_collapsed_stacks(&self) -> String {
+        self.profiler.as_ref().map_or(String::new(), |profiler| {
+            profiler.collapsed_stacks(self.debug_info.as_ref())
+        })
+    }
+
+    /// Cycles per function as tab-separated values, if an ELF with symbols is loaded.
+    pub fn profile_function_table(&self) -> String {
+        match (self.profiler.as_ref(), self.debug_info.as_ref()) {
+            (Some(profiler), Some(debug_info)) => profiler.function_table(debug_info),
+            _ => String::new(),
+        }
+    }
+
+    /// Cycles per instruction address, one `address cycles` line each, by address.
+    pub fn profile_pc_cycles(&self) -> String {
+        let profiler = match self.profiler.as_ref() {
+            Some(profiler) => profiler,
+            None => return String::new(),
+        };
+        let mut pcs: Vec<(&u32, &u64)> = profiler.pc_cycles().iter().collect();
+        pcs.sort();
+        pcs.iter()
+            .map(|(pc, cycles)| format!("0x{:08x} {}\n", pc, cycles))
+            .collect()
+    }
+}
+
+impl Gamebuino {
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_deref()
+    }
+
+    /// Called at the end of `step` with the address of the instruction that ran and the tick
+    /// count before it.
+    pub(crate) fn profile_step(&mut self, pc: u32, start_tick: u64) {
+        let ticks = self.tick_count - start_tick;
+        if let Some(profiler) = self.profiler.as_mut() {
+            // Counted where the instruction ran, before any call or return it made
+            profiler.record(pc, ticks);
+            profiler.update_stack(&self.call_stack);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_weigh_the_interval_they_stand_for() {
+        let mut profiler = Profiler::new(10);
+        for _ in 0..3 {
+            profiler.record(0x100, 3);
+        }
+        assert!(profiler.pc_cycles().is_empty());
+        // Crosses the sample a tick in and two more after it
+        profiler.record(0x102, 25);
+        assert_eq!(profiler.pc_cycles()[&0x102], 30);
+        // The next sample is due 10 ticks after the last one crossed
+        profiler.record(0x104, 5);
+        assert!(!profiler.pc_cycles().contains_key(&0x104));
+        profiler.record(0x106, 1);
+        assert_eq!(profiler.pc_cycles()[&0x106], 10);
+        // All the ticks were accounted for
+        let total: u64 = profiler.pc_cycles().values().sum();
+        assert_eq!(total, 3 * 3 + 25 + 5 + 1);
+
+        let mut profiler = Profiler::new(0);
+        profiler.record(0x100, 3);
+        profiler.record(0x100, 2);
+        assert_eq!(profiler.pc_cycles()[&0x100], 5);
+    }
+
+    #[test]
+    fn collapsed_stacks_attribute_ticks_to_call_stacks() {
+        let mut gamebuino = Gamebuino::for_test(
+            &[
+                0xf000, 0xf802, // bl function
+                0xe7fe, // b .
+                0x0000, //
+                0x2001, // function: movs r0, #1
+                0x4770, // bx lr
+            ],
+            &[],
+        );
+        gamebuino.start_profiling(0);
+        let mut ticks = Vec::new();
+        // Both halves of the BL run in main, and the return in the function
+        for _ in 0..5 {
+            let start = gamebuino.tick_count;
+            gamebuino.step();
+            ticks.push(gamebuino.tick_count - start);
+        }
+
+        let code = Gamebuino::TEST_CODE;
+        let mut lines: Vec<String> = gamebuino
+            .profile_collapsed_stacks()
+            .lines()
+            .map(String::from)
+            .collect();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                format!("0x{:08x} {}", code, ticks[0] + ticks[1] + ticks[4]),
+                format!("0x{:08x};0x{:08x} {}", code, code + 8, ticks[2] + ticks[3]),
+            ]
+        );
+        assert_eq!(gamebuino.profiler().unwrap().pc_cycles()[&(code + 8)], 1);
+
+        gamebuino.clear_profile();
+        assert_eq!(gamebuino.profile_collapsed_stacks(), "");
+    }
+}
@@ -16,104 +16,648 @@ impl CondRegister {
             | (if self.z { 8 } else { 0 })
     }
 
-    pub fn from_word(&mut self, val: u32) {
-        self.c = if val & 1 != 0 { true } else { false };
-        self.n = if val & 2 != 0 { true } else { false };
-        self.v = if val & 4 != 0 { true } else { false };
-        self.z = if val & 8 != 0 { true } else { false };
+    pub fn set_word(&mut self, val: u32) {
+        self.c = val & 1 != 0;
+        self.n = val & 2 != 0;
+        self.v = val & 4 != 0;
+        self.z = val & 8 != 0;
     }
 }
 
 pub trait Peripheral {
     fn handle_write_word(&mut self, offset: u32, value: u32, gamebuino: &mut Gamebuino);
     fn handle_write_byte(&mut self, offset: u32, value: u8, gamebuino: &mut Gamebuino);
+
+    /// Writes the two bytes in turn, leaving the rest of the word alone. Peripherals with
+    /// 16-bit registers that must be written at once handle those themselves.
+    fn handle_write_half_word(&mut self, offset: u32, value: u16, gamebuino: &mut Gamebuino) {
+        self.handle_write_byte(offset, value as u8, gamebuino);
+        self.handle_write_byte(offset + 1, (value >> 8) as u8, gamebuino);
+    }
+
     fn handle_read_word(&self, offset: u32) -> u32;
     fn handle_read_byte(&self, offset: u32) -> u8;
 }
 
+#[derive(Clone, Copy)]
+struct DmacDescriptor {
+    btctrl: u16,
+    btcnt: u16,
+    srcaddr: u32,
+    dstaddr: u32,
+    descaddr: u32,
+}
+
+impl DmacDescriptor {
+    const VALID: u16 = 1 << 0;
+    const EVOSEL_SHIFT: u16 = 1;
+    const BLOCKACT_SHIFT: u16 = 3;
+    const BEATSIZE_SHIFT: u16 = 8;
+    const SRCINC: u16 = 1 << 10;
+    const DSTINC: u16 = 1 << 11;
+    const STEPSEL_SRC: u16 = 1 << 12;
+    const STEPSIZE_SHIFT: u16 = 13;
+
+    const EVOSEL_BLOCK: u16 = 0x1;
+    const EVOSEL_BEAT: u16 = 0x3;
+    const BLOCKACT_INT: u16 = 0x1;
+    const BLOCKACT_SUSPEND: u16 = 0x2;
+
+    fn read(address: u32, gamebuino: &Gamebuino) -> DmacDescriptor {
+        DmacDescriptor {
+            btctrl: gamebuino.load_half_word(address),
+            btcnt: gamebuino.load_half_word(address + 0x02),
+            srcaddr: gamebuino.load_word(address + 0x04),
+            dstaddr: gamebuino.load_word(address + 0x08),
+            descaddr: gamebuino.load_word(address + 0x0C),
+        }
+    }
+
+    fn write(&self, address: u32, gamebuino: &mut Gamebuino) {
+        gamebuino.store_half_word(address, self.btctrl as u32);
+        gamebuino.store_half_word(address + 0x02, self.btcnt as u32);
+        gamebuino.store_word(address + 0x04, self.srcaddr);
+        gamebuino.store_word(address + 0x08, self.dstaddr);
+        gamebuino.store_word(address + 0x0C, self.descaddr);
+    }
+
+    fn is_valid(&self) -> bool {
+        self.btctrl & DmacDescriptor::VALID != 0
+    }
+
+    fn event_output(&self) -> u16 {
+        (self.btctrl >> DmacDescriptor::EVOSEL_SHIFT) & 0b11
+    }
+
+    fn block_action(&self) -> u16 {
+        (self.btctrl >> DmacDescriptor::BLOCKACT_SHIFT) & 0b11
+    }
+
+    fn beat_size(&self) -> u32 {
+        1 << ((self.btctrl >> DmacDescriptor::BEATSIZE_SHIFT) & 0b11).min(2)
+    }
+
+    /// Address of `beat`. Incrementing addresses in the descriptor point past the end of the
+    /// block, so count back from there.
+    fn beat_address(&self, address: u32, increment: u16, step_to_source: bool, beat: u16) -> u32 {
+        if self.btctrl & increment == 0 {
+            return address;
+        }
+        let step = if (self.btctrl & DmacDescriptor::STEPSEL_SRC != 0) == step_to_source {
+            1 << (self.btctrl >> DmacDescriptor::STEPSIZE_SHIFT)
+        } else {
+            1
+        };
+        let stride = self.beat_size() * step;
+        address
+            .wrapping_sub(self.btcnt as u32 * stride)
+            .wrapping_add(beat as u32 * stride)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct DmacChannel {
+    enabled: bool,
+    suspended: bool,
+    chctrlb: u32,
+    intenset: u8,
+    intflag: u8,
+    descriptor: Option<DmacDescriptor>,
+    beat: u16,
+    // A trigger was received and its beat, block or transaction is in progress
+    pending: bool,
+    // When the beat in progress completes
+    beat_end_tick: u64,
+}
+
+impl DmacChannel {
+    fn new() -> DmacChannel {
+        DmacChannel {
+            enabled: false,
+            suspended: false,
+            chctrlb: 0,
+            intenset: 0,
+            intflag: 0,
+            descriptor: None,
+            beat: 0,
+            pending: false,
+            beat_end_tick: 0,
+        }
+    }
+
+    /// Whether the channel has a descriptor to work on and may run.
+    fn is_ready(&self) -> bool {
+        self.enabled && !self.suspended && self.descriptor.is_some()
+    }
+
+    fn is_busy(&self) -> bool {
+        self.is_ready() && self.pending
+    }
+
+    fn trigger_source(&self) -> u8 {
+        ((self.chctrlb >> DmacRegisters::CHCTRLB_TRIGSRC_SHIFT) & 0x3f) as u8
+    }
+
+    fn trigger_action(&self) -> u32 {
+        (self.chctrlb >> DmacRegisters::CHCTRLB_TRIGACT_SHIFT) & 0b11
+    }
+
+    fn status(&self) -> u8 {
+        if self.is_busy() {
+            DmacRegisters::CHSTATUS_BUSY
+        } else {
+            0
+        }
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct DmacRegisters {
+    ctrl: u16,
     base_address: u32,
     wrb_address: u32,
-    descriptor: u32,
     selected_channel_id: u8,
+    channels: [DmacChannel; DmacRegisters::CHANNEL_COUNT],
 }
 
 impl DmacRegisters {
+    const CTRL_OFFSET: u32 = 0x00;
+    const SWTRIGCTRL_OFFSET: u32 = 0x10;
+    const INTPEND_OFFSET: u32 = 0x20;
+    const INTSTATUS_OFFSET: u32 = 0x24;
+    const BUSYCH_OFFSET: u32 = 0x28;
     const BASEADDR_OFFSET: u32 = 0x34;
     const WRBADDR_OFFSET: u32 = 0x38;
     const CHID_OFFSET: u32 = 0x3f;
     const CHCTRLA_OFFSET: u32 = 0x40;
+    const CHCTRLB_OFFSET: u32 = 0x44;
+    const CHINTENCLR_OFFSET: u32 = 0x4c;
+    const CHINTENSET_OFFSET: u32 = 0x4d;
     const CHINTFLAG_OFFSET: u32 = 0x4e;
+    const CHSTATUS_OFFSET: u32 = 0x4f;
     pub const DMAC_START_ADDR: u32 = 0x41004800;
-    pub const DMAC_END_ADDR: u32 = DmacRegisters::DMAC_START_ADDR + DmacRegisters::CHINTFLAG_OFFSET;
+    pub const DMAC_END_ADDR: u32 = DmacRegisters::DMAC_START_ADDR + DmacRegisters::CHSTATUS_OFFSET;
+
+    const CHANNEL_COUNT: usize = 12;
+    // Only the first four channels are connected to the event system
+    const EVENT_CHANNEL_COUNT: usize = 4;
+    const DESCRIPTOR_SIZE: u32 = 0x10;
+
+    const CTRL_SWRST: u16 = 1 << 0;
+    const CTRL_DMAENABLE: u16 = 1 << 1;
+    const CHCTRLA_SWRST: u8 = 1 << 0;
+    const CHCTRLA_ENABLE: u8 = 1 << 1;
+    const CHCTRLB_EVACT_MASK: u32 = 0b111;
+    const CHCTRLB_EVIE: u32 = 1 << 3;
+    const CHCTRLB_EVOE: u32 = 1 << 4;
+    const CHCTRLB_TRIGSRC_SHIFT: u32 = 8;
+    const CHCTRLB_TRIGACT_SHIFT: u32 = 22;
+    const CHCTRLB_CMD_SHIFT: u32 = 24;
+    const CHCTRLB_CMD_MASK: u32 = 0b11 << DmacRegisters::CHCTRLB_CMD_SHIFT;
+    const CMD_SUSPEND: u32 = 0x1;
+    const CMD_RESUME: u32 = 0x2;
+    const TRIGACT_BEAT: u32 = 0x2;
+    const TRIGACT_TRANSACTION: u32 = 0x3;
+    const EVACT_TRIG: u32 = 0x1;
+    const EVACT_CTRIG: u32 = 0x2;
+    const EVACT_CBLOCK: u32 = 0x3;
+    const EVACT_SUSPEND: u32 = 0x4;
+    const EVACT_RESUME: u32 = 0x5;
+    const CHINTFLAG_TERR: u8 = 1 << 0;
+    const CHINTFLAG_TCMPL: u8 = 1 << 1;
+    const CHINTFLAG_SUSP: u8 = 1 << 2;
+    const CHSTATUS_BUSY: u8 = 1 << 1;
+    const INTPEND_BUSY: u16 = 1 << 14;
+
+    // Peripheral trigger sources
+    pub const TRIGGER_SERCOM3_RX: u8 = 0x07;
+    pub const TRIGGER_SERCOM3_TX: u8 = 0x08;
+    pub const TRIGGER_SERCOM4_RX: u8 = 0x09;
+    pub const TRIGGER_SERCOM4_TX: u8 = 0x0A;
+    pub const TRIGGER_SERCOM5_RX: u8 = 0x0B;
+    pub const TRIGGER_SERCOM5_TX: u8 = 0x0C;
+    pub const TRIGGER_TC5_OVF: u8 = 0x1E;
+    pub const TRIGGER_ADC_RESRDY: u8 = 0x27;
+    pub const TRIGGER_DAC_EMPTY: u8 = 0x28;
 
     pub fn new() -> DmacRegisters {
         DmacRegisters {
+            ctrl: 0,
             base_address: 0,
             wrb_address: 0,
-            descriptor: 0,
             selected_channel_id: 0,
+            channels: [DmacChannel::new(); DmacRegisters::CHANNEL_COUNT],
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.channels.iter().any(|channel| channel.enabled)
+    }
+
+    /// Handles peripheral triggers, one bit per trigger source, and event inputs, one bit per
+    /// channel, then carries out the beats due by now. Returns the tick at which the next beat
+    /// completes, if any.
+    pub fn run(&mut self, triggers: u64, events: u8, gamebuino: &mut Gamebuino) -> Option<u64> {
+        for channel in 0..DmacRegisters::CHANNEL_COUNT {
+            let source = self.channels[channel].trigger_source();
+            if source != 0 && triggers & (1 << source) != 0 {
+                self.trigger(channel, gamebuino);
+            }
+            if channel < DmacRegisters::EVENT_CHANNEL_COUNT && events & (1 << channel) != 0 {
+                self.handle_event(channel, gamebuino);
+            }
+        }
+        self.advance(gamebuino)
+    }
+
+    fn advance(&mut self, gamebuino: &mut Gamebuino) -> Option<u64> {
+        if self.ctrl & DmacRegisters::CTRL_DMAENABLE == 0 {
+            return None;
+        }
+        let mut next_beat_end: Option<u64> = None;
+        // Lower channels have priority
+        for channel in 0..DmacRegisters::CHANNEL_COUNT {
+            let source = self.channels[channel].trigger_source();
+            let beat_ticks = gamebuino.dma_beat_ticks(source);
+            // Set once a beat has completed here, so that the beats requested since are
+            // caught up on when advancing late
+            let mut previous_beat_end = None;
+            loop {
+                // Peripherals with room for data, such as a SERCOM with an empty data register,
+                // keep requesting transfers
+                if !self.channels[channel].pending && gamebuino.dma_request_pending(source) {
+                    let earliest = previous_beat_end.unwrap_or(gamebuino.tick_count);
+                    self.trigger_from(channel, earliest, gamebuino);
+                }
+                let state = self.channels[channel];
+                if !state.is_busy() || state.beat_end_tick > gamebuino.tick_count {
+                    break;
+                }
+
+                let block_done = self.transfer_beat(channel, gamebuino);
+                let action = state.trigger_action();
+                if action == DmacRegisters::TRIGACT_BEAT
+                    || (block_done && action != DmacRegisters::TRIGACT_TRANSACTION)
+                {
+                    self.channels[channel].pending = false;
+                    previous_beat_end = Some(state.beat_end_tick);
+                } else {
+                    self.channels[channel].beat_end_tick += beat_ticks;
+                }
+                self.write_back_count(channel, gamebuino);
+            }
+            if self.channels[channel].is_busy() {
+                let beat_end = self.channels[channel].beat_end_tick;
+                next_beat_end = Some(next_beat_end.map_or(beat_end, |tick| tick.min(beat_end)));
+            }
         }
+        next_beat_end
+    }
+
+    fn handle_event(&mut self, channel: usize, gamebuino: &mut Gamebuino) {
+        let chctrlb = self.channels[channel].chctrlb;
+        if chctrlb & DmacRegisters::CHCTRLB_EVIE == 0 {
+            return;
+        }
+        match chctrlb & DmacRegisters::CHCTRLB_EVACT_MASK {
+            DmacRegisters::EVACT_TRIG
+            | DmacRegisters::EVACT_CTRIG
+            | DmacRegisters::EVACT_CBLOCK => self.trigger(channel, gamebuino),
+            DmacRegisters::EVACT_SUSPEND => self.suspend(channel, gamebuino),
+            DmacRegisters::EVACT_RESUME => self.channels[channel].suspended = false,
+            _ => {}
+        }
+    }
+
+    /// Starts what one trigger of `channel` moves: a beat, a block or the whole transaction.
+    /// Each beat takes time, so the transfer completes over the following ticks.
+    fn trigger(&mut self, channel: usize, gamebuino: &mut Gamebuino) {
+        self.trigger_from(channel, gamebuino.tick_count, gamebuino);
+    }
+
+    /// `trigger`, with the first beat starting no earlier than `earliest`, which may be in the
+    /// past for a trigger that followed straight on from the previous beat.
+    fn trigger_from(&mut self, channel: usize, earliest: u64, gamebuino: &mut Gamebuino) {
+        let state = &mut self.channels[channel];
+        if state.pending || !state.is_ready() {
+            return;
+        }
+        state.pending = true;
+        // Back-to-back beats follow on from the end of the previous one
+        let start = state.beat_end_tick.max(earliest);
+        state.beat_end_tick = start + gamebuino.dma_beat_ticks(state.trigger_source());
+    }
+
+    fn write_back_count(&self, channel: usize, gamebuino: &mut Gamebuino) {
+        if let Some(descriptor) = self.channels[channel].descriptor {
+            let remaining = descriptor.btcnt - self.channels[channel].beat;
+            gamebuino.store_half_word(self.write_back_address(channel) + 0x02, remaining as u32);
+        }
+    }
+
+    /// Moves one beat. Returns whether it completed the block.
+    fn transfer_beat(&mut self, channel: usize, gamebuino: &mut Gamebuino) -> bool {
+        let descriptor = match self.channels[channel].descriptor {
+            Some(descriptor) => descriptor,
+            None => return false,
+        };
+        let beat = self.channels[channel].beat;
+        let source =
+            descriptor.beat_address(descriptor.srcaddr, DmacDescriptor::SRCINC, true, beat);
+        let destination =
+            descriptor.beat_address(descriptor.dstaddr, DmacDescriptor::DSTINC, false, beat);
+        match descriptor.beat_size() {
+            1 => gamebuino.store_byte(destination, gamebuino.load_byte(source) as u32),
+            2 => gamebuino.store_half_word(destination, gamebuino.load_half_word(source) as u32),
+            _ => gamebuino.store_word(destination, gamebuino.load_word(source)),
+        }
+
+        if descriptor.event_output() == DmacDescriptor::EVOSEL_BEAT {
+            self.output_event(channel, gamebuino);
+        }
+
+        self.channels[channel].beat += 1;
+        if self.channels[channel].beat < descriptor.btcnt {
+            return false;
+        }
+        self.complete_block(channel, descriptor, gamebuino);
+        true
+    }
+
+    fn complete_block(
+        &mut self,
+        channel: usize,
+        descriptor: DmacDescriptor,
+        gamebuino: &mut Gamebuino,
+    ) {
+        if descriptor.event_output() == DmacDescriptor::EVOSEL_BLOCK {
+            self.output_event(channel, gamebuino);
+        }
+
+        let block_action = descriptor.block_action();
+        if block_action & DmacDescriptor::BLOCKACT_INT != 0 {
+            self.raise(channel, DmacRegisters::CHINTFLAG_TCMPL, gamebuino);
+        }
+
+        self.channels[channel].beat = 0;
+        if descriptor.descaddr == 0 {
+            // The last block always flags the end of the transfer
+            self.channels[channel].descriptor = None;
+            self.channels[channel].enabled = false;
+            let mut written_back = descriptor;
+            written_back.btcnt = 0;
+            written_back.write(self.write_back_address(channel), gamebuino);
+            self.raise(channel, DmacRegisters::CHINTFLAG_TCMPL, gamebuino);
+        } else {
+            self.load_descriptor(channel, descriptor.descaddr, gamebuino);
+            if block_action & DmacDescriptor::BLOCKACT_SUSPEND != 0 {
+                self.suspend(channel, gamebuino);
+            }
+        }
+    }
+
+    fn load_descriptor(&mut self, channel: usize, address: u32, gamebuino: &mut Gamebuino) {
+        let descriptor = DmacDescriptor::read(address, gamebuino);
+        descriptor.write(self.write_back_address(channel), gamebuino);
+        if descriptor.is_valid() {
+            self.channels[channel].descriptor = Some(descriptor);
+        } else {
+            // Fetch error: the channel stops
+            self.channels[channel].descriptor = None;
+            self.channels[channel].enabled = false;
+            self.raise(channel, DmacRegisters::CHINTFLAG_TERR, gamebuino);
+        }
+    }
+
+    fn suspend(&mut self, channel: usize, gamebuino: &mut Gamebuino) {
+        if self.channels[channel].enabled && !self.channels[channel].suspended {
+            self.channels[channel].suspended = true;
+            self.raise(channel, DmacRegisters::CHINTFLAG_SUSP, gamebuino);
+        }
+    }
+
+    fn raise(&mut self, channel: usize, flag: u8, gamebuino: &mut Gamebuino) {
+        self.channels[channel].intflag |= flag;
+        if self.channels[channel].intenset & flag != 0 {
+            gamebuino.dmac_interrupt();
+        }
+    }
+
+    fn output_event(&mut self, channel: usize, gamebuino: &mut Gamebuino) {
+        if channel < DmacRegisters::EVENT_CHANNEL_COUNT
+            && self.channels[channel].chctrlb & DmacRegisters::CHCTRLB_EVOE != 0
+        {
+            gamebuino.generate_event(EvsysRegisters::GEN_DMAC_CH0 + channel as u8);
+        }
+    }
+
+    fn write_back_address(&self, channel: usize) -> u32 {
+        self.wrb_address + channel as u32 * DmacRegisters::DESCRIPTOR_SIZE
+    }
+
+    /// The words at CHCTRLA, CHCTRLB and CHINTENCLR, the last up to CHSTATUS, as they read with
+    /// CHID set to `channel`.
+    pub fn channel_registers(&self, channel: u8) -> [u32; 3] {
+        let mut registers = *self;
+        registers.selected_channel_id = channel;
+        [
+            DmacRegisters::CHCTRLA_OFFSET,
+            DmacRegisters::CHCTRLB_OFFSET,
+            DmacRegisters::CHINTENCLR_OFFSET,
+        ]
+        .map(|offset| registers.handle_read_word(offset))
+    }
+
+    fn selected_channel(&mut self) -> Option<&mut DmacChannel> {
+        self.channels.get_mut(self.selected_channel_id as usize)
+    }
+
+    fn write_chctrla(&mut self, value: u8, gamebuino: &mut Gamebuino) {
+        let channel = self.selected_channel_id as usize;
+        if channel >= DmacRegisters::CHANNEL_COUNT {
+            return;
+        }
+        if value & DmacRegisters::CHCTRLA_SWRST != 0 {
+            self.channels[channel] = DmacChannel::new();
+        } else if value & DmacRegisters::CHCTRLA_ENABLE == 0 {
+            self.channels[channel].enabled = false;
+            self.channels[channel].descriptor = None;
+        } else if !self.channels[channel].enabled {
+            self.channels[channel].enabled = true;
+            self.channels[channel].suspended = false;
+            self.channels[channel].pending = false;
+            self.channels[channel].beat = 0;
+            let first = self.base_address + channel as u32 * DmacRegisters::DESCRIPTOR_SIZE;
+            self.load_descriptor(channel, first, gamebuino);
+        }
+    }
+
+    fn write_chctrlb(&mut self, value: u32, gamebuino: &mut Gamebuino) {
+        let channel = self.selected_channel_id as usize;
+        if channel >= DmacRegisters::CHANNEL_COUNT {
+            return;
+        }
+        self.channels[channel].chctrlb = value & !DmacRegisters::CHCTRLB_CMD_MASK;
+        match (value & DmacRegisters::CHCTRLB_CMD_MASK) >> DmacRegisters::CHCTRLB_CMD_SHIFT {
+            DmacRegisters::CMD_SUSPEND => self.suspend(channel, gamebuino),
+            DmacRegisters::CMD_RESUME => self.channels[channel].suspended = false,
+            _ => {}
+        }
+    }
+
+    /// INTPEND: the lowest channel with an interrupt flag set, and its flags.
+    fn interrupt_pending(&self) -> u16 {
+        match self
+            .channels
+            .iter()
+            .position(|channel| channel.intflag != 0)
+        {
+            Some(id) => {
+                let channel = &self.channels[id];
+                let busy = if channel.is_busy() {
+                    DmacRegisters::INTPEND_BUSY
+                } else {
+                    0
+                };
+                id as u16 | (channel.intflag as u16) << 8 | busy
+            }
+            None => 0,
+        }
+    }
+
+    fn channel_mask(&self, predicate: impl Fn(&DmacChannel) -> bool) -> u32 {
+        self.channels
+            .iter()
+            .enumerate()
+            .filter(|(_, channel)| predicate(channel))
+            .fold(0, |mask, (id, _)| mask | 1 << id)
     }
 }
 
 impl Peripheral for DmacRegisters {
-    fn handle_write_word(&mut self, offset: u32, value: u32, _gamebuino: &mut Gamebuino) {
+    fn handle_write_word(&mut self, offset: u32, value: u32, gamebuino: &mut Gamebuino) {
         // log!("dmac write word {:x}", offset);
         match offset {
+            DmacRegisters::CTRL_OFFSET => {
+                if value as u16 & DmacRegisters::CTRL_SWRST != 0 {
+                    *self = DmacRegisters::new();
+                } else {
+                    self.ctrl = value as u16;
+                }
+            }
+            DmacRegisters::SWTRIGCTRL_OFFSET => {
+                for channel in 0..DmacRegisters::CHANNEL_COUNT {
+                    if value & (1 << channel) != 0 {
+                        self.trigger(channel, gamebuino);
+                    }
+                }
+            }
+            DmacRegisters::INTPEND_OFFSET => {
+                // Writing the flags of channel ID clears them
+                if let Some(channel) = self.channels.get_mut((value & 0xf) as usize) {
+                    channel.intflag &= !((value >> 8) as u8 & 0b111);
+                }
+            }
             DmacRegisters::BASEADDR_OFFSET => {
                 self.base_address = value;
             }
             DmacRegisters::WRBADDR_OFFSET => {
                 self.wrb_address = value;
             }
-            _ => {}
+            DmacRegisters::CHCTRLB_OFFSET => self.write_chctrlb(value, gamebuino),
+            _ => {
+                for i in 0..4 {
+                    self.handle_write_byte(offset + i, (value >> (8 * i)) as u8, gamebuino);
+                }
+            }
         }
     }
 
     fn handle_write_byte(&mut self, offset: u32, value: u8, gamebuino: &mut Gamebuino) {
         match offset {
+            DmacRegisters::CTRL_OFFSET => {
+                let ctrl = (self.ctrl & 0xff00) | value as u16;
+                self.handle_write_word(offset, ctrl as u32, gamebuino);
+            }
             DmacRegisters::CHID_OFFSET => {
                 self.selected_channel_id = value;
             }
-            DmacRegisters::CHCTRLA_OFFSET => {
-                if value == 0b10 {
-                    if self.descriptor == 0 {
-                        self.descriptor =
-                            self.base_address + self.selected_channel_id as u32 * 0x10;
-                    }
-
-                    let _btctrl = gamebuino.fetch_half_word(self.descriptor);
-                    let btcnt = gamebuino.fetch_half_word(self.descriptor + 0x02) as u32;
-                    let srcaddr = gamebuino.fetch_word(self.descriptor + 0x04);
-                    let dstaddr = gamebuino.fetch_word(self.descriptor + 0x08);
-                    let descaddr = gamebuino.fetch_word(self.descriptor + 0x0C);
-
-                    for i in 0..btcnt {
-                        gamebuino
-                            .write_byte(dstaddr, gamebuino.fetch_byte(srcaddr + i - btcnt) as u32);
-                    }
-
-                    self.descriptor = descaddr;
-
-                    gamebuino.dmac_interrupt();
+            DmacRegisters::CHCTRLA_OFFSET => self.write_chctrla(value, gamebuino),
+            DmacRegisters::CHCTRLB_OFFSET => {
+                if let Some(chctrlb) = self.selected_channel().map(|channel| channel.chctrlb) {
+                    let chctrlb = (chctrlb & !0xff) | value as u32;
+                    self.write_chctrlb(chctrlb, gamebuino);
+                }
+            }
+            DmacRegisters::CHINTENCLR_OFFSET => {
+                if let Some(channel) = self.selected_channel() {
+                    channel.intenset &= !value;
+                }
+            }
+            DmacRegisters::CHINTENSET_OFFSET => {
+                if let Some(channel) = self.selected_channel() {
+                    channel.intenset |= value;
+                }
+            }
+            DmacRegisters::CHINTFLAG_OFFSET => {
+                if let Some(channel) = self.selected_channel() {
+                    channel.intflag &= !value;
                 }
             }
             _ => {}
         }
     }
 
+    fn handle_write_half_word(&mut self, offset: u32, value: u16, gamebuino: &mut Gamebuino) {
+        match offset {
+            DmacRegisters::CTRL_OFFSET | DmacRegisters::INTPEND_OFFSET => {
+                self.handle_write_word(offset, value as u32, gamebuino)
+            }
+            _ => {
+                self.handle_write_byte(offset, value as u8, gamebuino);
+                self.handle_write_byte(offset + 1, (value >> 8) as u8, gamebuino);
+            }
+        }
+    }
+
     fn handle_read_word(&self, offset: u32) -> u32 {
         match offset {
-            DmacRegisters::CHINTFLAG_OFFSET => 0b010, // TCMPL
-            _ => 0,
+            DmacRegisters::CTRL_OFFSET => self.ctrl as u32,
+            DmacRegisters::INTPEND_OFFSET => self.interrupt_pending() as u32,
+            DmacRegisters::INTSTATUS_OFFSET => {
+                self.channel_mask(|channel| channel.intflag & channel.intenset != 0)
+            }
+            DmacRegisters::BUSYCH_OFFSET => self.channel_mask(DmacChannel::is_busy),
+            DmacRegisters::BASEADDR_OFFSET => self.base_address,
+            DmacRegisters::WRBADDR_OFFSET => self.wrb_address,
+            DmacRegisters::CHCTRLB_OFFSET => self
+                .channels
+                .get(self.selected_channel_id as usize)
+                .map_or(0, |channel| channel.chctrlb),
+            _ => (0..4)
+                .map(|i| (self.handle_read_byte(offset + i) as u32) << (8 * i))
+                .fold(0, |word, byte| word | byte),
         }
     }
 
     fn handle_read_byte(&self, offset: u32) -> u8 {
+        let channel = self.channels.get(self.selected_channel_id as usize);
         match offset {
-            DmacRegisters::CHINTFLAG_OFFSET => 0b010, // TCMPL
+            DmacRegisters::CTRL_OFFSET => self.ctrl as u8,
+            DmacRegisters::CHID_OFFSET => self.selected_channel_id,
+            DmacRegisters::CHCTRLA_OFFSET => channel.map_or(0, |channel| {
+                if channel.enabled {
+                    DmacRegisters::CHCTRLA_ENABLE
+                } else {
+                    0
+                }
+            }),
+            DmacRegisters::CHCTRLB_OFFSET => channel.map_or(0, |channel| channel.chctrlb as u8),
+            DmacRegisters::CHINTENCLR_OFFSET | DmacRegisters::CHINTENSET_OFFSET => {
+                channel.map_or(0, |channel| channel.intenset)
+            }
+            DmacRegisters::CHINTFLAG_OFFSET => channel.map_or(0, |channel| channel.intflag),
+            DmacRegisters::CHSTATUS_OFFSET => channel.map_or(0, DmacChannel::status),
             _ => 0,
         }
     }
@@ -209,12 +753,28 @@ impl Peripheral for PortRegisters {
 pub struct SercomRegisters {
     pub data: u8,
     pub sent: Option<u8>,
+    pub address: Option<u8>,
+    pub command: Option<u8>,
+    pub intflag: u8,
+    pub status: u16,
+    ctrla: u32,
+    ctrlb: u32,
+    baud: u32,
 }
 
 impl SercomRegisters {
+    const CTRLA_OFFSET: u32 = 0x00;
+    const CTRLB_OFFSET: u32 = 0x04;
+    const CTRLB_CMD_OFFSET: u32 = 0x06;
+    const BAUD_OFFSET: u32 = 0x0C;
     const INTFLAG_OFFSET: u32 = 0x18;
+    const STATUS_OFFSET: u32 = 0x1A;
+    const ADDR_OFFSET: u32 = 0x24;
     const DATA_OFFSET: u32 = 0x28;
     const SERCOM0_ADDR: u32 = 0x42000800;
+    pub const SERCOM3_START_ADDR: u32 = SercomRegisters::SERCOM0_ADDR + 3 * 0x400;
+    pub const SERCOM3_END_ADDR: u32 =
+        SercomRegisters::SERCOM3_START_ADDR + SercomRegisters::DATA_OFFSET;
     pub const SERCOM4_START_ADDR: u32 = SercomRegisters::SERCOM0_ADDR + 4 * 0x400;
     pub const SERCOM4_END_ADDR: u32 =
         SercomRegisters::SERCOM4_START_ADDR + SercomRegisters::DATA_OFFSET;
@@ -222,17 +782,73 @@ impl SercomRegisters {
     pub const SERCOM5_END_ADDR: u32 =
         SercomRegisters::SERCOM5_START_ADDR + SercomRegisters::DATA_OFFSET;
 
+    // GCLK0, which clocks every SERCOM on the Gamebuino
+    const REFERENCE_CLOCK: u64 = 48000000;
+
+    const CTRLA_SWRST: u32 = 1 << 0;
+    const CTRLA_MODE_I2C_MASTER: u32 = 0x5 << 2;
+    const CTRLA_MODE_MASK: u32 = 0b111 << 2;
+    const CTRLB_CMD_MASK: u32 = 0b11 << 16;
+    pub const INTFLAG_DRE: u8 = 1 << 0; // SPI data register empty
+    pub const INTFLAG_RXC: u8 = 1 << 2; // SPI receive complete
+    pub const INTFLAG_MB: u8 = 1 << 0; // Master on bus
+    pub const INTFLAG_SB: u8 = 1 << 1; // Slave on bus
+    pub const STATUS_RXNACK: u16 = 1 << 2;
+    pub const STATUS_BUSSTATE_IDLE: u16 = 0b01 << 4;
+    pub const STATUS_BUSSTATE_OWNER: u16 = 0b10 << 4;
+
     pub fn new() -> SercomRegisters {
         SercomRegisters {
             data: 0x80,
             sent: None,
+            address: None,
+            command: None,
+            intflag: 0b00000111, // RXC, TXC, DRE
+            status: SercomRegisters::STATUS_BUSSTATE_IDLE,
+            ctrla: 0,
+            ctrlb: 0,
+            baud: 0,
+        }
+    }
+
+    pub fn is_i2c_master(&self) -> bool {
+        self.ctrla & SercomRegisters::CTRLA_MODE_MASK == SercomRegisters::CTRLA_MODE_I2C_MASTER
+    }
+
+    /// How many ticks it takes to shift a byte out in SPI mode, at a bit rate of
+    /// fREF / (2 * (BAUD + 1)).
+    pub fn spi_byte_ticks(&self, ticks_per_second: u64) -> u64 {
+        let bits_per_second =
+            SercomRegisters::REFERENCE_CLOCK / (2 * ((self.baud & 0xff) as u64 + 1));
+        (8 * ticks_per_second).div_ceil(bits_per_second)
+    }
+
+    fn write_ctrlb(&mut self, value: u32) {
+        // CMD triggers a bus operation and always reads back as zero
+        let command = ((value & SercomRegisters::CTRLB_CMD_MASK) >> 16) as u8;
+        if command != 0 {
+            self.command = Some(command);
         }
+        self.ctrlb = value & !SercomRegisters::CTRLB_CMD_MASK;
     }
 }
 
 impl Peripheral for SercomRegisters {
     fn handle_write_word(&mut self, offset: u32, value: u32, _gamebuino: &mut Gamebuino) {
         match offset {
+            SercomRegisters::CTRLA_OFFSET => {
+                // Reset completes immediately
+                self.ctrla = value & !SercomRegisters::CTRLA_SWRST;
+            }
+            SercomRegisters::CTRLB_OFFSET => {
+                self.write_ctrlb(value);
+            }
+            SercomRegisters::BAUD_OFFSET => {
+                self.baud = value;
+            }
+            SercomRegisters::ADDR_OFFSET => {
+                self.address = Some(value as u8);
+            }
             SercomRegisters::DATA_OFFSET => {
                 self.data = 0x80;
                 self.sent = Some(value as u8);
@@ -243,6 +859,16 @@ impl Peripheral for SercomRegisters {
 
     fn handle_write_byte(&mut self, offset: u32, value: u8, _gamebuino: &mut Gamebuino) {
         match offset {
+            SercomRegisters::CTRLB_CMD_OFFSET => {
+                let ctrlb = (self.ctrlb & !(0xff << 16)) | (value as u32) << 16;
+                self.write_ctrlb(ctrlb);
+            }
+            SercomRegisters::BAUD_OFFSET => {
+                self.baud = (self.baud & !0xff) | value as u32;
+            }
+            SercomRegisters::ADDR_OFFSET => {
+                self.address = Some(value);
+            }
             SercomRegisters::DATA_OFFSET => {
                 self.data = 0x80;
                 self.sent = Some(value);
@@ -253,7 +879,11 @@ impl Peripheral for SercomRegisters {
 
     fn handle_read_word(&self, offset: u32) -> u32 {
         match offset {
-            SercomRegisters::INTFLAG_OFFSET => 0b00000111 as u32, // RXC, TXC, DRE
+            SercomRegisters::CTRLA_OFFSET => self.ctrla,
+            SercomRegisters::CTRLB_OFFSET => self.ctrlb,
+            SercomRegisters::BAUD_OFFSET => self.baud,
+            SercomRegisters::INTFLAG_OFFSET => self.intflag as u32,
+            SercomRegisters::STATUS_OFFSET => self.status as u32,
             SercomRegisters::DATA_OFFSET => self.data as u32,
             _ => 0,
         }
@@ -261,17 +891,993 @@ impl Peripheral for SercomRegisters {
 
     fn handle_read_byte(&self, offset: u32) -> u8 {
         match offset {
-            SercomRegisters::INTFLAG_OFFSET => 0b00000111, // RXC, TXC, DRE
+            SercomRegisters::CTRLA_OFFSET => self.ctrla as u8,
+            SercomRegisters::CTRLB_CMD_OFFSET => (self.ctrlb >> 16) as u8,
+            SercomRegisters::BAUD_OFFSET => self.baud as u8,
+            SercomRegisters::INTFLAG_OFFSET => self.intflag,
+            SercomRegisters::STATUS_OFFSET => self.status as u8,
             SercomRegisters::DATA_OFFSET => self.data,
             _ => 0,
         }
     }
 }
 
-pub struct TcRegisters {
+#[derive(Clone, Copy)]
+pub struct AdcRegisters {
+    ctrlb: u16,
+    software_conversion: bool,
+    evctrl: u8,
+    intenset: u8,
+    intflag: u8,
+    result: u16,
+}
+
+impl AdcRegisters {
+    const CTRLB_OFFSET: u32 = 0x04;
+    const SWTRIG_OFFSET: u32 = 0x0C;
+    const EVCTRL_OFFSET: u32 = 0x14;
+    const INTENCLR_OFFSET: u32 = 0x16;
+    const INTENSET_OFFSET: u32 = 0x17;
+    const INTFLAG_OFFSET: u32 = 0x18;
+    const STATUS_OFFSET: u32 = 0x19;
+    const RESULT_OFFSET: u32 = 0x1A;
+    pub const ADC_START_ADDR: u32 = 0x42004000;
+    pub const ADC_END_ADDR: u32 = AdcRegisters::ADC_START_ADDR + AdcRegisters::RESULT_OFFSET + 1;
+
+    const CTRLB_FREERUN: u16 = 1 << 2;
+    const SWTRIG_START: u8 = 1 << 1;
+    const EVCTRL_STARTEI: u8 = 1 << 0;
+    const EVCTRL_RESRDYEO: u8 = 1 << 4;
+    const INTFLAG_RESRDY: u8 = 1 << 0;
+
+    pub fn new() -> AdcRegisters {
+        AdcRegisters {
+            ctrlb: 0,
+            software_conversion: false,
+            evctrl: 0,
+            intenset: 0,
+            intflag: 0,
+            result: 0,
+        }
+    }
+
+    /// START event input. Returns whether it started a conversion.
+    pub fn handle_start_event(&mut self) -> bool {
+        let enabled = self.evctrl & AdcRegisters::EVCTRL_STARTEI != 0;
+        if enabled {
+            self.start_conversion();
+        }
+        enabled
+    }
+
+    /// Whether a software trigger completed a conversion since the last call.
+    pub fn take_software_conversion(&mut self) -> bool {
+        std::mem::take(&mut self.software_conversion)
+    }
+
+    pub fn outputs_result_event(&self) -> bool {
+        self.evctrl & AdcRegisters::EVCTRL_RESRDYEO != 0
+    }
+
+    // Nothing is wired to the analog inputs, so conversions complete immediately with noise,
+    // which is what games sample to seed their random number generators
+    fn start_conversion(&mut self) {
+        self.result = AdcRegisters::noise();
+        self.intflag |= AdcRegisters::INTFLAG_RESRDY;
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn noise() -> u16 {
+        (js_sys::Math::random() * (0xffff as f64)).floor() as u16
+    }
+
+    // Native builds, such as a GDB server, have no JavaScript to ask
+    #[cfg(not(target_arch = "wasm32"))]
+    fn noise() -> u16 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.subsec_nanos() as u16)
+    }
+
+    fn is_free_running(&self) -> bool {
+        self.ctrlb & AdcRegisters::CTRLB_FREERUN != 0
+    }
+}
+
+impl Peripheral for AdcRegisters {
+    fn handle_write_word(&mut self, offset: u32, value: u32, gamebuino: &mut Gamebuino) {
+        match offset {
+            AdcRegisters::CTRLB_OFFSET => self.ctrlb = value as u16,
+            _ => self.handle_write_byte(offset, value as u8, gamebuino),
+        }
+    }
+
+    fn handle_write_byte(&mut self, offset: u32, value: u8, _gamebuino: &mut Gamebuino) {
+        match offset {
+            AdcRegisters::CTRLB_OFFSET => self.ctrlb = (self.ctrlb & 0xff00) | value as u16,
+            AdcRegisters::SWTRIG_OFFSET if value & AdcRegisters::SWTRIG_START != 0 => {
+                self.start_conversion();
+                self.software_conversion = true;
+            }
+            AdcRegisters::EVCTRL_OFFSET => self.evctrl = value,
+            AdcRegisters::INTENCLR_OFFSET => self.intenset &= !value,
+            AdcRegisters::INTENSET_OFFSET => self.intenset |= value,
+            AdcRegisters::INTFLAG_OFFSET => self.intflag &= !value,
+            _ => {}
+        }
+    }
+
+    fn handle_write_half_word(&mut self, offset: u32, value: u16, gamebuino: &mut Gamebuino) {
+        match offset {
+            AdcRegisters::CTRLB_OFFSET => self.ctrlb = value,
+            _ => {
+                self.handle_write_byte(offset, value as u8, gamebuino);
+                self.handle_write_byte(offset + 1, (value >> 8) as u8, gamebuino);
+            }
+        }
+    }
+
+    fn handle_read_word(&self, offset: u32) -> u32 {
+        match offset {
+            AdcRegisters::CTRLB_OFFSET => self.ctrlb as u32,
+            AdcRegisters::RESULT_OFFSET if self.is_free_running() => AdcRegisters::noise() as u32,
+            AdcRegisters::RESULT_OFFSET => self.result as u32,
+            _ => self.handle_read_byte(offset) as u32,
+        }
+    }
+
+    fn handle_read_byte(&self, offset: u32) -> u8 {
+        match offset {
+            AdcRegisters::CTRLB_OFFSET => self.ctrlb as u8,
+            AdcRegisters::EVCTRL_OFFSET => self.evctrl,
+            AdcRegisters::INTENCLR_OFFSET | AdcRegisters::INTENSET_OFFSET => self.intenset,
+            // A free-running ADC always has a fresh result
+            AdcRegisters::INTFLAG_OFFSET if self.is_free_running() => {
+                self.intflag | AdcRegisters::INTFLAG_RESRDY
+            }
+            AdcRegisters::INTFLAG_OFFSET => self.intflag,
+            AdcRegisters::STATUS_OFFSET => 0, // never SYNCBUSY
+            AdcRegisters::RESULT_OFFSET => self.handle_read_word(offset) as u8,
+            _ => 0,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct DacRegisters {
+    ctrla: u8,
+    ctrlb: u8,
+    evctrl: u8,
+    intenset: u8,
+    intflag: u8,
+    data: u16,
+    databuf: u16,
+    databuf_full: bool,
+    pub output: u16,
+}
+
+impl DacRegisters {
+    const CTRLA_OFFSET: u32 = 0x00;
+    const CTRLB_OFFSET: u32 = 0x01;
+    const EVCTRL_OFFSET: u32 = 0x02;
+    const INTENCLR_OFFSET: u32 = 0x04;
+    const INTENSET_OFFSET: u32 = 0x05;
+    const INTFLAG_OFFSET: u32 = 0x06;
+    const STATUS_OFFSET: u32 = 0x07;
+    const DATA_OFFSET: u32 = 0x08;
+    const DATABUF_OFFSET: u32 = 0x0C;
+    pub const DAC_START_ADDR: u32 = 0x42004800;
+    pub const DAC_END_ADDR: u32 = DacRegisters::DAC_START_ADDR + DacRegisters::DATABUF_OFFSET + 1;
+
+    const CTRLA_SWRST: u8 = 1 << 0;
+    const CTRLA_ENABLE: u8 = 1 << 1;
+    const CTRLB_LEFTADJ: u8 = 1 << 2;
+    const EVCTRL_STARTEI: u8 = 1 << 0;
+    const EVCTRL_EMPTYEO: u8 = 1 << 1;
+    const INTFLAG_UNDERRUN: u8 = 1 << 0;
+    const INTFLAG_EMPTY: u8 = 1 << 1;
+    const DATA_MIDPOINT: u16 = 0x200;
+
+    pub fn new() -> DacRegisters {
+        DacRegisters {
+            ctrla: 0,
+            ctrlb: 0,
+            evctrl: 0,
+            intenset: 0,
+            intflag: 0,
+            data: 0,
+            databuf: 0,
+            databuf_full: false,
+            output: DacRegisters::DATA_MIDPOINT,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.ctrla & DacRegisters::CTRLA_ENABLE != 0
+    }
+
+    /// Whether DATABUF can take another sample, which requests a DMA transfer.
+    pub fn is_buffer_empty(&self) -> bool {
+        self.is_enabled() && !self.databuf_full
+    }
+
+    /// Whether conversions start on the START event rather than on writes to DATA.
+    pub fn is_event_driven(&self) -> bool {
+        self.evctrl & DacRegisters::EVCTRL_STARTEI != 0
+    }
+
+    /// Start conversion event: moves DATABUF to DATA, or flags an underrun if the buffer wasn't
+    /// refilled since the last event. Returns whether this raised an enabled interrupt and
+    /// whether it outputs the EMPTY event.
+    pub fn start_conversion(&mut self) -> (bool, bool) {
+        let flag = if self.databuf_full {
+            self.databuf_full = false;
+            self.set_data(self.databuf);
+            DacRegisters::INTFLAG_EMPTY
+        } else {
+            DacRegisters::INTFLAG_UNDERRUN
+        };
+        self.intflag |= flag;
+        let empty_event =
+            flag == DacRegisters::INTFLAG_EMPTY && self.evctrl & DacRegisters::EVCTRL_EMPTYEO != 0;
+        (self.intenset & flag != 0, empty_event)
+    }
+
+    fn set_data(&mut self, value: u16) {
+        self.data = value;
+        self.output = if self.ctrlb & DacRegisters::CTRLB_LEFTADJ != 0 {
+            value >> 6
+        } else {
+            value & 0x3ff
+        };
+    }
+
+    fn write_databuf(&mut self, value: u16) {
+        if self.is_event_driven() {
+            self.databuf = value;
+            self.databuf_full = true;
+            self.intflag &= !DacRegisters::INTFLAG_EMPTY;
+        } else {
+            // Nothing would ever move the buffer without the event, so convert right away
+            self.set_data(value);
+        }
+    }
+
+    fn write_register_byte(&mut self, offset: u32, value: u8) {
+        match offset {
+            DacRegisters::CTRLA_OFFSET => {
+                if value & DacRegisters::CTRLA_SWRST != 0 {
+                    *self = DacRegisters::new();
+                } else {
+                    self.ctrla = value;
+                }
+            }
+            DacRegisters::CTRLB_OFFSET => self.ctrlb = value,
+            DacRegisters::EVCTRL_OFFSET => self.evctrl = value,
+            DacRegisters::INTENCLR_OFFSET => self.intenset &= !value,
+            DacRegisters::INTENSET_OFFSET => self.intenset |= value,
+            DacRegisters::INTFLAG_OFFSET => self.intflag &= !value,
+            _ => {}
+        }
+    }
+}
+
+impl Peripheral for DacRegisters {
+    fn handle_write_word(&mut self, offset: u32, value: u32, _gamebuino: &mut Gamebuino) {
+        match offset {
+            DacRegisters::DATA_OFFSET => self.set_data(value as u16),
+            DacRegisters::DATABUF_OFFSET => self.write_databuf(value as u16),
+            _ => {
+                for i in 0..4 {
+                    self.write_register_byte(offset + i, (value >> (8 * i)) as u8);
+                }
+            }
+        }
+    }
+
+    fn handle_write_byte(&mut self, offset: u32, value: u8, _gamebuino: &mut Gamebuino) {
+        match offset {
+            DacRegisters::DATA_OFFSET => self.set_data((self.data & 0xff00) | value as u16),
+            DacRegisters::DATABUF_OFFSET => {
+                self.write_databuf((self.databuf & 0xff00) | value as u16)
+            }
+            _ => self.write_register_byte(offset, value),
+        }
+    }
+
+    fn handle_write_half_word(&mut self, offset: u32, value: u16, gamebuino: &mut Gamebuino) {
+        match offset {
+            DacRegisters::DATA_OFFSET => self.set_data(value),
+            DacRegisters::DATABUF_OFFSET => self.write_databuf(value),
+            _ => {
+                self.handle_write_byte(offset, value as u8, gamebuino);
+                self.handle_write_byte(offset + 1, (value >> 8) as u8, gamebuino);
+            }
+        }
+    }
+
+    fn handle_read_word(&self, offset: u32) -> u32 {
+        match offset {
+            DacRegisters::DATA_OFFSET => self.data as u32,
+            DacRegisters::DATABUF_OFFSET => self.databuf as u32,
+            _ => (0..4)
+                .map(|i| (self.handle_read_byte(offset + i) as u32) << (8 * i))
+                .fold(0, |word, byte| word | byte),
+        }
+    }
+
+    fn handle_read_byte(&self, offset: u32) -> u8 {
+        match offset {
+            DacRegisters::CTRLA_OFFSET => self.ctrla,
+            DacRegisters::CTRLB_OFFSET => self.ctrlb,
+            DacRegisters::EVCTRL_OFFSET => self.evctrl,
+            DacRegisters::INTENCLR_OFFSET | DacRegisters::INTENSET_OFFSET => self.intenset,
+            DacRegisters::INTFLAG_OFFSET => self.intflag,
+            DacRegisters::STATUS_OFFSET => 0, // never SYNCBUSY
+            DacRegisters::DATA_OFFSET => self.data as u8,
+            DacRegisters::DATABUF_OFFSET => self.databuf as u8,
+            _ => 0,
+        }
+    }
+}
+
+/// RTC in mode 2, a clock/calendar counting seconds.
+#[derive(Clone, Copy)]
+pub struct RtcRegisters {
+    ctrl: u16,
+    evctrl: u16,
+    intenset: u8,
+    intflag: u8,
+    clock: u32,
+    alarm: u32,
+    mask: u8,
 }
 
+impl RtcRegisters {
+    const CTRL_OFFSET: u32 = 0x00;
+    const EVCTRL_OFFSET: u32 = 0x04;
+    const INTENCLR_OFFSET: u32 = 0x06;
+    const INTENSET_OFFSET: u32 = 0x07;
+    const INTFLAG_OFFSET: u32 = 0x08;
+    const STATUS_OFFSET: u32 = 0x0A;
+    const CLOCK_OFFSET: u32 = 0x10;
+    const ALARM_OFFSET: u32 = 0x18;
+    const MASK_OFFSET: u32 = 0x1C;
+    pub const RTC_START_ADDR: u32 = 0x40001400;
+    pub const RTC_END_ADDR: u32 = RtcRegisters::RTC_START_ADDR + RtcRegisters::MASK_OFFSET;
+
+    const CTRL_SWRST: u16 = 1 << 0;
+    const CTRL_ENABLE: u16 = 1 << 1;
+    const CTRL_MODE_MASK: u16 = 0b11 << 2;
+    const CTRL_MODE_CLOCK: u16 = 0x2 << 2;
+    const CTRL_CLKREP: u16 = 1 << 6;
+    const CTRL_MATCHCLR: u16 = 1 << 7;
+    const CTRL_PRESCALER_SHIFT: u16 = 8;
+    const EVCTRL_ALARMEO: u16 = 1 << 8;
+    const EVCTRL_OVFEO: u16 = 1 << 15;
+    pub const INTFLAG_ALARM: u8 = 1 << 0;
+    pub const INTFLAG_OVF: u8 = 1 << 7;
+
+    // GCLK_RTC, the 32.768 kHz oscillator divided by 32, as the Arduino core sets it up
+    const INPUT_CLOCK: u64 = 1024;
+    // The CLOCK register counts years from here, like RTCZero and the Gamebuino library
+    pub const REFERENCE_YEAR: u16 = 2000;
+
+    // CLOCK and ALARM fields, least significant first
+    const SECOND_SHIFT: u32 = 0;
+    const MINUTE_SHIFT: u32 = 6;
+    const HOUR_SHIFT: u32 = 12;
+    const DAY_SHIFT: u32 = 17;
+    const MONTH_SHIFT: u32 = 22;
+    const YEAR_SHIFT: u32 = 26;
+    const HOUR_PM: u32 = 0x10;
+    // Fields compared for each MASK.SEL, from seconds only up to the whole date and time
+    const ALARM_MASKS: [u32; 7] = [0, 0x3f, 0xfff, 0x1ffff, 0x3fffff, 0x3ffffff, 0xffffffff];
+
+    pub fn new() -> RtcRegisters {
+        RtcRegisters {
+            ctrl: 0,
+            evctrl: 0,
+            intenset: 0,
+            intflag: 0,
+            clock: RtcRegisters::pack(0, 1, 1, 0, 0, 0),
+            alarm: 0,
+            mask: 0,
+        }
+    }
+
+    /// Sets the calendar. `year` is relative to `REFERENCE_YEAR`, `hours` 0-23.
+    pub fn set_date_time(
+        &mut self,
+        year: u32,
+        month: u32,
+        day: u32,
+        hours: u32,
+        minutes: u32,
+        seconds: u32,
+    ) {
+        self.clock = RtcRegisters::pack(
+            year % 64,
+            month.clamp(1, 12),
+            day.clamp(1, 31),
+            self.encode_hour(hours % 24),
+            minutes % 60,
+            seconds % 60,
+        );
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.ctrl & RtcRegisters::CTRL_ENABLE != 0
+            && self.ctrl & RtcRegisters::CTRL_MODE_MASK == RtcRegisters::CTRL_MODE_CLOCK
+    }
+
+    /// Ticks between clock increments: the prescaler divides the 1024 Hz input clock, and
+    /// DIV1024 gives one-second steps.
+    pub fn period_ticks(&self, ticks_per_second: u64) -> u64 {
+        let prescaler = (self.ctrl >> RtcRegisters::CTRL_PRESCALER_SHIFT) & 0xf;
+        let divider = 1u64 << prescaler.min(10);
+        (ticks_per_second * divider / RtcRegisters::INPUT_CLOCK).max(1)
+    }
+
+    /// Advances the calendar a second. Returns the interrupt flags raised, and whether they
+    /// raise an enabled interrupt.
+    pub fn tick_second(&mut self) -> (u8, bool) {
+        let (mut year, mut month, mut day, hour, mut minute, mut second) = self.unpack();
+        let mut hours = self.decode_hour(hour);
+        let mut flags = 0;
+
+        second += 1;
+        if second == 60 {
+            second = 0;
+            minute += 1;
+        }
+        if minute == 60 {
+            minute = 0;
+            hours += 1;
+        }
+        if hours == 24 {
+            hours = 0;
+            day += 1;
+        }
+        if day > days_in_month(year, month) {
+            day = 1;
+            month += 1;
+        }
+        if month > 12 {
+            month = 1;
+            year += 1;
+        }
+        if year == 64 {
+            year = 0;
+            flags |= RtcRegisters::INTFLAG_OVF;
+        }
+        self.clock = RtcRegisters::pack(year, month, day, self.encode_hour(hours), minute, second);
+
+        let sel = (self.mask & 0b111) as usize;
+        let compared = RtcRegisters::ALARM_MASKS[sel.min(RtcRegisters::ALARM_MASKS.len() - 1)];
+        if compared != 0 && self.clock & compared == self.alarm & compared {
+            flags |= RtcRegisters::INTFLAG_ALARM;
+            if self.ctrl & RtcRegisters::CTRL_MATCHCLR != 0 {
+                self.clock = 0;
+            }
+        }
+
+        self.intflag |= flags;
+        (flags, self.intenset & flags != 0)
+    }
+
+    /// Whether the flags from `tick_second` output events, as (alarm, overflow).
+    pub fn events(&self, flags: u8) -> (bool, bool) {
+        (
+            flags & RtcRegisters::INTFLAG_ALARM != 0
+                && self.evctrl & RtcRegisters::EVCTRL_ALARMEO != 0,
+            flags & RtcRegisters::INTFLAG_OVF != 0 && self.evctrl & RtcRegisters::EVCTRL_OVFEO != 0,
+        )
+    }
+
+    fn pack(year: u32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> u32 {
+        year << RtcRegisters::YEAR_SHIFT
+            | month << RtcRegisters::MONTH_SHIFT
+            | day << RtcRegisters::DAY_SHIFT
+            | hour << RtcRegisters::HOUR_SHIFT
+            | minute << RtcRegisters::MINUTE_SHIFT
+            | second << RtcRegisters::SECOND_SHIFT
+    }
+
+    fn unpack(&self) -> (u32, u32, u32, u32, u32, u32) {
+        let clock = self.clock;
+        (
+            clock >> RtcRegisters::YEAR_SHIFT,
+            (clock >> RtcRegisters::MONTH_SHIFT) & 0xf,
+            (clock >> RtcRegisters::DAY_SHIFT) & 0x1f,
+            (clock >> RtcRegisters::HOUR_SHIFT) & 0x1f,
+            (clock >> RtcRegisters::MINUTE_SHIFT) & 0x3f,
+            (clock >> RtcRegisters::SECOND_SHIFT) & 0x3f,
+        )
+    }
+
+    fn is_twelve_hour(&self) -> bool {
+        self.ctrl & RtcRegisters::CTRL_CLKREP != 0
+    }
+
+    /// Converts 0-23 hours to the HOUR field, which is 1-12 with a PM bit in 12-hour mode.
+    fn encode_hour(&self, hours: u32) -> u32 {
+        if !self.is_twelve_hour() {
+            return hours;
+        }
+        let pm = if hours >= 12 {
+            RtcRegisters::HOUR_PM
+        } else {
+            0
+        };
+        match hours % 12 {
+            0 => 12 | pm,
+            hour => hour | pm,
+        }
+    }
+
+    fn decode_hour(&self, hour: u32) -> u32 {
+        if !self.is_twelve_hour() {
+            return hour;
+        }
+        let pm = if hour & RtcRegisters::HOUR_PM != 0 {
+            12
+        } else {
+            0
+        };
+        (hour & 0xf) % 12 + pm
+    }
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year & 3 == 0 => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+impl Peripheral for RtcRegisters {
+    fn handle_write_word(&mut self, offset: u32, value: u32, gamebuino: &mut Gamebuino) {
+        match offset {
+            RtcRegisters::CTRL_OFFSET => {
+                if value as u16 & RtcRegisters::CTRL_SWRST != 0 {
+                    *self = RtcRegisters::new();
+                } else {
+                    self.ctrl = value as u16;
+                }
+            }
+            RtcRegisters::EVCTRL_OFFSET => self.evctrl = value as u16,
+            RtcRegisters::CLOCK_OFFSET => self.clock = value,
+            RtcRegisters::ALARM_OFFSET => self.alarm = value,
+            _ => self.handle_write_byte(offset, value as u8, gamebuino),
+        }
+    }
+
+    fn handle_write_byte(&mut self, offset: u32, value: u8, gamebuino: &mut Gamebuino) {
+        match offset {
+            RtcRegisters::INTENCLR_OFFSET => self.intenset &= !value,
+            RtcRegisters::INTENSET_OFFSET => self.intenset |= value,
+            RtcRegisters::INTFLAG_OFFSET => self.intflag &= !value,
+            RtcRegisters::MASK_OFFSET => self.mask = value,
+            _ => {
+                // Part of a 16 or 32-bit register
+                let register = match offset {
+                    0x00..=0x01 => RtcRegisters::CTRL_OFFSET,
+                    0x04..=0x05 => RtcRegisters::EVCTRL_OFFSET,
+                    0x10..=0x13 => RtcRegisters::CLOCK_OFFSET,
+                    0x18..=0x1B => RtcRegisters::ALARM_OFFSET,
+                    _ => return,
+                };
+                let shift = 8 * (offset - register);
+                let current = self.handle_read_word(register);
+                let merged = (current & !(0xff << shift)) | (value as u32) << shift;
+                self.handle_write_word(register, merged, gamebuino);
+            }
+        }
+    }
+
+    fn handle_write_half_word(&mut self, offset: u32, value: u16, gamebuino: &mut Gamebuino) {
+        match offset {
+            RtcRegisters::CTRL_OFFSET | RtcRegisters::EVCTRL_OFFSET => {
+                self.handle_write_word(offset, value as u32, gamebuino)
+            }
+            _ => {
+                self.handle_write_byte(offset, value as u8, gamebuino);
+                self.handle_write_byte(offset + 1, (value >> 8) as u8, gamebuino);
+            }
+        }
+    }
+
+    fn handle_read_word(&self, offset: u32) -> u32 {
+        match offset {
+            RtcRegisters::CTRL_OFFSET => self.ctrl as u32,
+            RtcRegisters::EVCTRL_OFFSET => self.evctrl as u32,
+            RtcRegisters::CLOCK_OFFSET => self.clock,
+            RtcRegisters::ALARM_OFFSET => self.alarm,
+            RtcRegisters::INTENCLR_OFFSET..=RtcRegisters::STATUS_OFFSET
+            | RtcRegisters::MASK_OFFSET => self.handle_read_byte(offset) as u32,
+            _ => 0,
+        }
+    }
+
+    fn handle_read_byte(&self, offset: u32) -> u8 {
+        match offset {
+            RtcRegisters::INTENCLR_OFFSET | RtcRegisters::INTENSET_OFFSET => self.intenset,
+            RtcRegisters::INTFLAG_OFFSET => self.intflag,
+            RtcRegisters::STATUS_OFFSET => 0, // never SYNCBUSY
+            RtcRegisters::MASK_OFFSET => self.mask,
+            RtcRegisters::CLOCK_OFFSET..=0x13 | RtcRegisters::ALARM_OFFSET..=0x1B => {
+                (self.handle_read_word(offset & !3) >> (8 * (offset & 3))) as u8
+            }
+            RtcRegisters::CTRL_OFFSET..=0x01 | RtcRegisters::EVCTRL_OFFSET..=0x05 => {
+                (self.handle_read_word(offset & !1) >> (8 * (offset & 1))) as u8
+            }
+            _ => 0,
+        }
+    }
+}
+
+pub struct TcRegisters {}
+
 impl TcRegisters {
     const TC5_ADDRESS: u32 = 0x42003400;
+    pub const TC5_EVCTRL_ADDRESS: u32 = TcRegisters::TC5_ADDRESS + 0x0A;
+    pub const TC5_EVCTRL_END_ADDRESS: u32 = TcRegisters::TC5_EVCTRL_ADDRESS + 1;
     pub const TC5_CC_ADDRESS: u32 = TcRegisters::TC5_ADDRESS + 0x18;
-}
\ No newline at end of file
+
+    pub const EVCTRL_EVACT_MASK: u16 = 0b111;
+    pub const EVACT_RETRIGGER: u16 = 0x1;
+    pub const EVCTRL_TCEI: u16 = 1 << 5;
+    pub const EVCTRL_OVFEO: u16 = 1 << 8;
+}
+
+#[derive(Clone, Copy)]
+pub struct EvsysRegisters {
+    channels: [u32; EvsysRegisters::CHANNEL_COUNT],
+    users: [u8; EvsysRegisters::USER_COUNT],
+    selected_channel: u8,
+    selected_user: u8,
+    intenset: u32,
+    intflag: u32,
+    software_event: Option<u8>,
+}
+
+impl EvsysRegisters {
+    const CTRL_OFFSET: u32 = 0x00;
+    const CHANNEL_OFFSET: u32 = 0x04;
+    const USER_OFFSET: u32 = 0x08;
+    const CHSTATUS_OFFSET: u32 = 0x0C;
+    const INTENCLR_OFFSET: u32 = 0x10;
+    const INTENSET_OFFSET: u32 = 0x14;
+    const INTFLAG_OFFSET: u32 = 0x18;
+    pub const EVSYS_START_ADDR: u32 = 0x42000400;
+    pub const EVSYS_END_ADDR: u32 =
+        EvsysRegisters::EVSYS_START_ADDR + EvsysRegisters::INTFLAG_OFFSET + 3;
+
+    const CHANNEL_COUNT: usize = 12;
+    pub const USER_COUNT: usize = 32;
+    const CTRL_SWRST: u8 = 1 << 0;
+    const CHANNEL_MASK: u32 = 0xf;
+    const CHANNEL_SWEVT: u32 = 1 << 8;
+    const CHANNEL_EVGEN_SHIFT: u32 = 16;
+    const CHANNEL_EVGEN_MASK: u32 = 0x7f << EvsysRegisters::CHANNEL_EVGEN_SHIFT;
+    const CHANNEL_PATH_SHIFT: u32 = 24;
+    const CHANNEL_EDGSEL_SHIFT: u32 = 26;
+    const CHANNEL_CONFIG_MASK: u32 = EvsysRegisters::CHANNEL_EVGEN_MASK | 0b1111 << 24;
+    const PATH_ASYNCHRONOUS: u32 = 0x2;
+    const USER_MASK: u32 = 0x1f;
+    const USER_CHANNEL_SHIFT: u32 = 8;
+    const CHSTATUS_USRRDY: u32 = 0x000f00ff; // users are always ready, channels never busy
+
+    // Event generator IDs
+    pub const GEN_RTC_CMP0: u8 = 0x01;
+    pub const GEN_RTC_OVF: u8 = 0x03;
+    pub const GEN_DMAC_CH0: u8 = 0x1E;
+    pub const GEN_TC5_OVF: u8 = 0x39;
+    pub const GEN_ADC_RESRDY: u8 = 0x42;
+    pub const GEN_DAC_EMPTY: u8 = 0x47;
+
+    // Event user IDs
+    pub const USER_DMAC_CH0: u8 = 0x00;
+    pub const USER_DMAC_CH3: u8 = 0x03;
+    pub const USER_TC5: u8 = 0x14;
+    pub const USER_ADC_START: u8 = 0x17;
+    pub const USER_DAC_START: u8 = 0x1B;
+
+    pub fn new() -> EvsysRegisters {
+        EvsysRegisters {
+            channels: [0; EvsysRegisters::CHANNEL_COUNT],
+            users: [0; EvsysRegisters::USER_COUNT],
+            selected_channel: 0,
+            selected_user: 0,
+            intenset: 0,
+            intflag: 0,
+            software_event: None,
+        }
+    }
+
+    /// Passes an event from `generator` through every channel it is selected on. Returns the
+    /// users that receive it, one bit per user ID, and whether an enabled interrupt was raised.
+    pub fn route(&mut self, generator: u8) -> (u32, bool) {
+        let mut users = 0;
+        let mut interrupt = false;
+        for channel in 0..EvsysRegisters::CHANNEL_COUNT {
+            let evgen = (self.channels[channel] & EvsysRegisters::CHANNEL_EVGEN_MASK)
+                >> EvsysRegisters::CHANNEL_EVGEN_SHIFT;
+            if evgen != 0 && evgen == generator as u32 {
+                let (channel_users, channel_interrupt) = self.fire(channel);
+                users |= channel_users;
+                interrupt |= channel_interrupt;
+            }
+        }
+        (users, interrupt)
+    }
+
+    /// Routes a software event requested by the last write to CHANNEL, like `route`.
+    pub fn take_software_event(&mut self) -> Option<(u32, bool)> {
+        self.software_event
+            .take()
+            .map(|channel| self.fire(channel as usize))
+    }
+
+    fn fire(&mut self, channel: usize) -> (u32, bool) {
+        let config = self.channels[channel];
+        let mut interrupt = false;
+        if (config >> EvsysRegisters::CHANNEL_PATH_SHIFT) & 0b11
+            != EvsysRegisters::PATH_ASYNCHRONOUS
+        {
+            // The synchronous and resynchronized paths need an edge to output an event
+            if (config >> EvsysRegisters::CHANNEL_EDGSEL_SHIFT) & 0b11 == 0 {
+                return (0, false);
+            }
+            // EVD0-7 are bits 8-15 and EVD8-11 bits 24-27
+            let flag = if channel < 8 {
+                1 << (channel + 8)
+            } else {
+                1 << (channel + 16)
+            };
+            self.intflag |= flag;
+            interrupt = self.intenset & flag != 0;
+        }
+
+        let users = self
+            .users
+            .iter()
+            .enumerate()
+            .filter(|(_, &user_channel)| user_channel as usize == channel + 1)
+            .fold(0, |users, (user, _)| users | 1 << user);
+        (users, interrupt)
+    }
+}
+
+impl Peripheral for EvsysRegisters {
+    fn handle_write_word(&mut self, offset: u32, value: u32, gamebuino: &mut Gamebuino) {
+        match offset {
+            EvsysRegisters::CHANNEL_OFFSET => {
+                let channel = (value & EvsysRegisters::CHANNEL_MASK) as usize;
+                if channel < EvsysRegisters::CHANNEL_COUNT {
+                    self.selected_channel = channel as u8;
+                    self.channels[channel] = value & EvsysRegisters::CHANNEL_CONFIG_MASK;
+                    if value & EvsysRegisters::CHANNEL_SWEVT != 0 {
+                        self.software_event = Some(channel as u8);
+                    }
+                }
+            }
+            EvsysRegisters::USER_OFFSET => {
+                let user = (value & EvsysRegisters::USER_MASK) as usize;
+                self.selected_user = user as u8;
+                self.users[user] = ((value >> EvsysRegisters::USER_CHANNEL_SHIFT)
+                    & EvsysRegisters::USER_MASK) as u8;
+            }
+            EvsysRegisters::INTENCLR_OFFSET => self.intenset &= !value,
+            EvsysRegisters::INTENSET_OFFSET => self.intenset |= value,
+            EvsysRegisters::INTFLAG_OFFSET => self.intflag &= !value,
+            _ => self.handle_write_byte(offset, value as u8, gamebuino),
+        }
+    }
+
+    fn handle_write_byte(&mut self, offset: u32, value: u8, _gamebuino: &mut Gamebuino) {
+        // Byte writes to CHANNEL and USER only select what the next read returns
+        match offset {
+            EvsysRegisters::CTRL_OFFSET if value & EvsysRegisters::CTRL_SWRST != 0 => {
+                *self = EvsysRegisters::new();
+            }
+            EvsysRegisters::CHANNEL_OFFSET => {
+                let channel = value as u32 & EvsysRegisters::CHANNEL_MASK;
+                if (channel as usize) < EvsysRegisters::CHANNEL_COUNT {
+                    self.selected_channel = channel as u8;
+                }
+            }
+            EvsysRegisters::USER_OFFSET => {
+                self.selected_user = (value as u32 & EvsysRegisters::USER_MASK) as u8;
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_write_half_word(&mut self, offset: u32, value: u16, gamebuino: &mut Gamebuino) {
+        match offset {
+            EvsysRegisters::USER_OFFSET => self.handle_write_word(offset, value as u32, gamebuino),
+            _ => {
+                self.handle_write_byte(offset, value as u8, gamebuino);
+                self.handle_write_byte(offset + 1, (value >> 8) as u8, gamebuino);
+            }
+        }
+    }
+
+    fn handle_read_word(&self, offset: u32) -> u32 {
+        match offset {
+            EvsysRegisters::CHANNEL_OFFSET => {
+                self.channels[self.selected_channel as usize] | self.selected_channel as u32
+            }
+            EvsysRegisters::USER_OFFSET => {
+                (self.users[self.selected_user as usize] as u32)
+                    << EvsysRegisters::USER_CHANNEL_SHIFT
+                    | self.selected_user as u32
+            }
+            EvsysRegisters::CHSTATUS_OFFSET => EvsysRegisters::CHSTATUS_USRRDY,
+            EvsysRegisters::INTENCLR_OFFSET | EvsysRegisters::INTENSET_OFFSET => self.intenset,
+            EvsysRegisters::INTFLAG_OFFSET => self.intflag,
+            _ => 0,
+        }
+    }
+
+    fn handle_read_byte(&self, offset: u32) -> u8 {
+        (self.handle_read_word(offset & !3) >> (8 * (offset & 3))) as u8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESCRIPTORS: u32 = 0x20000000;
+    const WRITE_BACK: u32 = 0x20000100;
+    const SOURCE: u32 = 0x20000200;
+    const DESTINATION: u32 = 0x20000300;
+    const SERCOM4_DATA: u32 = SercomRegisters::SERCOM4_START_ADDR + SercomRegisters::DATA_OFFSET;
+
+    fn dmac_write(gamebuino: &mut Gamebuino, offset: u32, value: u8) {
+        gamebuino.store_byte(DmacRegisters::DMAC_START_ADDR + offset, value as u32);
+    }
+
+    /// Sets up channel 0 on `trigger` but leaves it disabled. The test programs spin on `b .`,
+    /// two ticks per step.
+    fn set_up(gamebuino: &mut Gamebuino, trigger: u8, trigger_action: u32) {
+        let dmac = DmacRegisters::DMAC_START_ADDR;
+        gamebuino.store_word(dmac + DmacRegisters::BASEADDR_OFFSET, DESCRIPTORS);
+        gamebuino.store_word(dmac + DmacRegisters::WRBADDR_OFFSET, WRITE_BACK);
+        dmac_write(gamebuino, DmacRegisters::CHID_OFFSET, 0);
+        gamebuino.store_word(
+            dmac + DmacRegisters::CHCTRLB_OFFSET,
+            (trigger as u32) << DmacRegisters::CHCTRLB_TRIGSRC_SHIFT
+                | trigger_action << DmacRegisters::CHCTRLB_TRIGACT_SHIFT,
+        );
+        dmac_write(
+            gamebuino,
+            DmacRegisters::CTRL_OFFSET,
+            DmacRegisters::CTRL_DMAENABLE as u8,
+        );
+    }
+
+    fn enable(gamebuino: &mut Gamebuino) {
+        dmac_write(
+            gamebuino,
+            DmacRegisters::CHCTRLA_OFFSET,
+            DmacRegisters::CHCTRLA_ENABLE,
+        );
+    }
+
+    fn step_to(gamebuino: &mut Gamebuino, tick: u64) {
+        while gamebuino.tick_count < tick {
+            gamebuino.step();
+        }
+    }
+
+    /// Byte beats copying `bytes` from `source` up.
+    fn byte_block(source: u32, destination: u32, bytes: u16, descaddr: u32) -> DmacDescriptor {
+        DmacDescriptor {
+            btctrl: DmacDescriptor::VALID | DmacDescriptor::SRCINC | DmacDescriptor::DSTINC,
+            btcnt: bytes,
+            srcaddr: source + bytes as u32,
+            dstaddr: destination + bytes as u32,
+            descaddr,
+        }
+    }
+
+    #[test]
+    fn sercom_transfers_take_a_byte_time_per_beat() {
+        let mut gamebuino = Gamebuino::for_test(&[0xe7fe], &[]);
+        set_up(
+            &mut gamebuino,
+            DmacRegisters::TRIGGER_SERCOM4_TX,
+            DmacRegisters::TRIGACT_BEAT,
+        );
+        // BAUD 1: 12 Mbit/s, so 2/3 us or 14 ticks a byte
+        gamebuino.store_byte(
+            SercomRegisters::SERCOM4_START_ADDR + SercomRegisters::BAUD_OFFSET,
+            1,
+        );
+        assert_eq!(
+            gamebuino.dma_beat_ticks(DmacRegisters::TRIGGER_SERCOM4_TX),
+            14
+        );
+        let mut descriptor = byte_block(SOURCE, 0, 4, 0);
+        descriptor.btctrl &= !DmacDescriptor::DSTINC;
+        descriptor.dstaddr = SERCOM4_DATA;
+        descriptor.write(DESCRIPTORS, &mut gamebuino);
+        let start = gamebuino.tick_count;
+        enable(&mut gamebuino);
+
+        step_to(&mut gamebuino, start + 3 * 14);
+        assert_eq!(gamebuino.load_half_word(WRITE_BACK + 0x02), 1);
+        step_to(&mut gamebuino, start + 4 * 14 - 2);
+        assert!(gamebuino.dmac_registers.channels[0].enabled);
+        step_to(&mut gamebuino, start + 4 * 14);
+        assert!(!gamebuino.dmac_registers.channels[0].enabled);
+        assert_eq!(gamebuino.load_half_word(WRITE_BACK + 0x02), 0);
+    }
+
+    #[test]
+    fn blocks_chain_through_descaddr() {
+        let mut gamebuino = Gamebuino::for_test(&[0xe7fe], &[]);
+        set_up(&mut gamebuino, 0, DmacRegisters::TRIGACT_TRANSACTION);
+        for (offset, byte) in [1, 2, 3, 4].iter().enumerate() {
+            gamebuino.store_byte(SOURCE + offset as u32, *byte);
+        }
+        let second = DESCRIPTORS + 0x80;
+        byte_block(SOURCE, DESTINATION, 2, second).write(DESCRIPTORS, &mut gamebuino);
+        byte_block(SOURCE + 2, DESTINATION + 0x10, 2, 0).write(second, &mut gamebuino);
+        enable(&mut gamebuino);
+        gamebuino.store_word(
+            DmacRegisters::DMAC_START_ADDR + DmacRegisters::SWTRIGCTRL_OFFSET,
+            1,
+        );
+        let end = gamebuino.tick_count + 10;
+        step_to(&mut gamebuino, end);
+
+        assert_eq!(gamebuino.load_half_word(DESTINATION), 0x0201);
+        assert_eq!(gamebuino.load_half_word(DESTINATION + 0x10), 0x0403);
+        // The write-back descriptor followed the chain to the end
+        let written_back = DmacDescriptor::read(WRITE_BACK, &gamebuino);
+        assert_eq!(written_back.btcnt, 0);
+        assert_eq!(written_back.srcaddr, SOURCE + 4);
+        assert!(!gamebuino.dmac_registers.channels[0].enabled);
+        assert_eq!(
+            gamebuino.dmac_registers.channels[0].intflag,
+            DmacRegisters::CHINTFLAG_TCMPL
+        );
+    }
+
+    /// Runs a one-byte transfer by software trigger with CHINTENSET set to `intenset`, and
+    /// returns whether its interrupt was taken.
+    fn transfer_interrupts(intenset: u8) -> bool {
+        let mut gamebuino = Gamebuino::for_test(&[0xe7fe], &[0xe7fe]);
+        set_up(&mut gamebuino, 0, DmacRegisters::TRIGACT_TRANSACTION);
+        byte_block(SOURCE, DESTINATION, 1, 0).write(DESCRIPTORS, &mut gamebuino);
+        dmac_write(&mut gamebuino, DmacRegisters::CHINTENSET_OFFSET, intenset);
+        enable(&mut gamebuino);
+        gamebuino.store_word(
+            DmacRegisters::DMAC_START_ADDR + DmacRegisters::SWTRIGCTRL_OFFSET,
+            1,
+        );
+        let end = gamebuino.tick_count + 10;
+        step_to(&mut gamebuino, end);
+
+        // The flag is raised either way
+        assert_eq!(
+            gamebuino.dmac_registers.channels[0].intflag,
+            DmacRegisters::CHINTFLAG_TCMPL
+        );
+        gamebuino.pc() == Gamebuino::TEST_HANDLER
+    }
+
+    #[test]
+    fn transfer_complete_interrupts_only_when_enabled() {
+        assert!(!transfer_interrupts(0));
+        assert!(!transfer_interrupts(DmacRegisters::CHINTFLAG_SUSP));
+        assert!(transfer_interrupts(DmacRegisters::CHINTFLAG_TCMPL));
+    }
+}
@@ -0,0 +1,401 @@
+//! Execution tracing: what each instruction was and what it changed.
+
+use crate::disassembler;
+use crate::instruction::Instruction;
+use crate::{Gamebuino, PC_INDEX};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use wasm_bindgen::prelude::*;
+
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceContext {
+    Any,
+    Thread,
+    Interrupt,
+}
+
+/// Which instructions to trace by the memory accesses they make.
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TraceMemory {
+    Any,
+    Reads,
+    Writes,
+    ReadsOrWrites,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MemoryAccess {
+    pub address: u32,
+    pub size: u32,
+    pub write: bool,
+}
+
+pub struct TraceRecord {
+    pub tick: u64,
+    pub pc: u32,
+    pub opcode: u16,
+    pub instruction: Instruction,
+    /// The instruction disassembled, with any literal it loaded as it was when it ran.
+    pub text: String,
+    pub in_interrupt: bool,
+    /// Registers other than the PC that the instruction changed, with their new values.
+    pub registers: Vec<(u8, u32)>,
+    /// The NZCV flags, if the instruction changed them.
+    pub flags: Option<u32>,
+    pub accesses: Vec<MemoryAccess>,
+}
+
+/// Receives trace records as they are made.
+pub trait TraceSink {
+    /// `line` is the record formatted as by `drain_trace`.
+    fn record(&mut self, record: &TraceRecord, line: &str);
+}
+
+enum Output {
+    Ring(VecDeque<TraceRecord>, usize),
+    Stream(Box<dyn TraceSink>),
+}
+
+pub(crate) struct Tracer {
+    output: Output,
+    pc_start: u32,
+    pc_end: u32,
+    context: TraceContext,
+    memory: TraceMemory,
+    // Made by the instruction being traced. The memory accessors only borrow the Gamebuino.
+    accesses: RefCell<Vec<MemoryAccess>>,
+}
+
+impl Tracer {
+    fn new(output: Output) -> Tracer {
+        Tracer {
+            output,
+            pc_start: 0,
+            pc_end: u32::MAX,
+            context: TraceContext::Any,
+            memory: TraceMemory::Any,
+            accesses: RefCell::new(Vec::new()),
+        }
+    }
+
+    fn traces(&self, pc: u32, in_interrupt: bool) -> bool {
+        let context = match self.context {
+            TraceContext::Any => true,
+            TraceContext::Thread => !in_interrupt,
+            TraceContext::Interrupt => in_interrupt,
+        };
+        context && pc >= self.pc_start && pc < self.pc_end
+    }
+
+    fn traces_accesses(&self, accesses: &[MemoryAccess]) -> bool {
+        match self.memory {
+            TraceMemory::Any => true,
+            TraceMemory::Reads => accesses.iter().any(|a| !a.write),
+            TraceMemory::Writes => accesses.iter().any(|a| a.write),
+            TraceMemory::ReadsOrWrites => !accesses.is_empty(),
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Gamebuino {
+    /// Starts tracing into a ring buffer that keeps the last `capacity` instructions.
+    pub fn start_trace(&mut self, capacity: usize) {
+        let ring = VecDeque::with_capacity(capacity.min(0x10000));
+        self.set_tracer(Some(Tracer::new(Output::Ring(ring, capacity))));
+    }
+
+    /// Streams the trace to the browser console, or to stderr in native builds.
+    pub fn start_trace_to_console(&mut self) {
+        self.start_trace_to_sink(Box::new(ConsoleSink));
+    }
+
+    pub fn stop_trace(&mut self) {
+        self.set_tracer(None);
+    }
+
+    /// Only traces instructions from `pc_start` up to but excluding `pc_end`, run in the given
+    /// context, that make the given kinds of memory access.
+    pub fn set_trace_filter(
+        &mut self,
+        pc_start: u32,
+        pc_end: u32,
+        context: TraceContext,
+        memory: TraceMemory,
+    ) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            tracer.pc_start = pc_start;
+            tracer.pc_end = pc_end;
+            tracer.context = context;
+            tracer.memory = memory;
+        }
+    }
+
+    /// Removes and formats the traced instructions in the ring buffer, oldest first.
+    pub fn drain_trace(&mut self) -> Vec<String> {
+        let records = match self.tracer.as_mut().map(|tracer| &mut tracer.output) {
+            Some(Output::Ring(ring, _)) => std::mem::take(ring),
+            _ => return Vec::new(),
+        };
+        records
+            .iter()
+            .map(|record| self.format_trace_record(record))
+            .collect()
+    }
+}
+
+impl Gamebuino {
+    /// Streams the trace to `sink`.
+    pub fn start_trace_to_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.set_tracer(Some(Tracer::new(Output::Stream(sink))));
+    }
+
+    fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer.map(Box::new);
+        self.update_memory_observation();
+    }
+
+    /// Runs `instruction` from `pc` like `step`, recording what it changed.
+    pub(crate) fn execute_traced(&mut self, pc: u32, instruction: Instruction) {
+        let opcode = self.debugger_read(pc, 2) as u16;
+        let registers = self.registers;
+        let flags = self.cond_reg.to_word();
+        if let Some(tracer) = self.tracer.as_ref() {
+            tracer.accesses.borrow_mut().clear();
+        }
+
+        self.increment_pc();
+        self.execute_instruction(instruction);
+
+        let in_interrupt = self.exception_depth > 0;
+        let tracer = match self.tracer.as_ref() {
+            Some(tracer) => tracer,
+            None => return,
+        };
+        let accesses = tracer.accesses.take();
+        if !tracer.traces(pc, in_interrupt) || !tracer.traces_accesses(&accesses) {
+            return;
+        }
+        let text = disassembler::format_instruction(instruction, pc, opcode, |address| {
+            self.debugger_read(address, 4)
+        });
+        let record = TraceRecord {
+            tick: self.tick_count,
+            pc,
+            opcode,
+            instruction,
+            text,
+            in_interrupt,
+            registers: (0..PC_INDEX)
+                .filter(|&i| self.registers[i as usize] != registers[i as usize])
+                .map(|i| (i, self.registers[i as usize]))
+                .collect(),
+            flags: Some(self.cond_reg.to_word()).filter(|&f| f != flags),
+            accesses,
+        };
+
+        let line = match tracer.output {
+            Output::Stream(_) => self.format_trace_record(&record),
+            Output::Ring(..) => String::new(),
+        };
+        if let Some(tracer) = self.tracer.as_mut() {
+            match &mut tracer.output {
+                Output::Ring(ring, capacity) => {
+                    if ring.len() >= *capacity {
+                        ring.pop_front();
+                    }
+                    if *capacity > 0 {
+                        ring.push_back(record);
+                    }
+                }
+                Output::Stream(sink) => sink.record(&record, &line),
+            }
+        }
+    }
+
+    /// Called by the memory accessors while tracing.
+    pub(crate) fn trace_access(&self, address: u32, size: u32, write: bool) {
+        if let Some(tracer) = self.tracer.as_ref() {
+            tracer.accesses.borrow_mut().push(MemoryAccess {
+                address,
+                size,
+                write,
+            });
+        }
+    }
+
+    fn format_trace_record(&self, record: &TraceRecord) -> String {
+        let mut line = format!(
+            "{:>10} {}{:08x}:  {:04x}  {:<40}",
+            record.tick,
+            if record.in_interrupt { '*' } else { ' ' },
+            record.pc,
+            record.opcode,
+            record.text
+        );
+        for &(register, value) in &record.registers {
+            line.push_str(&format!(
+                " {}={:08x}",
+                disassembler::register_name(register),
+                value
+            ));
+        }
+        if let Some(flags) = record.flags {
+            let flag = |bit: u32, name: char| {
+                if flags & bit != 0 {
+                    name.to_ascii_uppercase()
+                } else {
+                    name
+                }
+            };
+            // CondRegister packs C, N, V and Z from the lowest bit up
+            line.push_str(&format!(
+                " {}{}{}{}",
+                flag(2, 'n'),
+                flag(8, 'z'),
+                flag(1, 'c'),
+                flag(4, 'v')
+            ));
+        }
+        for access in &record.accesses {
+            line.push_str(&format!(
+                " [{} {:08x}/{}]",
+                if access.write { 'w' } else { 'r' },
+                access.address,
+                access.size
+            ));
+        }
+        line.trim_end().to_string()
+    }
+}
+
+struct ConsoleSink;
+
+impl TraceSink for ConsoleSink {
+    #[cfg(target_arch = "wasm32")]
+    fn record(&mut self, _record: &TraceRecord, line: &str) {
+        web_sys::console::log_1(&line.into());
+    }
+
+    // Native builds have no browser console
+    #[cfg(not(target_arch = "wasm32"))]
+    fn record(&mut self, _record: &TraceRecord, line: &str) {
+        eprintln!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::rc::Rc;
+
+    /// Reads a word from SRAM, writes it back, then spins.
+    fn program() -> Gamebuino {
+        Gamebuino::for_test(
+            &[
+                0x4802, // ldr r0, [pc, #8]
+                0x6801, // ldr r1, [r0]
+                0x6001, // str r1, [r0]
+                0x2201, // movs r2, #1
+                0xe7fe, // b .
+                0x0000, //
+                0x0000, // 0x20000000
+                0x2000, //
+            ],
+            &[
+                0x2303, // movs r3, #3
+                0x4770, // bx lr
+            ],
+        )
+    }
+
+    struct Collect(Rc<RefCell<Vec<(u32, bool)>>>);
+
+    impl TraceSink for Collect {
+        fn record(&mut self, record: &TraceRecord, _line: &str) {
+            self.0.borrow_mut().push((record.pc, record.in_interrupt));
+        }
+    }
+
+    /// The addresses traced, and whether each ran in an interrupt, over `steps` steps taken
+    /// after interrupting the program with the handler at `TEST_HANDLER`.
+    fn traced(filter: (u32, u32, TraceContext, TraceMemory), steps: usize) -> Vec<(u32, bool)> {
+        let mut gamebuino = program();
+        let records = Rc::new(RefCell::new(Vec::new()));
+        gamebuino.start_trace_to_sink(Box::new(Collect(records.clone())));
+        let (pc_start, pc_end, context, memory) = filter;
+        gamebuino.set_trace_filter(pc_start, pc_end, context, memory);
+        gamebuino.handle_interrupt(Gamebuino::TEST_HANDLER);
+        for _ in 0..steps {
+            gamebuino.step();
+        }
+        let records = records.borrow().clone();
+        records
+    }
+
+    const CODE: u32 = Gamebuino::TEST_CODE;
+    const HANDLER: u32 = Gamebuino::TEST_HANDLER;
+
+    #[test]
+    fn the_pc_range_excludes_its_end() {
+        let records = traced((CODE + 2, CODE + 6, TraceContext::Any, TraceMemory::Any), 6);
+        assert_eq!(records, [(CODE + 2, false), (CODE + 4, false)]);
+    }
+
+    #[test]
+    fn the_context_tells_handlers_from_the_program() {
+        let filter = |context| (0, u32::MAX, context, TraceMemory::Any);
+        let records = traced(filter(TraceContext::Any), 4);
+        assert_eq!(
+            records,
+            [
+                (HANDLER, true),
+                (HANDLER + 2, true),
+                (CODE, false),
+                (CODE + 2, false)
+            ]
+        );
+
+        let records = traced(filter(TraceContext::Interrupt), 4);
+        assert_eq!(records, [(HANDLER, true), (HANDLER + 2, true)]);
+
+        let records = traced(filter(TraceContext::Thread), 4);
+        assert_eq!(records, [(CODE, false), (CODE + 2, false)]);
+    }
+
+    #[test]
+    fn the_memory_filter_picks_instructions_by_their_accesses() {
+        let filter = |memory| (CODE, HANDLER, TraceContext::Any, memory);
+        let pcs = |memory| {
+            traced(filter(memory), 6)
+                .iter()
+                .map(|&(pc, _)| pc)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(pcs(TraceMemory::Any), [CODE, CODE + 2, CODE + 4, CODE + 6]);
+        // The literal load reads flash
+        assert_eq!(pcs(TraceMemory::Reads), [CODE, CODE + 2]);
+        assert_eq!(pcs(TraceMemory::Writes), [CODE + 4]);
+        assert_eq!(pcs(TraceMemory::ReadsOrWrites), [CODE, CODE + 2, CODE + 4]);
+    }
+
+    #[test]
+    fn the_ring_keeps_the_latest_instructions() {
+        let mut gamebuino = program();
+        gamebuino.start_trace(2);
+        for _ in 0..4 {
+            gamebuino.step();
+        }
+        let lines = gamebuino.drain_trace();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("00004104:  6001  str"), "{}", lines[0]);
+        assert!(lines[1].contains("00004106:  2201  movs"), "{}", lines[1]);
+        assert!(gamebuino.drain_trace().is_empty());
+
+        gamebuino.start_trace(0);
+        gamebuino.step();
+        assert!(gamebuino.drain_trace().is_empty());
+    }
+}
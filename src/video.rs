@@ -0,0 +1,386 @@
+//! Gameplay recording from completed screen frames.
+
+use std::collections::HashMap;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum VideoFormat {
+    Gif,
+    /// Little-endian RGB565 frames back to back, with no header, at `RGB565_FRAMES_PER_SECOND`.
+    /// Each frame is width × height pixels, row by row, so it plays with e.g.
+    /// `ffmpeg -f rawvideo -pixel_format rgb565le -video_size 160x128 -framerate 50`.
+    Rgb565,
+}
+
+/// Raw video has no timestamps, so completed frames are repeated or dropped to this rate.
+pub const RGB565_FRAMES_PER_SECOND: u64 = 50;
+
+/// Encodes frames as they complete. Each frame is written once the next one arrives, since its
+/// duration is only known then.
+pub struct Recorder {
+    format: VideoFormat,
+    width: usize,
+    height: usize,
+    output: Vec<u8>,
+    pending: Option<(Vec<u32>, u64)>,
+    written_centiseconds: u64,
+    written_frames: u64,
+    start_tick: u64,
+    ticks_per_second: u64,
+}
+
+impl Recorder {
+    pub fn new(
+        format: VideoFormat,
+        width: usize,
+        height: usize,
+        start_tick: u64,
+        ticks_per_second: u64,
+    ) -> Recorder {
+        let mut recorder = Recorder {
+            format,
+            width,
+            height,
+            output: Vec::new(),
+            pending: None,
+            written_centiseconds: 0,
+            written_frames: 0,
+            start_tick,
+            ticks_per_second,
+        };
+        if format == VideoFormat::Gif {
+            recorder.write_gif_header();
+        }
+        recorder
+    }
+
+    /// Adds a frame that completed at `tick`.
+    pub fn add_frame(&mut self, pixels: &[u32], tick: u64) {
+        if let Some((previous, _)) = self.pending.take() {
+            self.write_frame(&previous, tick);
+        }
+        self.pending = Some((pixels.to_vec(), tick));
+    }
+
+    /// Writes the last frame, lasting until `tick`, and returns the encoded video.
+    pub fn finish(mut self, tick: u64) -> Vec<u8> {
+        if let Some((previous, start)) = self.pending.take() {
+            // Show the last frame for at least one frame period
+            let end = tick.max(start + self.ticks_per_second / RGB565_FRAMES_PER_SECOND);
+            self.write_frame(&previous, end);
+        }
+        if self.format == VideoFormat::Gif {
+            self.output.push(0x3b); // trailer
+        }
+        self.output
+    }
+
+    fn write_frame(&mut self, pixels: &[u32], end_tick: u64) {
+        match self.format {
+            VideoFormat::Gif => {
+                // Delays are in hundredths of a second; round the end time, not each delay,
+                // so that errors don't accumulate
+                let end = (end_tick - self.start_tick) * 100 / self.ticks_per_second;
+                let delay = end.saturating_sub(self.written_centiseconds).max(1);
+                self.written_centiseconds += delay;
+                self.write_gif_frame(pixels, delay.min(u16::MAX as u64) as u16);
+            }
+            VideoFormat::Rgb565 => {
+                // Write the frame for each frame period that ends nearest to while it showed
+                let elapsed = (end_tick - self.start_tick) * RGB565_FRAMES_PER_SECOND;
+                let end = (elapsed + self.ticks_per_second / 2) / self.ticks_per_second;
+                let start = self.output.len();
+                for &pixel in pixels {
+                    let r = (pixel & 0xf8) as u16;
+                    let g = ((pixel >> 8) & 0xfc) as u16;
+                    let b = ((pixel >> 16) & 0xf8) as u16;
+                    let color = (r << 8) | (g << 3) | (b >> 3);
+                    self.output.extend_from_slice(&color.to_le_bytes());
+                }
+                match end.saturating_sub(self.written_frames) {
+                    0 => self.output.truncate(start),
+                    repeats => {
+                        let frame = self.output[start..].to_vec();
+                        for _ in 1..repeats {
+                            self.output.extend_from_slice(&frame);
+                        }
+                    }
+                }
+                self.written_frames = self.written_frames.max(end);
+            }
+        }
+    }
+
+    fn write_gif_header(&mut self) {
+        self.output.extend_from_slice(b"GIF89a");
+        self.output
+            .extend_from_slice(&(self.width as u16).to_le_bytes());
+        self.output
+            .extend_from_slice(&(self.height as u16).to_le_bytes());
+        self.output.extend_from_slice(&[0, 0, 0]); // no global color table
+
+        // Loop forever
+        self.output.extend_from_slice(&[0x21, 0xff, 11]);
+        self.output.extend_from_slice(b"NETSCAPE2.0");
+        self.output.extend_from_slice(&[3, 1, 0, 0, 0]);
+    }
+
+    fn write_gif_frame(&mut self, pixels: &[u32], delay: u16) {
+        // Use the exact colors when they fit in one table, which is the case for most games,
+        // otherwise fall back to a 3-3-2 palette
+        let mut palette: Vec<u32> = Vec::new();
+        let mut lookup: HashMap<u32, u8> = HashMap::new();
+        let mut indices = Vec::with_capacity(pixels.len());
+        for &pixel in pixels {
+            let color = pixel & 0xffffff;
+            let index = match lookup.get(&color) {
+                Some(&index) => index,
+                None => {
+                    if palette.len() == 256 {
+                        break;
+                    }
+                    lookup.insert(color, palette.len() as u8);
+                    palette.push(color);
+                    (palette.len() - 1) as u8
+                }
+            };
+            indices.push(index);
+        }
+        if indices.len() < pixels.len() {
+            palette = (0..256u32)
+                .map(|i| {
+                    let r = (i >> 5) * 255 / 7;
+                    let g = ((i >> 2) & 0b111) * 255 / 7;
+                    let b = (i & 0b11) * 255 / 3;
+                    r | (g << 8) | (b << 16)
+                })
+                .collect();
+            indices = pixels
+                .iter()
+                .map(|&pixel| {
+                    ((pixel & 0xe0) | ((pixel >> 11) & 0x1c) | ((pixel >> 22) & 0x03)) as u8
+                })
+                .collect();
+        }
+
+        let mut table_bits = 1;
+        while (1 << table_bits) < palette.len() {
+            table_bits += 1;
+        }
+
+        // Graphic control extension
+        self.output.extend_from_slice(&[0x21, 0xf9, 4, 0]);
+        self.output.extend_from_slice(&delay.to_le_bytes());
+        self.output.extend_from_slice(&[0, 0]);
+
+        // Image descriptor with a local color table
+        self.output.push(0x2c);
+        self.output.extend_from_slice(&[0, 0, 0, 0]);
+        self.output
+            .extend_from_slice(&(self.width as u16).to_le_bytes());
+        self.output
+            .extend_from_slice(&(self.height as u16).to_le_bytes());
+        self.output.push(0x80 | (table_bits - 1) as u8);
+        for i in 0..(1 << table_bits) {
+            let color = palette.get(i).copied().unwrap_or(0);
+            self.output
+                .extend_from_slice(&[color as u8, (color >> 8) as u8, (color >> 16) as u8]);
+        }
+
+        let min_code_size = table_bits.max(2);
+        self.output.push(min_code_size as u8);
+        let data = lzw_encode(&indices, min_code_size);
+        for block in data.chunks(255) {
+            self.output.push(block.len() as u8);
+            self.output.extend_from_slice(block);
+        }
+        self.output.push(0);
+    }
+}
+
+/// GIF-flavoured LZW: variable code size up to 12 bits, codes packed least significant bit
+/// first.
+fn lzw_encode(indices: &[u8], min_code_size: u32) -> Vec<u8> {
+    const MAX_CODE: u32 = 4095;
+    let clear_code = 1u32 << min_code_size;
+    let end_code = clear_code + 1;
+
+    let mut output = Vec::new();
+    let mut bit_buffer = 0u32;
+    let mut bit_count = 0u32;
+    let mut emit = |code: u32, size: u32, output: &mut Vec<u8>| {
+        bit_buffer |= code << bit_count;
+        bit_count += size;
+        while bit_count >= 8 {
+            output.push(bit_buffer as u8);
+            bit_buffer >>= 8;
+            bit_count -= 8;
+        }
+    };
+
+    let mut dictionary: HashMap<(u32, u8), u32> = HashMap::new();
+    let mut next_code = end_code + 1;
+    let mut code_size = min_code_size + 1;
+    emit(clear_code, code_size, &mut output);
+
+    let mut iter = indices.iter();
+    let mut prefix = match iter.next() {
+        Some(&index) => index as u32,
+        None => {
+            emit(end_code, code_size, &mut output);
+            emit(0, 7, &mut output);
+            return output;
+        }
+    };
+    for &index in iter {
+        if let Some(&code) = dictionary.get(&(prefix, index)) {
+            prefix = code;
+            continue;
+        }
+        emit(prefix, code_size, &mut output);
+        if next_code <= MAX_CODE {
+            dictionary.insert((prefix, index), next_code);
+            // The decoder widens its codes one code later than the encoder adds them
+            if next_code == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+            next_code += 1;
+        } else {
+            emit(clear_code, code_size, &mut output);
+            dictionary.clear();
+            next_code = end_code + 1;
+            code_size = min_code_size + 1;
+        }
+        prefix = index as u32;
+    }
+    emit(prefix, code_size, &mut output);
+    emit(end_code, code_size, &mut output);
+    // Flush the partial byte
+    emit(0, 7, &mut output);
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// GIF LZW decoding, as a viewer would do it.
+    fn lzw_decode(data: &[u8], min_code_size: u32) -> Vec<u8> {
+        let clear_code = 1u32 << min_code_size;
+        let end_code = clear_code + 1;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let reset = |table: &mut Vec<Vec<u8>>| {
+            table.clear();
+            table.extend((0..clear_code).map(|i| vec![i as u8]));
+            table.extend([Vec::new(), Vec::new()]);
+        };
+        reset(&mut table);
+
+        let mut output = Vec::new();
+        let mut code_size = min_code_size + 1;
+        let mut previous: Option<Vec<u8>> = None;
+        let mut bit_position = 0;
+        loop {
+            let mut code = 0u32;
+            for bit in 0..code_size {
+                let byte = data[(bit_position + bit as usize) / 8];
+                code |= ((byte >> ((bit_position + bit as usize) % 8)) as u32 & 1) << bit;
+            }
+            bit_position += code_size as usize;
+
+            if code == clear_code {
+                reset(&mut table);
+                code_size = min_code_size + 1;
+                previous = None;
+                continue;
+            }
+            if code == end_code {
+                return output;
+            }
+            let entry = match (table.get(code as usize), &previous) {
+                (Some(entry), _) => entry.clone(),
+                (None, Some(previous)) => {
+                    let mut entry = previous.clone();
+                    entry.push(previous[0]);
+                    entry
+                }
+                (None, None) => panic!("code {} before any other", code),
+            };
+            output.extend_from_slice(&entry);
+            if let Some(mut added) = previous.take() {
+                if table.len() < 4096 {
+                    added.push(entry[0]);
+                    table.push(added);
+                }
+            }
+            if table.len() == 1 << code_size && code_size < 12 {
+                code_size += 1;
+            }
+            previous = Some(entry);
+        }
+    }
+
+    #[test]
+    fn lzw_round_trips() {
+        let repetitive: Vec<u8> = (0..20_000u32).map(|i| (i / 37 % 4) as u8).collect();
+        // Varied enough to fill the dictionary and force clear codes
+        let mut state = 12345u32;
+        let noisy: Vec<u8> = (0..50_000)
+            .map(|_| {
+                state = state.wrapping_mul(1103515245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        for (indices, min_code_size) in [
+            (vec![], 2),
+            (vec![3], 2),
+            (vec![0, 0, 0, 0, 0, 0, 0], 2),
+            (repetitive, 2),
+            (noisy, 8),
+        ] {
+            let encoded = lzw_encode(&indices, min_code_size);
+            assert_eq!(lzw_decode(&encoded, min_code_size), indices);
+        }
+    }
+
+    #[test]
+    fn gif_frames_carry_their_delays() {
+        let ticks_per_second = 1000;
+        let mut recorder = Recorder::new(VideoFormat::Gif, 2, 1, 0, ticks_per_second);
+        recorder.add_frame(&[0xff0000ff, 0xff00ff00], 0);
+        recorder.add_frame(&[0xff00ff00, 0xff0000ff], 250);
+        let gif = recorder.finish(300);
+
+        assert_eq!(&gif[..6], b"GIF89a");
+        assert_eq!(&gif[6..10], &[2, 0, 1, 0]);
+        assert_eq!(gif.last(), Some(&0x3b));
+        let delays: Vec<u16> = gif
+            .windows(4)
+            .enumerate()
+            .filter(|(_, window)| window[..3] == [0x21, 0xf9, 4])
+            .map(|(i, _)| u16::from_le_bytes([gif[i + 4], gif[i + 5]]))
+            .collect();
+        // The last frame lasts at least a 50 fps frame period
+        assert_eq!(delays, [25, 5]);
+    }
+
+    #[test]
+    fn rgb565_frames_have_no_header() {
+        let mut recorder = Recorder::new(VideoFormat::Rgb565, 2, 1, 0, 1000);
+        recorder.add_frame(&[0xff0000ff, 0xffffffff], 0);
+        recorder.add_frame(&[0xff00ff00, 0xffff0000], 20);
+        let video = recorder.finish(40);
+        assert_eq!(video, [0x00, 0xf8, 0xff, 0xff, 0xe0, 0x07, 0x1f, 0x00]);
+    }
+
+    #[test]
+    fn rgb565_frames_are_repeated_or_dropped_to_the_frame_rate() {
+        let (red, blue) = ([0x00, 0xf8], [0x1f, 0x00]);
+        let mut recorder = Recorder::new(VideoFormat::Rgb565, 1, 1, 1000, 1000);
+        recorder.add_frame(&[0xff0000ff], 1000);
+        // Red shows for 2.5 frame periods, then green for a quarter of one
+        recorder.add_frame(&[0xff00ff00], 1050);
+        recorder.add_frame(&[0xffff0000], 1055);
+        let video = recorder.finish(1080);
+        assert_eq!(video, [red, red, red, blue].concat());
+    }
+}